@@ -0,0 +1,47 @@
+//! Compares serial and multithreaded `WaterSim::simulate`.
+//! Run with `cargo bench --bench simulate`.
+use voxel_water::cells::CellRegistry;
+use voxel_water::map::WaterSim;
+use voxel_water::terrain::{self, TerrainGenerator, TerrainParams};
+
+const WARMUP_STEPS: usize = 10;
+const STEPS: usize = 100;
+
+fn main() {
+    let registry = CellRegistry::default();
+    // at least two threads, so that parallel path is always compared with serial one
+    let threads = std::thread::available_parallelism().map_or(2, |it| it.get().max(2));
+    println!("size, threads, ms per step, total mass");
+    for size in [[40, 20, 40], [80, 40, 80], [160, 40, 160]] {
+        let params = TerrainParams {
+            size,
+            sea_level: Some(size[1] / 3),
+            ..Default::default()
+        };
+        // walled basin, so that water stays on the map
+        let map = terrain::Basin.generate(&params);
+        let sim = WaterSim::new(map, &registry);
+
+        let mut totals = Vec::new();
+        for threads in [1, threads] {
+            let mut sim = sim.clone();
+            sim.set_threads(threads);
+            for _ in 0..WARMUP_STEPS {
+                sim.simulate();
+            }
+            let start = std::time::Instant::now();
+            for _ in 0..STEPS {
+                sim.simulate();
+            }
+            let elapsed = start.elapsed().as_secs_f64() * 1000.0 / STEPS as f64;
+            let total: f64 = sim.mass().iter().map(|it| *it as f64).sum();
+            println!(
+                "{}x{}x{}, {}, {:.3}, {:.3}",
+                size[0], size[1], size[2], threads, elapsed, total
+            );
+            totals.push(total);
+        }
+        // slabs are applied in the same order whatever the thread count is
+        assert_eq!(totals[0], totals[1], "serial and parallel totals differ");
+    }
+}
@@ -1,9 +1,18 @@
+import sys
+
 import matplotlib.pyplot as plt
 
-with open("docs/inc/timing.csv") as f:
+# also reads output of the sim_bench binary: (step, threads, time) followed by
+# water statistics, after a header line
+path = sys.argv[1] if len(sys.argv) > 1 else "docs/inc/timing.csv"
+with open(path) as f:
     data = []
+    sim_bench = False
     for line in f.readlines():
-        a, b, c = line.split(",")
+        if line.startswith("#"):
+            sim_bench = True
+            continue
+        a, b, c = line.split(",")[:3]
         t = (int(a), int(b), float(c))
         data.append(t)
 
@@ -13,12 +22,17 @@ def plot_slice(a, t):
     plt.plot([i[0] for i in items], [i[2] for i in items], label=t)
 
 
-plot_slice(1, "число отскоков 1")
-plot_slice(2, "число отскоков 2")
-plot_slice(4, "число отскоков 4")
-plot_slice(8, "число отскоков 8")
+if sim_bench:
+    for threads in sorted({i[1] for i in data}):
+        plot_slice(threads, f"потоков {threads}")
+    plt.xlabel("шаг симуляции")
+else:
+    plot_slice(1, "число отскоков 1")
+    plot_slice(2, "число отскоков 2")
+    plot_slice(4, "число отскоков 4")
+    plot_slice(8, "число отскоков 8")
+    plt.xlabel("дальность видимости")
 plt.ylabel("время, мкс")
-plt.xlabel("дальность видимости")
 plt.legend(loc="upper left")
 plt.savefig("result.svg")
 
@@ -4,6 +4,7 @@ const two_pi = 6.28318530718;
 const MAT_DIFFUSE: i32 = 0;
 const MAT_METAL: i32 = 1;
 const MAT_DIELECTRIC: i32 = 2;
+const MAT_EMISSIVE: i32 = 3;
 
 const VOXEL_SIZE: f32 = 0.5;
 
@@ -11,7 +12,7 @@ struct Settings {
     max_bounce_count: i32,
     maximum_traversal_distance: i32,
     reproject: f32,
-    pad: f32
+    foam: f32
 };
 
 struct Onb {
@@ -52,7 +53,7 @@ struct Material {
     fuzz: f32,
     refractive_index: f32,
     kind: i32,
-    pad0: f32,
+    emission: f32,
     pad1: f32
 };
 
@@ -64,6 +65,7 @@ struct Ray {
 struct HitRecord {
     normal: vec3f,
     pos: vec3f,
+    voxel: vec3i,
     offset_id: i32,
     t: f32,
     id: u32,
@@ -74,12 +76,16 @@ struct ScatterRecord {
     direction: vec3f
 };
 
+struct Highlight {
+    voxel: vec3i,
+    enabled: u32
+};
+
 struct RandomSeed {
     value: u32,
     p0: u32, p1: u32, p2: u32
 };
 
-@group(0) @binding(0) var voxel_data: texture_3d<u32>;
 @group(0) @binding(1) var<uniform> random_seed: RandomSeed;
 @group(0) @binding(2) var<uniform> inverse_projection_matrix: mat4x4f;
 @group(0) @binding(3) var<uniform> projection_matrix: mat4x4f;
@@ -89,14 +95,22 @@ struct RandomSeed {
 @group(0) @binding(6) var<uniform> prev_view_matrix: mat4x4f;
 @group(0) @binding(7) var<uniform> materials: array<Material, 256>;
 @group(0) @binding(8) var<uniform> settings: Settings;
+@group(0) @binding(9) var<uniform> highlight: Highlight;
 
 @group(1) @binding(0) var prev_color_tex: texture_2d<f32>;
 @group(1) @binding(1) var prev_normal_tex: texture_2d<f32>;
 @group(1) @binding(2) var prev_mat_tex: texture_2d<f32>;
 @group(1) @binding(3) var prev_offset_tex: texture_2d<f32>;
 
+@group(2) @binding(0) var voxel_data: texture_3d<u32>;
+// fluid level of every voxel, 1 for full cubes
+@group(2) @binding(1) var fill_data: texture_3d<f32>;
+// fluid velocity in cells per step, only uploaded when foam is enabled
+@group(2) @binding(2) var velocity_data: texture_3d<f32>;
+
 var<private> rng_state: u32;
-var<private> is_in_water: bool = false;
+// id of the transparent cell the ray is currently travelling through, 0 for air
+var<private> medium_id: u32 = 0u;
 
 @vertex 
 fn vs_main(in: VertexInput) -> VertexOutput {
@@ -241,8 +255,18 @@ fn voxel_traverse(ray: Ray) -> HitRecord {
         }
 
         record.id = textureLoad(voxel_data, current_voxel, 0).r;
-        if is_in_water {
-            if record.id != 2u {
+        record.voxel = current_voxel;
+        // partially filled voxels are fluid only below their surface
+        var fill = 1.0;
+        if record.id != 0u {
+            fill = textureLoad(fill_data, current_voxel, 0).r;
+        }
+        let surface = (f32(current_voxel.y) + fill) * VOXEL_SIZE;
+        let entry_height = origin.y + direction.y * record.t;
+        let t_exit = min(t_max.x, min(t_max.y, t_max.z));
+        let t_surface = (surface - origin.y) / direction.y;
+        if medium_id != 0u {
+            if record.id != medium_id {
                 if record.id == 0u {
                     record.id = original_id;
                     record.pos = ray_at(ray, record.t + 0.001);
@@ -251,10 +275,35 @@ fn voxel_traverse(ray: Ray) -> HitRecord {
                 }
                 break;
             }
+            if fill < 1.0 {
+                if entry_height > surface {
+                    // left through a side, above the surface of this voxel
+                    record.pos = ray_at(ray, record.t + 0.001);
+                    break;
+                }
+                if direction.y > 0.0 && t_surface < t_exit {
+                    record.t = t_surface;
+                    record.normal = vec3f(0.0, -1.0, 0.0);
+                    record.offset_id = current_voxel.y;
+                    record.pos = ray_at(ray, record.t + 0.001);
+                    break;
+                }
+            }
         } else {
             if record.id != 0u {
-                record.pos = ray_at(ray, record.t + 0.001);
-                break;
+                if fill >= 1.0 || entry_height <= surface {
+                    record.pos = ray_at(ray, record.t + 0.001);
+                    break;
+                }
+                if direction.y < 0.0 && t_surface < t_exit {
+                    record.t = t_surface;
+                    record.normal = vec3f(0.0, 1.0, 0.0);
+                    record.offset_id = current_voxel.y;
+                    record.pos = ray_at(ray, record.t + 0.001);
+                    break;
+                }
+                // passed above the surface
+                record.id = 0u;
             }
         }
         original_id = record.id;
@@ -267,6 +316,15 @@ fn scatter(ray: Ray, hrec: HitRecord) -> ScatterRecord {
     var srec: ScatterRecord;
 
     let material = materials[hrec.id];
+    // fast moving fluid is partly covered with diffuse foam
+    if material.kind == MAT_DIELECTRIC && medium_id == 0u && settings.foam > 0.0 {
+        let speed = length(textureLoad(velocity_data, hrec.voxel, 0).xyz);
+        if random_f32() < saturate(speed * settings.foam) {
+            srec.direction = sample_cosine_weighted_hemisphere(hrec.normal);
+            srec.attenuation = vec3f(0.9);
+            return srec;
+        }
+    }
     switch material.kind {
         case 0 /* MAT_DIFFUSE */, default: {
             srec.direction = sample_cosine_weighted_hemisphere(hrec.normal);
@@ -295,7 +353,11 @@ fn scatter(ray: Ray, hrec: HitRecord) -> ScatterRecord {
             } else {
                 srec.direction = refract(ray.direction, hrec.normal, 
                                             refraction_ratio);
-                is_in_water = !is_in_water;
+                if medium_id == 0u {
+                    medium_id = hrec.id;
+                } else {
+                    medium_id = 0u;
+                }
                 srec.attenuation = material.albedo;
             }
         }
@@ -315,39 +377,55 @@ fn trace(ray_: Ray) -> TraceResult {
         return result;
     }
 
-    let srec = scatter(ray, hrec);
-    result.color *= srec.attenuation;
-    ray.origin = hrec.pos;
-    ray.direction = normalize(srec.direction);
+    let highlighted = highlight.enabled != 0u && all(hrec.voxel == highlight.voxel);
 
     result.pos = hrec.pos;
     result.id = hrec.id;
     result.normal = hrec.normal;
     result.offset_id = hrec.offset_id;
 
-    var i: i32 = 1;
-    for (; i < settings.max_bounce_count; i += 1) {
-        let hrec = voxel_traverse(ray);
-        if hrec.id == 0u {
-            break;
-        }
-
+    // emissive surfaces end the path, like the sky does
+    let material = materials[hrec.id];
+    if material.kind == MAT_EMISSIVE {
+        result.color = material.albedo * material.emission;
+    } else {
         let srec = scatter(ray, hrec);
         result.color *= srec.attenuation;
         ray.origin = hrec.pos;
         ray.direction = normalize(srec.direction);
 
-        /*
-        if i > 3 {
-            let p = max(max(result.x, result.y), result.z);
-            if random_f32() > min(p, 0.95) {
+        var i: i32 = 1;
+        for (; i < settings.max_bounce_count; i += 1) {
+            let hrec = voxel_traverse(ray);
+            if hrec.id == 0u {
                 break;
             }
-            result *= 1.0 / p;
+            let material = materials[hrec.id];
+            if material.kind == MAT_EMISSIVE {
+                result.color *= material.albedo * material.emission;
+                break;
+            }
+
+            let srec = scatter(ray, hrec);
+            result.color *= srec.attenuation;
+            ray.origin = hrec.pos;
+            ray.direction = normalize(srec.direction);
+
+            /*
+            if i > 3 {
+                let p = max(max(result.x, result.y), result.z);
+                if random_f32() > min(p, 0.95) {
+                    break;
+                }
+                result *= 1.0 / p;
+            }
+            */
         }
-        */
     }
 
+    if highlighted {
+        result.color = mix(result.color, vec3f(1.0, 0.8, 0.2), 0.4);
+    }
     return result;
 }
 
@@ -0,0 +1,252 @@
+// GPU version of WaterSim::simulate. Flows out of every cell are computed
+// first and then gathered by receiving cells, so no two invocations write
+// the same memory.
+
+const FLAG_SOLID: u32 = 1u;
+const FLAG_FLUID: u32 = 2u;
+const MAX_EMITTERS: u32 = 64u;
+const EMITTER_ADD: u32 = 0u;
+const EMITTER_REMOVE: u32 = 1u;
+
+struct Params {
+    size: vec3u,
+    // cells next to the +x and +z faces below these levels are kept full,
+    // 0 where the face drains
+    ocean_level_x: u32,
+    max_mass: f32,
+    max_compress: f32,
+    min_mass: f32,
+    min_flow: f32,
+    max_speed: f32,
+    spread: f32,
+    // words per row of packed cells
+    row_words: u32,
+    emitter_count: u32,
+    ocean_level_z: u32,
+    // id of the water cell type in the registry
+    water: u32
+};
+
+struct Emitter {
+    min: vec3u,
+    kind: u32,
+    max: vec3u,
+    // mass per cell and step
+    amount: f32
+};
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> flags: array<u32>;
+@group(0) @binding(2) var<storage, read_write> cells: array<u32>;
+@group(0) @binding(3) var<storage, read_write> mass: array<f32>;
+// down, -x, +x, -z, +z, up for every cell
+@group(0) @binding(4) var<storage, read_write> flows: array<f32>;
+@group(0) @binding(5) var<storage, read_write> new_mass: array<f32>;
+// cells as bytes with rows padded for buffer to texture copy
+@group(0) @binding(6) var<storage, read_write> packed: array<u32>;
+@group(0) @binding(7) var<uniform> emitters: array<Emitter, MAX_EMITTERS>;
+// fill levels, packed the same way
+@group(0) @binding(8) var<storage, read_write> packed_fill: array<u32>;
+
+fn index(p: vec3u) -> u32 {
+    return p.z * (params.size.x * params.size.y) + p.y * params.size.x + p.x;
+}
+
+fn is_solid(p: vec3u) -> bool {
+    return (flags[cells[index(p)]] & FLAG_SOLID) != 0u;
+}
+
+fn is_interior(p: vec3u) -> bool {
+    return all(p > vec3u(0u)) && all(p < params.size - vec3u(1u));
+}
+
+fn get_stable_state_b(total: f32) -> f32 {
+    if total <= params.max_mass {
+        return params.max_mass;
+    }
+    if total < 2.0 * params.max_mass + params.max_compress {
+        return (params.max_mass * params.max_mass + total * params.max_compress)
+            / (params.max_mass + params.max_compress);
+    }
+    return (total + params.max_compress) * 0.5;
+}
+
+fn damp(flow: f32) -> f32 {
+    if flow > params.min_flow {
+        return flow * 0.5;
+    }
+    return flow;
+}
+
+@compute @workgroup_size(4, 4, 4)
+fn apply_emitters(@builtin(global_invocation_id) p: vec3u) {
+    if any(p >= params.size) || is_solid(p) {
+        return;
+    }
+    let i = index(p);
+    var m = mass[i];
+    for (var k = 0u; k < min(params.emitter_count, MAX_EMITTERS); k += 1u) {
+        let emitter = emitters[k];
+        if any(p < emitter.min) || any(p >= emitter.max) {
+            continue;
+        }
+        if emitter.kind == EMITTER_ADD {
+            m += emitter.amount;
+        } else if emitter.kind == EMITTER_REMOVE {
+            m = max(m - emitter.amount, 0.0);
+        } else {
+            // drain
+            m = 0.0;
+        }
+    }
+    mass[i] = m;
+}
+
+@compute @workgroup_size(4, 4, 4)
+fn compute_flows(@builtin(global_invocation_id) p: vec3u) {
+    if any(p >= params.size) {
+        return;
+    }
+    let i = index(p);
+    for (var k = 0u; k < 6u; k += 1u) {
+        flows[i * 6u + k] = 0.0;
+    }
+    if !is_interior(p) || is_solid(p) {
+        return;
+    }
+
+    let current = mass[i];
+    var remaining = current;
+    if remaining <= 0.0 {
+        return;
+    }
+
+    let below = p - vec3u(0u, 1u, 0u);
+    if !is_solid(below) {
+        let below_mass = mass[index(below)];
+        let flow = clamp(damp(get_stable_state_b(remaining + below_mass) - below_mass),
+                         0.0, min(params.max_speed, remaining));
+        flows[i * 6u] = flow;
+        remaining -= flow;
+    }
+
+    // fluid may leave the map only through the far padding planes
+    var neighbours = array<vec3u, 4>(
+        p - vec3u(1u, 0u, 0u),
+        p + vec3u(1u, 0u, 0u),
+        p - vec3u(0u, 0u, 1u),
+        p + vec3u(0u, 0u, 1u),
+    );
+    for (var k = 0u; k < 4u; k += 1u) {
+        if remaining <= 0.0 {
+            return;
+        }
+        let n = neighbours[k];
+        if n.x == 0u || n.z == 0u || is_solid(n) {
+            continue;
+        }
+        let flow = clamp(damp((current - mass[index(n)]) / params.spread), 0.0, remaining);
+        flows[i * 6u + 1u + k] = flow;
+        remaining -= flow;
+    }
+
+    if remaining <= 0.0 {
+        return;
+    }
+    let above = p + vec3u(0u, 1u, 0u);
+    if !is_solid(above) {
+        let flow = clamp(damp(remaining - get_stable_state_b(remaining + mass[index(above)])),
+                         0.0, min(params.max_speed, remaining));
+        flows[i * 6u + 5u] = flow;
+    }
+}
+
+@compute @workgroup_size(4, 4, 4)
+fn apply_flows(@builtin(global_invocation_id) p: vec3u) {
+    if any(p >= params.size) {
+        return;
+    }
+    let i = index(p);
+    var m = mass[i];
+    for (var k = 0u; k < 6u; k += 1u) {
+        m -= flows[i * 6u + k];
+    }
+
+    // neighbour and index of its flow towards this cell
+    if p.y + 1u < params.size.y {
+        m += flows[index(p + vec3u(0u, 1u, 0u)) * 6u];
+    }
+    if p.x + 1u < params.size.x {
+        m += flows[index(p + vec3u(1u, 0u, 0u)) * 6u + 1u];
+    }
+    if p.x > 0u {
+        m += flows[index(p - vec3u(1u, 0u, 0u)) * 6u + 2u];
+    }
+    if p.z + 1u < params.size.z {
+        m += flows[index(p + vec3u(0u, 0u, 1u)) * 6u + 3u];
+    }
+    if p.z > 0u {
+        m += flows[index(p - vec3u(0u, 0u, 1u)) * 6u + 4u];
+    }
+    if p.y > 0u {
+        m += flows[index(p - vec3u(0u, 1u, 0u)) * 6u + 5u];
+    }
+
+    var cell = cells[i];
+    if is_interior(p) && (flags[cell] & FLAG_SOLID) == 0u {
+        if m > params.min_mass {
+            cell = params.water;
+        } else {
+            cell = 0u;
+        }
+    }
+
+    let ocean = (p.x + 2u == params.size.x && p.y < params.ocean_level_x)
+        || (p.z + 2u == params.size.z && p.y < params.ocean_level_z);
+    if is_interior(p) && ocean && (flags[cell] & FLAG_SOLID) == 0u
+        && params.max_mass - m > params.min_mass {
+        cell = params.water;
+        m = params.max_mass;
+    }
+
+    // fluid that flowed into the padding leaves the map
+    if !is_interior(p) {
+        m = 0.0;
+    }
+    new_mass[i] = m;
+    cells[i] = cell;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn pack_cells(@builtin(global_invocation_id) p: vec3u) {
+    if p.x >= params.row_words || p.y >= params.size.y || p.z >= params.size.z {
+        return;
+    }
+    var word = 0u;
+    var fill_word = 0u;
+    for (var b = 0u; b < 4u; b += 1u) {
+        let x = p.x * 4u + b;
+        if x < params.size.x {
+            let q = vec3u(x, p.y, p.z);
+            word |= (cells[index(q)] & 0xffu) << (b * 8u);
+            fill_word |= fill_level(q) << (b * 8u);
+        }
+    }
+    let i = (p.z * params.size.y + p.y) * params.row_words + p.x;
+    packed[i] = word;
+    packed_fill[i] = fill_word;
+}
+
+// Same as fill_level in map.rs, reads mass written by apply_flows
+fn fill_level(p: vec3u) -> u32 {
+    let i = index(p);
+    if (flags[cells[i]] & FLAG_FLUID) == 0u {
+        return 255u;
+    }
+    // the cell above counts only if it is not padding
+    if p.y + 2u < params.size.y && new_mass[index(p + vec3u(0u, 1u, 0u))] > params.min_mass {
+        return 255u;
+    }
+    let level = clamp(new_mass[i] / params.max_mass, 0.0, 1.0) * 255.0;
+    return u32(max(round(level), 1.0));
+}
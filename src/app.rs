@@ -1,65 +1,92 @@
+use crate::boundary::{Boundary, Face};
+use crate::brush::{self, Bounds, Shape};
 use crate::camera::Camera;
-use crate::map::{Map, WaterSim};
+use crate::cells::{Cell, CellRegistry};
+use crate::clock::SimClock;
+use crate::emitters::{Emitter, EmitterKind, EmitterShape};
+use crate::gpu_sim::{GpuWaterSim, MAX_EMITTERS};
+use crate::history::{EntryKind, History, DEFAULT_HISTORY_BYTES};
+use crate::map::{BlockActivity, ErosionParams, WaterSimParams};
 use crate::materials::Material;
 use crate::math::*;
-use crate::perlin::Perlin;
-use crate::renderer::{MaterialDTO, SettingsDTO};
+use crate::picking::{self, Pick};
+use crate::renderer::MaterialDTO;
 use crate::renderer::{Renderer, WorldDTO};
-use crate::xorshift32::{self, Xorshift32, Xorshift32Seed};
-use rand::SeedableRng;
+use crate::scene::Scene;
+use crate::settings::Settings;
+use crate::solver::{self, FluidSolver};
+use crate::terrain::{self, Sea, TerrainGenerator, TerrainParams};
+use crate::vox::{self, UpAxis, VoxImportOptions};
+use crate::weather::{RainDistribution, Weather};
+use crate::xorshift32::{self, derive_seed, Xorshift32};
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
 };
 
-use crate::input::Input;
+use crate::input::{Input, MousePos};
 
-struct Settings {
-    max_bounce_count: i32,
-    maximum_traversal_distance: i32,
-    enable_reproject: bool,
-    enable_gauss: bool,
-}
+// Random streams derived from session seed
+const TERRAIN_STREAM: u32 = 0;
+const SIM_STREAM: u32 = 1;
+const FRAME_STREAM: u32 = 2;
+const APP_STREAM: u32 = 3;
 
-impl Default for Settings {
-    fn default() -> Self {
-        Self {
-            max_bounce_count: 4,
-            maximum_traversal_distance: 64,
-            enable_reproject: true,
-            enable_gauss: true,
-        }
-    }
-}
+// Cells that can be placed with the mouse
+const PALETTE: [Cell; 4] = [Cell::GROUND, Cell::GRASS, Cell::WATER, Cell::LAVA];
+// Cursor may move this many pixels between press and release for it to be a click
+const CLICK_TOLERANCE: f64 = 4.0;
 
-impl Settings {
-    fn as_dto(&self) -> SettingsDTO {
-        SettingsDTO {
-            max_bounce_count: self.max_bounce_count,
-            maximum_traversal_distance: self.maximum_traversal_distance,
-            reproject: if self.enable_reproject { 1.0 } else { 0.0 },
-            pad: 0.0,
-        }
-    }
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    /// Mouse only moves camera
+    Camera,
+    Erase,
+    Place(Cell),
 }
 
 pub struct App {
     settings: Settings,
+    session_seed: u32,
     rng: xorshift32::Xorshift32,
     input: Input,
     camera: Camera,
+    registry: CellRegistry,
     materials: Vec<Material>,
+    terrain: TerrainParams,
+    generators: Vec<Box<dyn TerrainGenerator>>,
+    generator: usize,
     // map: Map,
-    map: WaterSim,
+    map: Box<dyn FluidSolver>,
+    // while set, simulation state lives on the GPU and `map` is synced on demand
+    gpu_sim: Option<GpuWaterSim>,
+    gpu_sim_error: Option<&'static str>,
     renderer: Renderer,
     start_time: instant::Instant,
     last_time: instant::Instant,
     frame_counter: usize,
-    sim_enabled: bool,
-    sim_divider: usize,
+    sim_clock: SimClock,
+    // edited copy, applied to the simulation only when valid
+    sim_params: WaterSimParams,
+    sim_params_error: Option<&'static str>,
+    lava_params: WaterSimParams,
+    lava_params_error: Option<&'static str>,
+    erosion: ErosionParams,
+    erosion_error: Option<&'static str>,
+    boundaries_error: Option<&'static str>,
+
+    scene_path: String,
+    vox_path: String,
+    scene_status: String,
 
-    water_source_coord: [usize; 3],
-    source_enabled: bool,
+    emitters: Vec<Emitter>,
+    weather: Weather,
+
+    history: History,
+    tool: Tool,
+    brush_radius: f32,
+    highlight: Option<[usize; 3]>,
+    left_press_pos: MousePos<f64>,
 
     timing_active: bool,
     timing_counter: usize,
@@ -76,26 +103,25 @@ impl App {
         let mut camera = Camera::new(aspect_ratio, 60.0_f32.to_radians(), 0.1, 1000.0);
         camera.translate(Vector3::new(10.0, 10.0, 10.0) * 1.5);
 
-        let mut rng = Xorshift32::from_seed(Xorshift32Seed(rand::random::<[u8; 4]>()));
-        let mut perlin = Perlin::new(&mut rng);
-        let map = Map::with_perlin(40, 20, 40, &mut perlin);
-        let map = WaterSim::new(map);
-        // let map = Map::random(10, 10, 10);
+        // this is the only source of nondeterminism, everything else is derived from it
+        let session_seed = std::env::var("VOXEL_WATER_SEED")
+            .ok()
+            .and_then(|it| it.parse().ok())
+            .unwrap_or_else(rand::random::<u32>);
+        let rng = Xorshift32::new(derive_seed(session_seed, APP_STREAM));
+        let registry = CellRegistry::default();
+        let terrain = TerrainParams {
+            seed: derive_seed(session_seed, TERRAIN_STREAM),
+            ..Default::default()
+        };
+        let generators = terrain::generators();
+        let map = generators[0].generate(&terrain);
+        let map = solver::default_solver(map, &registry, derive_seed(session_seed, SIM_STREAM));
+        // let map = Map::random(10, 10, 10, &registry);
         // let map = Map::cube(10, 10, 10);
+        let emitter_position = top_center(&map.editable());
 
-        let materials = vec![
-            Material::diffuse(Vector3::new(0.0, 0.0, 0.0)),
-            Material::diffuse(Vector3::new(
-                0.44313725490196076,
-                0.6666666666666666,
-                0.20392156862745098,
-            )),
-            Material::dielectric(Vector3::new(0.5, 0.5, 0.9), 2.045),
-            Material::metal(
-                Vector3::new(0.6274509803921569, 0.3568627450980392, 0.3254901960784314),
-                0.5,
-            ),
-        ];
+        let materials = registry.materials();
         let material_dto = materials
             .iter()
             .map(|it| it.as_dto())
@@ -112,19 +138,44 @@ impl App {
         let start_time = instant::Instant::now();
         Self {
             settings,
+            session_seed,
             rng,
             input,
             camera,
+            registry,
             materials,
+            terrain,
+            generators,
+            generator: 0,
             map,
+            gpu_sim: None,
+            gpu_sim_error: None,
             renderer,
             start_time,
             last_time: start_time,
             frame_counter: 0,
-            sim_enabled: false,
-            sim_divider: 10,
-            water_source_coord: [20, 19, 20],
-            source_enabled: false,
+            sim_clock: SimClock::new(6.0),
+            sim_params: WaterSimParams::default(),
+            sim_params_error: None,
+            lava_params: WaterSimParams::lava(),
+            lava_params_error: None,
+            erosion: ErosionParams::default(),
+            erosion_error: None,
+            boundaries_error: None,
+            scene_path: String::from("scene.vxws"),
+            vox_path: String::from("scene.vox"),
+            scene_status: String::new(),
+            emitters: vec![Emitter {
+                enabled: false,
+                ..Emitter::new(EmitterKind::Source, emitter_position)
+            }],
+            weather: Weather::default(),
+
+            history: History::new(DEFAULT_HISTORY_BYTES),
+            tool: Tool::Camera,
+            brush_radius: 0.5,
+            highlight: None,
+            left_press_pos: MousePos { x: 0.0, y: 0.0 },
 
             timing_active: false,
             timing_counter: 0,
@@ -168,24 +219,31 @@ impl App {
         }
 
         self.frame_counter += 1;
-        if self.frame_counter % self.sim_divider == 0 && self.sim_enabled {
-            if self.source_enabled {
-                self.map.set_mass(
-                    self.water_source_coord[0],
-                    self.water_source_coord[1],
-                    self.water_source_coord[2],
-                );
-            }
-            if self.map.simulate() {
-                self.renderer.update_map(self.map.as_dto());
-            }
-        }
-
         let new_time = instant::Instant::now();
         let time_delta = new_time.duration_since(self.last_time);
         self.last_time = new_time;
-        let rng_seed = new_time.duration_since(self.start_time).as_millis();
-        self.renderer.update_random_seed(rng_seed as u32);
+
+        let steps = self.sim_clock.advance(time_delta);
+        for _ in 0..steps {
+            self.sim_step();
+        }
+        self.upload_changed_regions();
+        let velocity_regions = self.map.take_velocity_regions();
+        if let Some(velocity) = self
+            .map
+            .velocity()
+            .filter(|_| self.settings.foam && self.gpu_sim.is_none())
+        {
+            for region in velocity_regions.iter() {
+                self.renderer.update_velocity_region(velocity, region);
+            }
+        }
+
+        let rng_seed = derive_seed(
+            derive_seed(self.session_seed, FRAME_STREAM),
+            self.frame_counter as u32,
+        );
+        self.renderer.update_random_seed(rng_seed);
 
         let time_delta_s = (time_delta.as_micros() as f32) / 1_000_000.0;
         let mut dp = Vector3::zeros();
@@ -221,6 +279,63 @@ impl App {
             camera_was_changed = true;
         }
 
+        let hovered = if self.tool == Tool::Camera {
+            None
+        } else {
+            self.pick()
+        };
+        if self.input.is_mouse_pressed(MouseButton::Left) {
+            self.left_press_pos = self.input.mouse_pos();
+        }
+        if let Some(pick) = hovered {
+            let pos = self.input.mouse_pos();
+            let clicked = self.input.is_mouse_released(MouseButton::Left)
+                && (pos.x - self.left_press_pos.x).abs() + (pos.y - self.left_press_pos.y).abs()
+                    < CLICK_TOLERANCE;
+            if clicked || self.input.is_mouse_pressed(MouseButton::Right) {
+                self.pull_gpu_sim();
+                if let Some(sim) = self.map.restorable_mut() {
+                    self.history.begin(sim);
+                }
+            }
+            let dirty = match self.tool {
+                Tool::Place(cell) if clicked => pick.before.and_then(|it| {
+                    brush::fill(self.map.as_mut(), &brush_shape(it, self.brush_radius), cell)
+                }),
+                Tool::Erase if clicked => brush::clear(
+                    self.map.as_mut(),
+                    &brush_shape(pick.voxel, self.brush_radius),
+                ),
+                _ if self.input.is_mouse_pressed(MouseButton::Right) => brush::clear(
+                    self.map.as_mut(),
+                    &brush_shape(pick.voxel, self.brush_radius),
+                ),
+                _ => None,
+            };
+            if let Some(dirty) = dirty {
+                if let Some(sim) = self.map.restorable_mut() {
+                    self.history.commit(sim, EntryKind::Edit);
+                }
+                self.push_gpu_sim();
+                self.renderer.update_map_region(self.map.as_dto(), &dirty);
+                self.upload_changed_regions();
+            }
+        }
+
+        let ctrl = self.input.is_key_down(VirtualKeyCode::LControl)
+            || self.input.is_key_down(VirtualKeyCode::RControl);
+        let shift = self.input.is_key_down(VirtualKeyCode::LShift)
+            || self.input.is_key_down(VirtualKeyCode::RShift);
+        let mut undo = ctrl && !shift && self.input.is_key_pressed(VirtualKeyCode::Z);
+        let mut redo = ctrl
+            && ((shift && self.input.is_key_pressed(VirtualKeyCode::Z))
+                || self.input.is_key_pressed(VirtualKeyCode::Y));
+        let highlight = hovered.map(|it| it.voxel);
+        if highlight != self.highlight {
+            self.highlight = highlight;
+            self.renderer.update_highlight(highlight);
+        }
+
         let egui_ctx = self.renderer.begin_ui_frame();
         {
             #[cfg(feature = "russian")]
@@ -236,17 +351,130 @@ impl App {
             #[cfg(not(feature = "russian"))]
             ui.label(format!("Frame time: {:?}", time_delta));
 
+            ui.horizontal(|ui| {
+                let mut running = !self.sim_clock.paused;
+                #[cfg(feature = "russian")]
+                ui.checkbox(&mut running, "включить симуляцию");
+                #[cfg(not(feature = "russian"))]
+                ui.checkbox(&mut running, "sim enabled");
+                self.sim_clock.paused = !running;
+                #[cfg(feature = "russian")]
+                let step_clicked = ui.button("шаг").clicked();
+                #[cfg(not(feature = "russian"))]
+                let step_clicked = ui.button("step").clicked();
+                if step_clicked {
+                    self.sim_clock.request_step();
+                }
+                if self.sim_clock.is_behind() {
+                    #[cfg(feature = "russian")]
+                    ui.colored_label(egui::Color32::RED, "не успевает");
+                    #[cfg(not(feature = "russian"))]
+                    ui.colored_label(egui::Color32::RED, "can't keep up");
+                }
+            });
+            ui.add_enabled_ui(self.renderer.compute_supported(), |ui| {
+                let mut gpu_enabled = self.gpu_sim.is_some();
+                #[cfg(feature = "russian")]
+                let changed = ui.checkbox(&mut gpu_enabled, "симуляция на GPU").changed();
+                #[cfg(not(feature = "russian"))]
+                let changed = ui.checkbox(&mut gpu_enabled, "GPU simulation").changed();
+                if changed {
+                    self.gpu_sim_error = None;
+                }
+                if changed && gpu_enabled {
+                    self.start_gpu_sim();
+                    if self.gpu_sim.is_some() {
+                        // steps on the GPU are not recorded, older entries would not apply
+                        self.history.clear();
+                        // velocity is only computed on the CPU
+                        self.renderer.update_velocity(None);
+                    }
+                } else if changed {
+                    self.pull_gpu_sim();
+                    self.gpu_sim = None;
+                    self.renderer.update_map(self.map.as_dto());
+                }
+            });
+            if let Some(err) = self.gpu_sim_error {
+                ui.colored_label(egui::Color32::RED, err);
+            }
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut self.sim_clock.steps_per_second)
+                        .speed(0.1)
+                        .clamp_range(0.5..=240.0),
+                );
+                #[cfg(feature = "russian")]
+                ui.label("шагов в секунду");
+                #[cfg(not(feature = "russian"))]
+                ui.label("steps per second");
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut self.sim_clock.speed, 0.1..=8.0).logarithmic(true));
+                #[cfg(feature = "russian")]
+                ui.label("скорость");
+                #[cfg(not(feature = "russian"))]
+                ui.label("speed");
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.sim_clock.max_catch_up).clamp_range(1..=64));
+                #[cfg(feature = "russian")]
+                ui.label("макс. шагов за кадр");
+                #[cfg(not(feature = "russian"))]
+                ui.label("max steps per frame");
+            });
+            if let Some(stats) = self.map.mass_stats().filter(|_| self.gpu_sim.is_none()) {
+                #[cfg(feature = "russian")]
+                ui.label(format!(
+                    "масса: {:.2}, +{:.3} -{:.3}, погрешность {:.2e}",
+                    stats.total, stats.created, stats.removed, stats.drift
+                ));
+                #[cfg(not(feature = "russian"))]
+                ui.label(format!(
+                    "mass: {:.2}, +{:.3} -{:.3}, drift {:.2e}",
+                    stats.total, stats.created, stats.removed, stats.drift
+                ));
+            }
             #[cfg(feature = "russian")]
-            ui.checkbox(&mut self.sim_enabled, "включить симуляцию");
+            let header = "параметры воды";
             #[cfg(not(feature = "russian"))]
-            ui.checkbox(&mut self.sim_enabled, "sim enabled");
-            ui.horizontal(|ui| {
-                ui.add(egui::DragValue::new(&mut self.sim_divider).clamp_range(1..=32));
-
+            let header = "water parameters";
+            ui.collapsing(header, |ui| {
+                if params_ui(ui, &mut self.sim_params, WaterSimParams::default()) {
+                    self.apply_sim_params();
+                }
+                if let Some(err) = self.sim_params_error {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+            });
+            #[cfg(feature = "russian")]
+            let header = "параметры лавы";
+            #[cfg(not(feature = "russian"))]
+            let header = "lava parameters";
+            ui.collapsing(header, |ui| {
+                // the GPU simulation has no lava
+                ui.set_enabled(self.gpu_sim.is_none());
+                if params_ui(ui, &mut self.lava_params, WaterSimParams::lava()) {
+                    self.apply_lava_params();
+                }
+                if let Some(err) = self.lava_params_error {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+            });
+            #[cfg(feature = "russian")]
+            let header = "активные блоки";
+            #[cfg(not(feature = "russian"))]
+            let header = "active blocks";
+            ui.collapsing(header, |ui| match self.map.block_activity() {
+                Some(activity) if self.gpu_sim.is_none() => draw_block_activity(ui, &activity),
                 #[cfg(feature = "russian")]
-                ui.label("частота симуляции");
+                _ => {
+                    ui.label("нет данных");
+                }
                 #[cfg(not(feature = "russian"))]
-                ui.label("sim divider");
+                _ => {
+                    ui.label("not tracked");
+                }
             });
             ui.horizontal(|ui| {
                 was_changed |= ui
@@ -288,6 +516,15 @@ impl App {
                     .clicked();
             }
 
+            #[cfg(feature = "russian")]
+            let foam_clicked = ui.checkbox(&mut self.settings.foam, "пена").clicked();
+            #[cfg(not(feature = "russian"))]
+            let foam_clicked = ui.checkbox(&mut self.settings.foam, "foam").clicked();
+            if foam_clicked && self.settings.foam && self.gpu_sim.is_none() {
+                self.renderer.update_velocity(self.map.velocity());
+            }
+            was_changed |= foam_clicked;
+
             if was_changed {
                 self.renderer.update_settings(self.settings.as_dto());
             }
@@ -373,7 +610,7 @@ impl App {
                 ui.label("camera yaw");
             });
             let mut materials_changed = false;
-            match &mut self.materials[2] {
+            match &mut self.materials[Cell::WATER.id() as usize] {
                 Material::Dielectric {
                     albedo,
                     refractive_index,
@@ -405,34 +642,484 @@ impl App {
                 }
                 _ => {}
             }
+            #[cfg(feature = "russian")]
+            let header = "источники и стоки";
+            #[cfg(not(feature = "russian"))]
+            let header = "sources and drains";
+            ui.collapsing(header, |ui| {
+                let editable = self.map.editable();
+                let mut removed = None;
+                for (i, emitter) in self.emitters.iter_mut().enumerate() {
+                    ui.push_id(i, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut emitter.enabled, "");
+                            egui::ComboBox::from_id_source("kind")
+                                .selected_text(emitter_kind_label(emitter.kind))
+                                .show_ui(ui, |ui| {
+                                    for kind in EmitterKind::ALL {
+                                        let label = emitter_kind_label(kind);
+                                        ui.selectable_value(&mut emitter.kind, kind, label);
+                                    }
+                                });
+                            if emitter.kind != EmitterKind::Drain {
+                                ui.add(
+                                    egui::DragValue::new(&mut emitter.rate)
+                                        .speed(0.01)
+                                        .clamp_range(0.0..=f32::MAX),
+                                );
+                                #[cfg(feature = "russian")]
+                                ui.label("масса за шаг");
+                                #[cfg(not(feature = "russian"))]
+                                ui.label("mass per tick");
+                            }
+                            if ui.button("x").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            for (axis, it) in emitter.position.iter_mut().enumerate() {
+                                ui.add(
+                                    egui::DragValue::new(it)
+                                        .clamp_range(editable.min[axis]..=editable.max[axis] - 1),
+                                );
+                            }
+                            #[cfg(feature = "russian")]
+                            ui.label("позиция");
+                            #[cfg(not(feature = "russian"))]
+                            ui.label("position");
+                        });
+                        ui.horizontal(|ui| {
+                            let mut is_box = matches!(emitter.shape, EmitterShape::Box { .. });
+                            #[cfg(feature = "russian")]
+                            let l = "параллелепипед";
+                            #[cfg(not(feature = "russian"))]
+                            let l = "box";
+                            if ui.checkbox(&mut is_box, l).changed() {
+                                emitter.shape = if is_box {
+                                    EmitterShape::Box { size: [1; 3] }
+                                } else {
+                                    EmitterShape::Point
+                                };
+                            }
+                            if let EmitterShape::Box { size } = &mut emitter.shape {
+                                for it in size.iter_mut() {
+                                    ui.add(egui::DragValue::new(it).clamp_range(1..=256));
+                                }
+                            }
+                        });
+                    });
+                }
+                if let Some(i) = removed {
+                    self.emitters.remove(i);
+                }
+                // GPU simulation has a fixed size buffer for emitters
+                let can_add = self.emitters.len() < MAX_EMITTERS;
+                #[cfg(feature = "russian")]
+                let add_clicked = ui
+                    .add_enabled(can_add, egui::Button::new("добавить"))
+                    .clicked();
+                #[cfg(not(feature = "russian"))]
+                let add_clicked = ui.add_enabled(can_add, egui::Button::new("add")).clicked();
+                if add_clicked {
+                    // under the cursor if possible, otherwise in the middle of the top layer
+                    let position = self.highlight.unwrap_or(top_center(&editable));
+                    self.emitters
+                        .push(Emitter::new(EmitterKind::Source, position));
+                }
+            });
+            #[cfg(feature = "russian")]
+            let header = "погода";
+            #[cfg(not(feature = "russian"))]
+            let header = "weather";
+            ui.collapsing(header, |ui| {
+                // not implemented in compute shaders
+                ui.set_enabled(self.gpu_sim.is_none());
+                let weather = &mut self.weather;
+                #[cfg(feature = "russian")]
+                ui.checkbox(&mut weather.enabled, "включить дождь и испарение");
+                #[cfg(not(feature = "russian"))]
+                ui.checkbox(&mut weather.enabled, "rain and evaporation");
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut weather.rain_rate)
+                            .speed(0.0001)
+                            .clamp_range(0.0..=1.0)
+                            .max_decimals(4),
+                    );
+                    #[cfg(feature = "russian")]
+                    ui.label("дождь, масса на столбец за шаг");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("rain, mass per column and tick");
+                });
+                let editable = self.map.editable();
+                let cloud = RainDistribution::Cloud {
+                    center: [
+                        (editable.min[0] + editable.max[0]) / 2,
+                        (editable.min[2] + editable.max[2]) / 2,
+                    ],
+                    radius: (editable.size()[0].min(editable.size()[2]) / 4) as f32,
+                };
+                let options = [
+                    RainDistribution::Uniform,
+                    RainDistribution::Scattered { coverage: 0.1 },
+                    cloud,
+                ];
+                egui::ComboBox::from_label({
+                    #[cfg(feature = "russian")]
+                    let l = "распределение";
+                    #[cfg(not(feature = "russian"))]
+                    let l = "distribution";
+                    l
+                })
+                .selected_text(rain_distribution_label(&weather.distribution))
+                .show_ui(ui, |ui| {
+                    for it in options {
+                        let selected = it.name() == weather.distribution.name();
+                        if ui
+                            .selectable_label(selected, rain_distribution_label(&it))
+                            .clicked()
+                            && !selected
+                        {
+                            weather.distribution = it;
+                        }
+                    }
+                });
+                match &mut weather.distribution {
+                    RainDistribution::Uniform => {}
+                    RainDistribution::Scattered { coverage } => {
+                        ui.add(egui::Slider::new(coverage, 0.0..=1.0).text({
+                            #[cfg(feature = "russian")]
+                            let l = "покрытие";
+                            #[cfg(not(feature = "russian"))]
+                            let l = "coverage";
+                            l
+                        }));
+                    }
+                    RainDistribution::Cloud { center, radius } => {
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                egui::DragValue::new(&mut center[0])
+                                    .clamp_range(editable.min[0]..=editable.max[0] - 1),
+                            );
+                            ui.add(
+                                egui::DragValue::new(&mut center[1])
+                                    .clamp_range(editable.min[2]..=editable.max[2] - 1),
+                            );
+                            ui.add(egui::DragValue::new(radius).clamp_range(0.0..=256.0));
+                            #[cfg(feature = "russian")]
+                            ui.label("центр и радиус");
+                            #[cfg(not(feature = "russian"))]
+                            ui.label("center and radius");
+                        });
+                    }
+                }
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut weather.evaporation_rate)
+                            .speed(0.0001)
+                            .clamp_range(0.0..=1.0)
+                            .max_decimals(4),
+                    );
+                    #[cfg(feature = "russian")]
+                    ui.label("испарение, масса на клетку за шаг");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("evaporation, mass per cell and tick");
+                });
+            });
+            #[cfg(feature = "russian")]
+            let header = "эрозия";
+            #[cfg(not(feature = "russian"))]
+            let header = "erosion";
+            ui.collapsing(header, |ui| {
+                // not implemented in compute shaders
+                ui.set_enabled(self.gpu_sim.is_none());
+                let mut erosion_changed = false;
+                let erosion = &mut self.erosion;
+                #[cfg(feature = "russian")]
+                let label = "размывать грунт";
+                #[cfg(not(feature = "russian"))]
+                let label = "erode terrain";
+                erosion_changed |= ui.checkbox(&mut erosion.enabled, label).changed();
+                #[cfg(feature = "russian")]
+                let fields = [
+                    (&mut erosion.erosion_rate, "скорость размыва"),
+                    (&mut erosion.deposition_rate, "скорость осаждения"),
+                    (&mut erosion.min_speed, "минимальная скорость воды"),
+                ];
+                #[cfg(not(feature = "russian"))]
+                let fields = [
+                    (&mut erosion.erosion_rate, "erosion rate"),
+                    (&mut erosion.deposition_rate, "deposition rate"),
+                    (&mut erosion.min_speed, "min water speed"),
+                ];
+                for (value, label) in fields {
+                    ui.horizontal(|ui| {
+                        erosion_changed |= ui
+                            .add(egui::DragValue::new(value).speed(0.001).max_decimals(4))
+                            .changed();
+                        ui.label(label);
+                    });
+                }
+                ui.horizontal_wrapped(|ui| {
+                    #[cfg(feature = "russian")]
+                    ui.label("размываются:");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("erodible:");
+                    for it in self.registry.iter().filter(|it| it.solid) {
+                        let mut erodible = erosion.erodible.contains(&it.id);
+                        if ui.checkbox(&mut erodible, &it.name).changed() {
+                            if erodible {
+                                erosion.erodible.push(it.id);
+                            } else {
+                                erosion.erodible.retain(|cell| *cell != it.id);
+                            }
+                            erosion_changed = true;
+                        }
+                    }
+                });
+                if erosion_changed {
+                    self.apply_erosion();
+                }
+                if let Some(err) = self.erosion_error {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+                if let Some(stats) = self.map.erosion_stats() {
+                    #[cfg(feature = "russian")]
+                    ui.label(format!(
+                        "размыто клеток: {}, намыто: {}",
+                        stats.eroded, stats.deposited
+                    ));
+                    #[cfg(not(feature = "russian"))]
+                    ui.label(format!(
+                        "cells eroded: {}, deposited: {}",
+                        stats.eroded, stats.deposited
+                    ));
+                }
+            });
+            #[cfg(feature = "russian")]
+            let header = "границы";
+            #[cfg(not(feature = "russian"))]
+            let header = "boundaries";
+            ui.collapsing(header, |ui| {
+                // the GPU simulation keeps the default boundaries
+                ui.set_enabled(self.gpu_sim.is_none());
+                if let Some(mut boundaries) = self.map.boundaries() {
+                    let mut changed = false;
+                    let height = self.map.size()[1];
+                    let options = [
+                        Boundary::Closed,
+                        Boundary::Drain,
+                        Boundary::Ocean { level: height / 2 },
+                        Boundary::Periodic,
+                    ];
+                    for face in Face::ALL {
+                        ui.horizontal(|ui| {
+                            let boundary = boundaries.get(face);
+                            egui::ComboBox::from_id_source(face.name())
+                                .selected_text(boundary_label(&boundary))
+                                .show_ui(ui, |ui| {
+                                    for it in options {
+                                        let selected = it.name() == boundary.name();
+                                        if ui
+                                            .selectable_label(selected, boundary_label(&it))
+                                            .clicked()
+                                            && !selected
+                                        {
+                                            // periodic faces come in pairs
+                                            let opposite = face.opposite();
+                                            if it == Boundary::Periodic
+                                                || boundaries.get(opposite) == Boundary::Periodic
+                                            {
+                                                boundaries.set(opposite, it);
+                                            }
+                                            boundaries.set(face, it);
+                                            changed = true;
+                                        }
+                                    }
+                                });
+                            // cells below the level are kept full, so the top padding row is the limit
+                            if let Boundary::Ocean { level } = &mut boundaries.0[face as usize] {
+                                changed |= ui
+                                    .add(egui::DragValue::new(level).clamp_range(0..=height - 1))
+                                    .changed();
+                            }
+                            ui.label(face.name());
+                        });
+                    }
+                    if changed {
+                        self.boundaries_error = self.map.set_boundaries(boundaries).err();
+                    }
+                }
+                if let Some(err) = self.boundaries_error {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+            });
+            ui.horizontal(|ui| {
+                #[cfg(feature = "russian")]
+                ui.selectable_value(&mut self.tool, Tool::Camera, "камера");
+                #[cfg(not(feature = "russian"))]
+                ui.selectable_value(&mut self.tool, Tool::Camera, "camera");
+                #[cfg(feature = "russian")]
+                ui.selectable_value(&mut self.tool, Tool::Erase, "стереть");
+                #[cfg(not(feature = "russian"))]
+                ui.selectable_value(&mut self.tool, Tool::Erase, "erase");
+                for cell in PALETTE {
+                    if let Some(it) = self.registry.get(cell) {
+                        ui.selectable_value(&mut self.tool, Tool::Place(cell), &it.name);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::Slider::new(&mut self.brush_radius, 0.5..=8.0));
+                #[cfg(feature = "russian")]
+                ui.label("радиус кисти");
+                #[cfg(not(feature = "russian"))]
+                ui.label("brush radius");
+            });
+            ui.horizontal(|ui| {
+                #[cfg(feature = "russian")]
+                {
+                    undo |= ui.button("отменить").clicked();
+                    redo |= ui.button("повторить").clicked();
+                }
+                #[cfg(not(feature = "russian"))]
+                {
+                    undo |= ui.button("undo").clicked();
+                    redo |= ui.button("redo").clicked();
+                }
+                ui.label(format!(
+                    "{} / {}, {:.1} MiB",
+                    self.history.undo_len(),
+                    self.history.redo_len(),
+                    self.history.used_bytes() as f32 / (1 << 20) as f32
+                ));
+            });
+            if undo || redo {
+                // simulation would immediately overwrite restored state
+                self.sim_clock.paused = true;
+                self.pull_gpu_sim();
+                let history = &mut self.history;
+                let dirty = self.map.restorable_mut().and_then(|sim| {
+                    if undo {
+                        history.undo(sim)
+                    } else {
+                        history.redo(sim)
+                    }
+                });
+                if let Some(dirty) = dirty {
+                    self.push_gpu_sim();
+                    self.renderer.update_map_region(self.map.as_dto(), &dirty);
+                }
+                self.upload_changed_regions();
+            }
             ui.horizontal(|ui| {
-                materials_changed |= ui
-                    .add(egui::Slider::new(
-                        &mut self.water_source_coord[0],
-                        1..=self.map.x() - 2,
-                    ))
-                    .dragged();
-                materials_changed |= ui
-                    .add(egui::Slider::new(
-                        &mut self.water_source_coord[1],
-                        1..=self.map.y() - 2,
-                    ))
-                    .dragged();
-                materials_changed |= ui
-                    .add(egui::Slider::new(
-                        &mut self.water_source_coord[2],
-                        1..=self.map.z() - 2,
-                    ))
-                    .dragged();
+                ui.add(egui::DragValue::new(&mut self.session_seed));
                 #[cfg(feature = "russian")]
-                ui.label("координаты источника воды");
+                let randomize_clicked = ui.button("случайное").clicked();
                 #[cfg(not(feature = "russian"))]
-                ui.label("water source coord");
+                let randomize_clicked = ui.button("randomize").clicked();
+                if randomize_clicked {
+                    self.session_seed = rand::random();
+                }
+                #[cfg(feature = "russian")]
+                ui.label("зерно сессии");
+                #[cfg(not(feature = "russian"))]
+                ui.label("session seed");
             });
             #[cfg(feature = "russian")]
-            ui.checkbox(&mut self.source_enabled, "включить источник воды");
+            let header = "генерация ландшафта";
             #[cfg(not(feature = "russian"))]
-            ui.checkbox(&mut self.source_enabled, "water source enable");
+            let header = "terrain";
+            ui.collapsing(header, |ui| {
+                egui::ComboBox::from_label({
+                    #[cfg(feature = "russian")]
+                    let l = "генератор";
+                    #[cfg(not(feature = "russian"))]
+                    let l = "generator";
+                    l
+                })
+                .selected_text(self.generators[self.generator].name())
+                .show_ui(ui, |ui| {
+                    for (i, it) in self.generators.iter().enumerate() {
+                        ui.selectable_value(&mut self.generator, i, it.name());
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for it in self.terrain.size.iter_mut() {
+                        ui.add(egui::DragValue::new(it).clamp_range(4..=256));
+                    }
+                    #[cfg(feature = "russian")]
+                    ui.label("размер");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("size");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.terrain.octaves).clamp_range(1..=8));
+                    #[cfg(feature = "russian")]
+                    ui.label("октавы");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("octaves");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut self.terrain.frequency)
+                            .speed(0.05)
+                            .clamp_range(0.05..=16.0),
+                    );
+                    #[cfg(feature = "russian")]
+                    ui.label("частота");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("frequency");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(egui::Slider::new(&mut self.terrain.amplitude, 0.0..=1.0));
+                    #[cfg(feature = "russian")]
+                    ui.label("амплитуда");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("amplitude");
+                });
+                ui.horizontal(|ui| {
+                    let options = [
+                        Sea::None,
+                        Sea::LowestGrass,
+                        Sea::Level(self.terrain.size[1] / 4),
+                    ];
+                    let sea = &mut self.terrain.sea;
+                    egui::ComboBox::from_id_source("sea")
+                        .selected_text(sea_label(sea))
+                        .show_ui(ui, |ui| {
+                            for it in options {
+                                let selected = it.name() == sea.name();
+                                if ui.selectable_label(selected, sea_label(&it)).clicked()
+                                    && !selected
+                                {
+                                    *sea = it;
+                                }
+                            }
+                        });
+                    if let Sea::Level(level) = sea {
+                        ui.add(
+                            egui::DragValue::new(level).clamp_range(0..=self.terrain.size[1] - 1),
+                        );
+                    }
+                    #[cfg(feature = "russian")]
+                    ui.label("море");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("sea");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut self.terrain.top_thickness).clamp_range(0..=16),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut self.terrain.soil_thickness).clamp_range(0..=64),
+                    );
+                    #[cfg(feature = "russian")]
+                    ui.label("толщина слоев");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("layer thickness");
+                });
+            });
             if {
                 #[cfg(feature = "russian")]
                 let result = ui.button("сбросить сцену").clicked();
@@ -440,29 +1127,157 @@ impl App {
                 let result = ui.button("reset scene").clicked();
                 result
             } {
-                let mut rng = Xorshift32::from_seed(Xorshift32Seed(rand::random::<[u8; 4]>()));
-                let mut perlin = Perlin::new(&mut rng);
-                let map = Map::with_perlin(40, 20, 40, &mut perlin);
-                let map = WaterSim::new(map);
-                self.map = map;
-
-                let materials = vec![
-                    Material::diffuse(Vector3::new(0.0, 0.0, 0.0)),
-                    Material::diffuse(Vector3::new(
-                        0.44313725490196076,
-                        0.6666666666666666,
-                        0.20392156862745098,
-                    )),
-                    Material::dielectric(Vector3::new(0.5, 0.5, 0.9), 2.045),
-                    Material::metal(
-                        Vector3::new(0.6274509803921569, 0.3568627450980392, 0.3254901960784314),
-                        0.5,
-                    ),
-                ];
-                self.materials = materials;
+                self.terrain.seed = derive_seed(self.session_seed, TERRAIN_STREAM);
+                let map = self.generators[self.generator].generate(&self.terrain);
+                let seed = derive_seed(self.session_seed, SIM_STREAM);
+                self.map = solver::default_solver(map, &self.registry, seed);
+                self.history.clear();
+                self.apply_sim_params();
+                self.apply_lava_params();
+                self.apply_erosion();
+                self.recreate_gpu_sim();
+                self.rng = Xorshift32::new(derive_seed(self.session_seed, APP_STREAM));
+                self.frame_counter = 0;
+                self.sim_clock.reset();
+                self.clamp_emitters();
+
+                self.materials = self.registry.materials();
                 self.renderer.update_map(self.map.as_dto());
                 materials_changed = true;
             }
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.scene_path);
+                    #[cfg(feature = "russian")]
+                    ui.label("файл сцены");
+                    #[cfg(not(feature = "russian"))]
+                    ui.label("scene file");
+                });
+                ui.horizontal(|ui| {
+                    #[cfg(feature = "russian")]
+                    let save_clicked = ui.button("сохранить сцену").clicked();
+                    #[cfg(not(feature = "russian"))]
+                    let save_clicked = ui.button("save scene").clicked();
+                    if save_clicked {
+                        self.pull_gpu_sim();
+                        let scene = Scene {
+                            settings: self.settings,
+                            camera: self.camera.pose(),
+                            session_seed: self.session_seed,
+                            materials: self.materials.clone(),
+                            sim: self.map.boxed_clone(),
+                            emitters: self.emitters.clone(),
+                            weather: self.weather,
+                        };
+                        self.scene_status = match scene.save(&self.scene_path) {
+                            Ok(_) => format!("saved {}", self.scene_path),
+                            Err(err) => format!("{}", err),
+                        };
+                    }
+                    #[cfg(feature = "russian")]
+                    let load_clicked = ui.button("загрузить сцену").clicked();
+                    #[cfg(not(feature = "russian"))]
+                    let load_clicked = ui.button("load scene").clicked();
+                    if load_clicked {
+                        match Scene::load(&self.scene_path) {
+                            Ok(scene) => {
+                                self.settings = scene.settings;
+                                self.camera.set_pose(scene.camera);
+                                self.session_seed = scene.session_seed;
+                                // the app stream restarts, so the scene replays the same way
+                                // whatever happened earlier in the session
+                                self.rng =
+                                    Xorshift32::new(derive_seed(self.session_seed, APP_STREAM));
+                                self.frame_counter = 0;
+                                self.sim_clock.reset();
+                                self.registry = scene.sim.registry().clone();
+                                self.materials = scene.materials;
+                                self.sim_params = scene.sim.params().unwrap_or_default();
+                                self.lava_params =
+                                    scene.sim.lava_params().unwrap_or_else(WaterSimParams::lava);
+                                self.map = scene.sim;
+                                self.emitters = scene.emitters;
+                                self.weather = scene.weather;
+                                self.sim_params_error = None;
+                                self.lava_params_error = None;
+                                self.erosion = self.map.erosion().unwrap_or_default();
+                                self.erosion_error = None;
+                                self.boundaries_error = None;
+                                self.history.clear();
+                                self.recreate_gpu_sim();
+                                self.renderer.update_map(self.map.as_dto());
+                                self.renderer.update_settings(self.settings.as_dto());
+                                self.renderer.set_enable_gauss(self.settings.enable_gauss);
+                                camera_was_changed = true;
+                                materials_changed = true;
+                                self.scene_status = format!("loaded {}", self.scene_path);
+                            }
+                            Err(err) => self.scene_status = format!("{}", err),
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.vox_path);
+                    #[cfg(feature = "russian")]
+                    let import_clicked = ui.button("импорт .vox").clicked();
+                    #[cfg(not(feature = "russian"))]
+                    let import_clicked = ui.button("import .vox").clicked();
+                    if import_clicked {
+                        let mut registry = self.registry.clone();
+                        match vox::import_file(
+                            &self.vox_path,
+                            &mut registry,
+                            &VoxImportOptions::default(),
+                        ) {
+                            Ok(import) => {
+                                for it in registry.iter().skip(self.materials.len()) {
+                                    self.materials.push(it.material);
+                                }
+                                self.registry = registry;
+                                let seed = derive_seed(self.session_seed, SIM_STREAM);
+                                self.map = solver::default_solver(import.map, &self.registry, seed);
+                                self.history.clear();
+                                self.clamp_emitters();
+                                self.apply_sim_params();
+                                self.apply_lava_params();
+                                self.apply_erosion();
+                                self.recreate_gpu_sim();
+                                self.renderer.update_map(self.map.as_dto());
+                                materials_changed = true;
+                                self.scene_status =
+                                    format!("imported {} models", import.model_count);
+                                if !import.unsupported_chunks.is_empty() {
+                                    self.scene_status += &format!(
+                                        ", skipped chunks: {}",
+                                        import.unsupported_chunks.join(" ")
+                                    );
+                                }
+                            }
+                            Err(err) => self.scene_status = format!("{}", err),
+                        }
+                    }
+                    #[cfg(feature = "russian")]
+                    let export_clicked = ui.button("экспорт .vox").clicked();
+                    #[cfg(not(feature = "russian"))]
+                    let export_clicked = ui.button("export .vox").clicked();
+                    if export_clicked {
+                        self.pull_gpu_sim();
+                        self.scene_status = match vox::export_file(
+                            &self.vox_path,
+                            self.map.as_ref(),
+                            &self.materials,
+                            UpAxis::Z,
+                        ) {
+                            Ok(_) => format!("exported {}", self.vox_path),
+                            Err(err) => format!("{}", err),
+                        };
+                    }
+                });
+                if !self.scene_status.is_empty() {
+                    ui.label(&self.scene_status);
+                }
+            }
             if materials_changed {
                 let material_dto = self
                     .materials
@@ -514,6 +1329,16 @@ impl App {
             ui.label("для движения камеры зажать ЛКМ и перемещать мышь");
             #[cfg(not(feature = "russian"))]
             ui.label("to move camera hold left mouse button and move mouse");
+
+            #[cfg(feature = "russian")]
+            ui.label("редактирование: выбрать инструмент, ЛКМ - применить, ПКМ - стереть");
+            #[cfg(not(feature = "russian"))]
+            ui.label("editing: pick a tool, left click to apply, right click to erase");
+
+            #[cfg(feature = "russian")]
+            ui.label("отмена: Ctrl+Z, повтор: Ctrl+Y");
+            #[cfg(not(feature = "russian"))]
+            ui.label("undo: Ctrl+Z, redo: Ctrl+Y");
         });
 
         self.renderer
@@ -528,6 +1353,132 @@ impl App {
         self.input.next_frame();
     }
 
+    /// Runs one simulation tick. Changed cells of `map` are uploaded by the
+    /// caller, GPU simulation updates the texture by itself.
+    fn sim_step(&mut self) {
+        if let Some(gpu_sim) = &mut self.gpu_sim {
+            // solids never change during simulation, so stale cells are good enough here
+            self.renderer
+                .set_gpu_sim_emitters(gpu_sim, &self.emitters, self.map.as_ref());
+            self.renderer.step_gpu_sim(gpu_sim);
+            return;
+        }
+
+        if let Some(sim) = self.map.restorable_mut() {
+            self.history.begin(sim);
+        }
+        for it in self.emitters.iter() {
+            it.apply(self.map.as_mut());
+        }
+        // rain needs the generator of the automaton, so that history and scenes replay it
+        match self.map.restorable_mut() {
+            Some(sim) => {
+                let mut rng = sim.rng();
+                self.weather.apply(sim, &mut rng);
+                sim.set_rng(rng);
+            }
+            None => self.weather.apply(self.map.as_mut(), &mut self.rng),
+        }
+        self.map.step();
+        if let Some(sim) = self.map.restorable_mut() {
+            self.history.commit(sim, EntryKind::SimStep);
+        }
+    }
+
+    /// Uploads cells changed since the last upload, by steps as well as by
+    /// fill levels around edited and restored cells.
+    fn upload_changed_regions(&mut self) {
+        for region in self.map.take_changed_regions() {
+            self.renderer.update_map_region(self.map.as_dto(), &region);
+        }
+    }
+
+    /// Keeps emitters inside of the grid after it was replaced.
+    fn clamp_emitters(&mut self) {
+        let editable = self.map.editable();
+        for it in self.emitters.iter_mut() {
+            for axis in 0..3 {
+                it.position[axis] =
+                    it.position[axis].clamp(editable.min[axis], editable.max[axis] - 1);
+            }
+        }
+    }
+
+    /// Passes edited parameters to the simulation if they are valid.
+    fn apply_sim_params(&mut self) {
+        self.sim_params_error = self.map.set_params(self.sim_params).err();
+        if let (None, Some(gpu_sim)) = (self.sim_params_error, &mut self.gpu_sim) {
+            self.renderer.set_gpu_sim_params(gpu_sim, &self.sim_params);
+        }
+    }
+
+    /// Passes edited lava parameters to the simulation if they are valid.
+    fn apply_lava_params(&mut self) {
+        self.lava_params_error = self.map.set_lava_params(self.lava_params).err();
+    }
+
+    /// Passes edited erosion settings to the simulation if they are valid.
+    fn apply_erosion(&mut self) {
+        self.erosion_error = self.map.set_erosion(self.erosion.clone()).err();
+    }
+
+    /// Copies GPU simulation state into `map` before it is read or edited.
+    fn pull_gpu_sim(&mut self) {
+        if let (Some(gpu_sim), Some(sim)) = (&self.gpu_sim, self.map.restorable_mut()) {
+            if !self.renderer.download_gpu_sim(gpu_sim, sim) {
+                self.gpu_sim_error = Some("failed to read simulation state from GPU");
+            }
+        }
+    }
+
+    /// Sends edited `map` back to the GPU simulation. Simulation continues on
+    /// the CPU if the edit made it unsupported, e.g. by placing lava.
+    fn push_gpu_sim(&mut self) {
+        let Some(gpu_sim) = &mut self.gpu_sim else {
+            return;
+        };
+        match GpuWaterSim::supports(self.map.as_ref()) {
+            Ok(()) => self.renderer.upload_gpu_sim(gpu_sim, self.map.as_ref()),
+            Err(err) => {
+                self.gpu_sim = None;
+                self.gpu_sim_error = Some(err);
+            }
+        }
+    }
+
+    /// Moves simulation to the GPU, the reason is shown if it can not be.
+    fn start_gpu_sim(&mut self) {
+        match self.renderer.create_gpu_sim(self.map.as_ref()) {
+            Ok(gpu_sim) => self.gpu_sim = Some(gpu_sim),
+            Err(err) => {
+                self.gpu_sim = None;
+                self.gpu_sim_error = Some(err);
+            }
+        }
+    }
+
+    /// Must be called after `map` is replaced, the grid size may have changed.
+    fn recreate_gpu_sim(&mut self) {
+        if self.gpu_sim.is_some() {
+            self.start_gpu_sim();
+        }
+    }
+
+    /// Voxel under the cursor.
+    fn pick(&self) -> Option<Pick> {
+        let size = self.renderer.size();
+        let pos = self.input.mouse_pos();
+        let ndc = Vector2::new(
+            (2.0 * pos.x / size.width as f64 - 1.0) as f32,
+            (1.0 - 2.0 * pos.y / size.height as f64) as f32,
+        );
+        picking::voxel_traverse(
+            self.map.as_ref(),
+            &self.camera.ray(ndc),
+            self.settings.maximum_traversal_distance,
+        )
+    }
+
     pub fn run(mut self, event_loop: EventLoop<()>) {
         let mut is_initialized = false;
         event_loop.run(move |event, _, control_flow| {
@@ -558,3 +1509,148 @@ impl App {
         });
     }
 }
+
+/// Top-down view of the block grid, brighter columns have more active blocks.
+fn draw_block_activity(ui: &mut egui::Ui, activity: &BlockActivity) {
+    const BLOCK_PIXELS: f32 = 8.0;
+    let [cx, cy, cz] = activity.counts;
+    let active = activity.active.iter().filter(|it| **it).count();
+    ui.label(format!("{} / {}", active, activity.active.len()));
+
+    let size = egui::vec2(cx as f32, cz as f32) * BLOCK_PIXELS;
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    for bz in 0..cz {
+        for bx in 0..cx {
+            let count = (0..cy)
+                .filter(|by| activity.active[(bz * cy + by) * cx + bx])
+                .count();
+            let t = count as f32 / cy.max(1) as f32;
+            let color = if count == 0 {
+                egui::Color32::from_gray(40)
+            } else {
+                egui::Color32::from_rgb(40, (80.0 + 175.0 * t) as u8, 40)
+            };
+            let min = rect.min + egui::vec2(bx as f32, bz as f32) * BLOCK_PIXELS;
+            let block = egui::Rect::from_min_size(min, egui::vec2(BLOCK_PIXELS, BLOCK_PIXELS));
+            painter.rect_filled(block.shrink(0.5), 0.0, color);
+        }
+    }
+}
+
+fn emitter_kind_label(kind: EmitterKind) -> &'static str {
+    #[cfg(feature = "russian")]
+    return match kind {
+        EmitterKind::Source => "источник",
+        EmitterKind::Sink => "сток",
+        EmitterKind::Drain => "слив",
+    };
+    #[cfg(not(feature = "russian"))]
+    kind.name()
+}
+
+/// Fields of fluid parameters with a button that resets them to `defaults`.
+/// Returns true if anything was changed.
+fn params_ui(ui: &mut egui::Ui, params: &mut WaterSimParams, defaults: WaterSimParams) -> bool {
+    let mut changed = false;
+    #[cfg(feature = "russian")]
+    let fields = [
+        (&mut params.max_mass, "максимальная масса"),
+        (&mut params.max_compress, "сжатие"),
+        (&mut params.min_mass, "минимальная масса"),
+        (&mut params.min_flow, "минимальный поток"),
+        (&mut params.max_speed, "максимальная скорость"),
+        (&mut params.spread, "делитель растекания"),
+    ];
+    #[cfg(not(feature = "russian"))]
+    let fields = [
+        (&mut params.max_mass, "max mass"),
+        (&mut params.max_compress, "max compress"),
+        (&mut params.min_mass, "min mass"),
+        (&mut params.min_flow, "min flow"),
+        (&mut params.max_speed, "max speed"),
+        (&mut params.spread, "spread"),
+    ];
+    for (value, label) in fields {
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(egui::DragValue::new(value).speed(0.001).max_decimals(4))
+                .changed();
+            ui.label(label);
+        });
+    }
+    ui.horizontal(|ui| {
+        changed |= ui
+            .add(
+                egui::DragValue::new(&mut params.sleep_threshold)
+                    .speed(0.00001)
+                    .max_decimals(6),
+            )
+            .changed();
+        #[cfg(feature = "russian")]
+        ui.label("порог засыпания");
+        #[cfg(not(feature = "russian"))]
+        ui.label("sleep threshold");
+    });
+    #[cfg(feature = "russian")]
+    let reset_clicked = ui.button("по умолчанию").clicked();
+    #[cfg(not(feature = "russian"))]
+    let reset_clicked = ui.button("defaults").clicked();
+    if reset_clicked {
+        *params = defaults;
+        changed = true;
+    }
+    changed
+}
+
+fn boundary_label(boundary: &Boundary) -> &'static str {
+    #[cfg(feature = "russian")]
+    return match boundary {
+        Boundary::Closed => "стена",
+        Boundary::Drain => "сток",
+        Boundary::Ocean { .. } => "океан",
+        Boundary::Periodic => "периодическая",
+    };
+    #[cfg(not(feature = "russian"))]
+    boundary.name()
+}
+
+fn sea_label(sea: &Sea) -> &'static str {
+    #[cfg(feature = "russian")]
+    return match sea {
+        Sea::None => "нет",
+        Sea::LowestGrass => "на нижней траве",
+        Sea::Level(_) => "уровень",
+    };
+    #[cfg(not(feature = "russian"))]
+    sea.name()
+}
+
+fn rain_distribution_label(distribution: &RainDistribution) -> &'static str {
+    #[cfg(feature = "russian")]
+    return match distribution {
+        RainDistribution::Uniform => "равномерно",
+        RainDistribution::Scattered { .. } => "местами",
+        RainDistribution::Cloud { .. } => "туча",
+    };
+    #[cfg(not(feature = "russian"))]
+    distribution.name()
+}
+
+/// Middle of the top layer of `bounds`, where new emitters are placed.
+fn top_center(bounds: &Bounds) -> [usize; 3] {
+    [
+        (bounds.min[0] + bounds.max[0]) / 2,
+        bounds.max[1] - 1,
+        (bounds.min[2] + bounds.max[2]) / 2,
+    ]
+}
+
+/// Sphere around the center of the voxel, radius of 0.5 covers only that voxel.
+fn brush_shape(voxel: [usize; 3], radius: f32) -> Shape {
+    Shape::Sphere {
+        center: Vector3::new(voxel[0] as f32, voxel[1] as f32, voxel[2] as f32)
+            + Vector3::repeat(0.5),
+        radius,
+    }
+}
@@ -0,0 +1,241 @@
+//! Runs `WaterSim::simulate` on a generated map without a window and writes
+//! timing and water statistics of every step as CSV.
+//! Run with `cargo run --release --bin sim_bench -- --help`.
+use std::io::Write;
+use voxel_water::cells::CellRegistry;
+use voxel_water::map::WaterSim;
+use voxel_water::terrain::{self, Sea, TerrainParams};
+
+const USAGE: &str = "\
+usage: sim_bench [options]
+  --seed N             terrain seed (default 1)
+  --size X,Y,Z         map size in cells (default 80,40,80)
+  --terrain NAME       terrain generator (default basin)
+  --sea-level N        water level, 0 for none (default a third of the height)
+  --steps N            measured steps (default 100)
+  --warmup N           steps run before measuring (default 10)
+  --threads N[,N...]   thread counts, each runs on a fresh copy of the map
+                       (default all available)
+  --out PATH           output file (default stdout)
+
+Every measured step is written as a line of
+  step,threads,microseconds,total mass,created,removed,drift,active blocks
+after a header line starting with '#'. The first three columns are read by
+scripts/plot.py, with one curve per thread count.";
+
+struct Options {
+    seed: u32,
+    size: [usize; 3],
+    terrain: String,
+    /// `--sea-level 0` gives a map without water
+    sea: Sea,
+    steps: usize,
+    warmup: usize,
+    threads: Vec<usize>,
+    out: Option<String>,
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+fn parse_list(name: &str, value: &str) -> Result<Vec<usize>, String> {
+    value.split(',').map(|it| parse_number(name, it)).collect()
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        seed: 1,
+        size: [80, 40, 80],
+        terrain: String::from("basin"),
+        sea: Sea::None,
+        steps: 100,
+        warmup: 10,
+        threads: vec![std::thread::available_parallelism().map_or(1, |it| it.get())],
+        out: None,
+    };
+    let mut sea_level = None;
+    while let Some(name) = args.next() {
+        if name == "--help" || name == "-h" {
+            return Err(String::new());
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", name))?;
+        match name.as_str() {
+            "--seed" => options.seed = parse_number(&name, &value)?,
+            "--size" => {
+                options.size = parse_list(&name, &value)?
+                    .try_into()
+                    .map_err(|_| format!("{} needs three values", name))?;
+            }
+            "--terrain" => options.terrain = value,
+            "--sea-level" => sea_level = Some(parse_number(&name, &value)?),
+            "--steps" => options.steps = parse_number(&name, &value)?,
+            "--warmup" => options.warmup = parse_number(&name, &value)?,
+            "--threads" => options.threads = parse_list(&name, &value)?,
+            "--out" => options.out = Some(value),
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+
+    if options.size.contains(&0) {
+        return Err(String::from("size must not be zero"));
+    }
+    if options.threads.contains(&0) {
+        return Err(String::from("thread count must not be zero"));
+    }
+    options.sea = match sea_level {
+        Some(0) => Sea::None,
+        Some(level) => Sea::Level(level),
+        None => Sea::Level(options.size[1] / 3),
+    };
+    Ok(options)
+}
+
+fn run(options: &Options, out: &mut impl Write) -> std::io::Result<()> {
+    let generator = terrain::generators()
+        .into_iter()
+        .find(|it| it.name() == options.terrain)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown terrain {}", options.terrain),
+            )
+        })?;
+    let params = TerrainParams {
+        seed: options.seed,
+        size: options.size,
+        sea: options.sea,
+        ..Default::default()
+    };
+    let sim = WaterSim::new(generator.generate(&params), &CellRegistry::default());
+
+    writeln!(
+        out,
+        "# step,threads,microseconds,total mass,created,removed,drift,active blocks"
+    )?;
+    for &threads in options.threads.iter() {
+        let mut sim = sim.clone();
+        sim.reseed(options.seed);
+        sim.set_threads(threads);
+        for _ in 0..options.warmup {
+            sim.simulate();
+        }
+        for step in 0..options.steps {
+            let start = std::time::Instant::now();
+            sim.simulate();
+            let micros = start.elapsed().as_secs_f64() * 1e6;
+            let stats = sim.stats();
+            let active = sim.block_activity().active.iter().filter(|it| **it).count();
+            writeln!(
+                out,
+                "{},{},{:.3},{:.6},{:.6},{:.6},{:e},{}",
+                step,
+                threads,
+                micros,
+                stats.total,
+                stats.created,
+                stats.removed,
+                stats.drift,
+                active
+            )?;
+        }
+    }
+    out.flush()
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("{}", err);
+            }
+            eprintln!("{}", USAGE);
+            std::process::exit(if err.is_empty() { 0 } else { 2 });
+        }
+    };
+
+    let result = match &options.out {
+        Some(path) => std::fs::File::create(path)
+            .and_then(|file| run(&options, &mut std::io::BufWriter::new(file))),
+        None => run(&options, &mut std::io::stdout().lock()),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|it| it.to_string()))
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse(&[
+            "--seed",
+            "7",
+            "--size",
+            "10,20,30",
+            "--terrain",
+            "terraces",
+            "--steps",
+            "5",
+            "--warmup",
+            "0",
+            "--threads",
+            "1,4",
+            "--out",
+            "bench.csv",
+        ])
+        .unwrap();
+        assert_eq!(options.seed, 7);
+        assert_eq!(options.size, [10, 20, 30]);
+        assert_eq!(options.terrain, "terraces");
+        assert_eq!(options.steps, 5);
+        assert_eq!(options.warmup, 0);
+        assert_eq!(options.threads, [1, 4]);
+        assert_eq!(options.out.as_deref(), Some("bench.csv"));
+        // a third of the height by default
+        assert_eq!(options.sea, Sea::Level(6));
+    }
+
+    #[test]
+    fn zero_sea_level_means_no_water() {
+        assert_eq!(parse(&["--sea-level", "5"]).unwrap().sea, Sea::Level(5));
+        let options = parse(&["--sea-level", "0", "--size", "12,12,12"]).unwrap();
+        assert_eq!(options.sea, Sea::None);
+
+        let mut out = Vec::new();
+        let options = Options {
+            steps: 1,
+            warmup: 0,
+            threads: vec![1],
+            ..options
+        };
+        run(&options, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let total_mass = out.lines().nth(1).unwrap().split(',').nth(3).unwrap();
+        assert_eq!(total_mass.parse::<f32>().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert_eq!(parse(&["--help"]).err().as_deref(), Some(""));
+        assert!(parse(&["--size", "1,2"]).is_err());
+        assert!(parse(&["--size", "1,0,2"]).is_err());
+        assert!(parse(&["--threads", "0"]).is_err());
+        assert!(parse(&["--steps"]).is_err());
+        assert!(parse(&["--steps", "many"]).is_err());
+        assert!(parse(&["--colour", "blue"]).is_err());
+    }
+}
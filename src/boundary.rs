@@ -0,0 +1,123 @@
+/// Side of the simulated box, named by the axis it is perpendicular to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+}
+
+impl Face {
+    pub const ALL: [Self; 6] = [
+        Self::NegX,
+        Self::PosX,
+        Self::NegY,
+        Self::PosY,
+        Self::NegZ,
+        Self::PosZ,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NegX => "-x",
+            Self::PosX => "+x",
+            Self::NegY => "-y",
+            Self::PosY => "+y",
+            Self::NegZ => "-z",
+            Self::PosZ => "+z",
+        }
+    }
+
+    pub fn axis(&self) -> usize {
+        *self as usize / 2
+    }
+
+    pub fn is_positive(&self) -> bool {
+        *self as usize % 2 == 1
+    }
+
+    pub fn opposite(&self) -> Self {
+        Self::ALL[*self as usize ^ 1]
+    }
+}
+
+/// What happens to fluid at a face of the simulated box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    /// Acts as a solid wall
+    Closed,
+    /// Fluid flowing out leaves the map
+    Drain,
+    /// Open sea filled up to `level` (padded y coordinate, exclusive): fluid
+    /// flowing out leaves the map and cells next to the face below the level
+    /// are kept full
+    Ocean { level: usize },
+    /// Fluid flowing out enters at the opposite face, which has to be periodic too
+    Periodic,
+}
+
+impl Boundary {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Drain => "drain",
+            Self::Ocean { .. } => "ocean",
+            Self::Periodic => "periodic",
+        }
+    }
+}
+
+/// Boundary of every face, indexed by `Face`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Boundaries(pub [Boundary; 6]);
+
+impl Default for Boundaries {
+    /// Walls at the near x and z faces, the other faces drain.
+    fn default() -> Self {
+        let mut faces = [Boundary::Drain; 6];
+        faces[Face::NegX as usize] = Boundary::Closed;
+        faces[Face::NegZ as usize] = Boundary::Closed;
+        Self(faces)
+    }
+}
+
+impl Boundaries {
+    pub fn get(&self, face: Face) -> Boundary {
+        self.0[face as usize]
+    }
+
+    pub fn set(&mut self, face: Face, boundary: Boundary) {
+        self.0[face as usize] = boundary;
+    }
+
+    /// Faces at the far x and z sides that drain become oceans up to `level`,
+    /// so that water of a map stays at its level.
+    pub fn with_sea(mut self, level: usize) -> Self {
+        for face in [Face::PosX, Face::PosZ] {
+            if self.get(face) == Boundary::Drain {
+                self.set(face, Boundary::Ocean { level });
+            }
+        }
+        self
+    }
+
+    /// Checks that periodic faces come in pairs and that ocean levels are
+    /// inside of a grid of padded `height`.
+    pub fn validate(&self, height: usize) -> Result<(), &'static str> {
+        for face in Face::ALL {
+            let periodic = |face: Face| self.get(face) == Boundary::Periodic;
+            if periodic(face) != periodic(face.opposite()) {
+                return Err("opposite faces must both be periodic");
+            }
+            match self.get(face) {
+                Boundary::Ocean { level } if level >= height => {
+                    return Err("ocean level is above the map");
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
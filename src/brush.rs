@@ -0,0 +1,389 @@
+use crate::cells::Cell;
+use crate::map::{Map, WaterSim};
+use crate::math::*;
+
+/// Box of cells, `min` is inclusive and `max` is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: [usize; 3],
+    pub max: [usize; 3],
+}
+
+impl Bounds {
+    pub fn point(p: [usize; 3]) -> Self {
+        Self {
+            min: p,
+            max: [p[0] + 1, p[1] + 1, p[2] + 1],
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: [0, 1, 2].map(|i| self.min[i].min(other.min[i])),
+            max: [0, 1, 2].map(|i| self.max[i].max(other.max[i])),
+        }
+    }
+
+    pub fn contains(&self, p: [usize; 3]) -> bool {
+        (0..3).all(|i| p[i] >= self.min[i] && p[i] < self.max[i])
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        [0, 1, 2].map(|i| self.max[i].saturating_sub(self.min[i]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size().contains(&0)
+    }
+}
+
+/// Grid that can be edited with brushes.
+pub trait VoxelGrid {
+    fn size(&self) -> [usize; 3];
+    /// Part of the grid brushes are allowed to modify.
+    fn editable(&self) -> Bounds {
+        Bounds {
+            min: [0; 3],
+            max: self.size(),
+        }
+    }
+    fn get(&self, p: [usize; 3]) -> Cell;
+    fn set(&mut self, p: [usize; 3], cell: Cell);
+}
+
+impl VoxelGrid for Map {
+    fn size(&self) -> [usize; 3] {
+        [self.x(), self.y(), self.z()]
+    }
+
+    fn get(&self, p: [usize; 3]) -> Cell {
+        self.at(p[0], p[1], p[2])
+    }
+
+    fn set(&mut self, p: [usize; 3], cell: Cell) {
+        *self.at_mut(p[0], p[1], p[2]) = cell;
+    }
+}
+
+impl VoxelGrid for WaterSim {
+    fn size(&self) -> [usize; 3] {
+        [self.x(), self.y(), self.z()]
+    }
+
+    /// Padding border is never edited.
+    fn editable(&self) -> Bounds {
+        Bounds {
+            min: [1; 3],
+            max: [self.x() - 1, self.y() - 1, self.z() - 1],
+        }
+    }
+
+    fn get(&self, p: [usize; 3]) -> Cell {
+        self.at(p[0], p[1], p[2])
+    }
+
+    fn set(&mut self, p: [usize; 3], cell: Cell) {
+        self.set_cell(p[0], p[1], p[2], cell);
+    }
+}
+
+/// Brush shapes in grid coordinates, cell (x, y, z) occupies [x, x + 1) on each axis.
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    /// Inclusive box of cells
+    Box {
+        min: [i32; 3],
+        max: [i32; 3],
+    },
+    Sphere {
+        center: Vector3,
+        radius: f32,
+    },
+    /// Vertical cylinder standing on `base`
+    Cylinder {
+        base: Vector3,
+        radius: f32,
+        height: f32,
+    },
+    /// Segment with thickness
+    Line {
+        from: Vector3,
+        to: Vector3,
+        radius: f32,
+    },
+}
+
+impl Shape {
+    fn aabb(&self) -> (Vector3, Vector3) {
+        match *self {
+            Self::Box { min, max } => (
+                Vector3::new(min[0] as f32, min[1] as f32, min[2] as f32),
+                Vector3::new(max[0] as f32, max[1] as f32, max[2] as f32) + Vector3::repeat(1.0),
+            ),
+            Self::Sphere { center, radius } => (
+                center - Vector3::repeat(radius),
+                center + Vector3::repeat(radius),
+            ),
+            Self::Cylinder {
+                base,
+                radius,
+                height,
+            } => (
+                base - Vector3::new(radius, 0.0, radius),
+                base + Vector3::new(radius, height, radius),
+            ),
+            Self::Line { from, to, radius } => (
+                from.inf(&to) - Vector3::repeat(radius),
+                from.sup(&to) + Vector3::repeat(radius),
+            ),
+        }
+    }
+
+    /// Cell belongs to the shape if its center does.
+    fn contains(&self, p: Vector3) -> bool {
+        match *self {
+            Self::Box { .. } => true,
+            Self::Sphere { center, radius } => (p - center).norm_squared() <= radius * radius,
+            Self::Cylinder {
+                base,
+                radius,
+                height,
+            } => {
+                let d = Vector2::new(p.x - base.x, p.z - base.z);
+                p.y >= base.y && p.y <= base.y + height && d.norm_squared() <= radius * radius
+            }
+            Self::Line { from, to, radius } => {
+                let d = to - from;
+                let len_sq = d.norm_squared();
+                let t = if len_sq > 0.0 {
+                    ((p - from).dot(&d) / len_sq).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                };
+                (p - (from + d * t)).norm_squared() <= radius * radius
+            }
+        }
+    }
+
+    /// Cells possibly covered by shape, clipped to `limits`.
+    fn cell_bounds(&self, limits: &Bounds) -> Bounds {
+        let (min, max) = self.aabb();
+        let clip = |v: f32, i: usize| {
+            (v.max(0.0) as usize).clamp(limits.min[i], limits.max[i].max(limits.min[i]))
+        };
+        Bounds {
+            min: [
+                clip(min.x.floor(), 0),
+                clip(min.y.floor(), 1),
+                clip(min.z.floor(), 2),
+            ],
+            max: [
+                clip(max.x.ceil(), 0),
+                clip(max.y.ceil(), 1),
+                clip(max.z.ceil(), 2),
+            ],
+        }
+    }
+}
+
+/// Sets all cells inside the shape. Returns bounding box of changed cells.
+pub fn fill(grid: &mut (impl VoxelGrid + ?Sized), shape: &Shape, cell: Cell) -> Option<Bounds> {
+    let area = shape.cell_bounds(&grid.editable());
+    let mut dirty: Option<Bounds> = None;
+    for z in area.min[2]..area.max[2] {
+        for y in area.min[1]..area.max[1] {
+            for x in area.min[0]..area.max[0] {
+                let center = Vector3::new(x as f32, y as f32, z as f32) + Vector3::repeat(0.5);
+                if !shape.contains(center) || grid.get([x, y, z]) == cell {
+                    continue;
+                }
+
+                grid.set([x, y, z], cell);
+                let p = Bounds::point([x, y, z]);
+                dirty = Some(dirty.map_or(p, |it| it.union(&p)));
+            }
+        }
+    }
+    dirty
+}
+
+pub fn clear(grid: &mut (impl VoxelGrid + ?Sized), shape: &Shape) -> Option<Bounds> {
+    fill(grid, shape, Cell::NONE)
+}
+
+/// Replaces face-connected region of cells of the same type as `start`.
+/// Stops after `limit` cells, so that filling open air does not walk the whole grid.
+pub fn flood_fill(
+    grid: &mut (impl VoxelGrid + ?Sized),
+    start: [usize; 3],
+    cell: Cell,
+    limit: usize,
+) -> Option<Bounds> {
+    let editable = grid.editable();
+    if limit == 0 || !editable.contains(start) {
+        return None;
+    }
+    let target = grid.get(start);
+    if target == cell {
+        return None;
+    }
+
+    let mut dirty = Bounds::point(start);
+    let mut stack = vec![start];
+    let mut count = 1;
+    grid.set(start, cell);
+    while let Some(p) = stack.pop() {
+        for axis in 0..3 {
+            for forward in [false, true] {
+                let mut n = p;
+                if forward {
+                    n[axis] += 1;
+                } else if n[axis] == 0 {
+                    continue;
+                } else {
+                    n[axis] -= 1;
+                }
+
+                if !editable.contains(n) || grid.get(n) != target {
+                    continue;
+                }
+                if count == limit {
+                    return Some(dirty);
+                }
+                count += 1;
+                grid.set(n, cell);
+                dirty = dirty.union(&Bounds::point(n));
+                stack.push(n);
+            }
+        }
+    }
+    Some(dirty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::CellRegistry;
+
+    fn cells_of(grid: &impl VoxelGrid, cell: Cell) -> Vec<[usize; 3]> {
+        let size = grid.size();
+        let mut cells = Vec::new();
+        for z in 0..size[2] {
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    if grid.get([x, y, z]) == cell {
+                        cells.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    #[test]
+    fn box_fills_inclusive_range() {
+        let mut map = Map::empty(6, 6, 6);
+        let shape = Shape::Box {
+            min: [1, 2, 3],
+            max: [2, 2, 4],
+        };
+        let dirty = fill(&mut map, &shape, Cell::STONE).unwrap();
+
+        assert_eq!(dirty.min, [1, 2, 3]);
+        assert_eq!(dirty.max, [3, 3, 5]);
+        assert_eq!(
+            cells_of(&map, Cell::STONE),
+            [[1, 2, 3], [2, 2, 3], [1, 2, 4], [2, 2, 4]]
+        );
+        // nothing changes the second time
+        assert_eq!(fill(&mut map, &shape, Cell::STONE), None);
+    }
+
+    #[test]
+    fn round_shapes_take_cells_with_center_inside() {
+        let mut map = Map::empty(8, 8, 8);
+        let sphere = Shape::Sphere {
+            center: Vector3::repeat(4.0),
+            radius: 1.0,
+        };
+        fill(&mut map, &sphere, Cell::STONE);
+        // 2x2x2 cells around the center, their centers are sqrt(0.75) away
+        assert_eq!(cells_of(&map, Cell::STONE).len(), 8);
+
+        let mut map = Map::empty(8, 8, 8);
+        let cylinder = Shape::Cylinder {
+            base: Vector3::new(4.0, 1.0, 4.0),
+            radius: 1.0,
+            height: 3.0,
+        };
+        let dirty = fill(&mut map, &cylinder, Cell::STONE).unwrap();
+        assert_eq!(cells_of(&map, Cell::STONE).len(), 4 * 3);
+        assert_eq!(dirty.min, [3, 1, 3]);
+        assert_eq!(dirty.max, [5, 4, 5]);
+
+        let mut map = Map::empty(8, 8, 8);
+        let line = Shape::Line {
+            from: Vector3::new(1.5, 2.5, 2.5),
+            to: Vector3::new(6.5, 2.5, 2.5),
+            radius: 0.5,
+        };
+        fill(&mut map, &line, Cell::STONE);
+        assert_eq!(
+            cells_of(&map, Cell::STONE),
+            (1..7).map(|x| [x, 2, 2]).collect::<Vec<_>>()
+        );
+        assert_eq!(clear(&mut map, &line).unwrap().size(), [6, 1, 1]);
+        assert!(cells_of(&map, Cell::STONE).is_empty());
+    }
+
+    #[test]
+    fn shapes_are_clipped_to_padding_border() {
+        let mut sim = WaterSim::new(Map::empty(4, 4, 4), &CellRegistry::default());
+        let sphere = Shape::Sphere {
+            center: Vector3::zeros(),
+            radius: 3.0,
+        };
+        let dirty = fill(&mut sim, &sphere, Cell::STONE).unwrap();
+
+        assert_eq!(dirty.min, [1; 3]);
+        let stone = cells_of(&sim, Cell::STONE);
+        assert!(!stone.is_empty());
+        assert!(stone.iter().all(|it| sim.editable().contains(*it)));
+        assert!(dirty.max.iter().all(|it| *it <= 5));
+
+        // completely outside of the editable area
+        let outside = Shape::Box {
+            min: [-4, -4, -4],
+            max: [0, 0, 0],
+        };
+        assert_eq!(fill(&mut sim, &outside, Cell::STONE), None);
+    }
+
+    #[test]
+    fn flood_fill_replaces_connected_cells_up_to_limit() {
+        let mut map = Map::empty(6, 3, 3);
+        // wall at x = 3 splits the map in two
+        fill(
+            &mut map,
+            &Shape::Box {
+                min: [3, 0, 0],
+                max: [3, 2, 2],
+            },
+            Cell::STONE,
+        );
+
+        let dirty = flood_fill(&mut map, [0, 0, 0], Cell::WATER, usize::MAX).unwrap();
+        assert_eq!(dirty.min, [0, 0, 0]);
+        assert_eq!(dirty.max, [3, 3, 3]);
+        assert_eq!(cells_of(&map, Cell::WATER).len(), 3 * 3 * 3);
+        assert_eq!(
+            flood_fill(&mut map, [0, 0, 0], Cell::WATER, usize::MAX),
+            None
+        );
+
+        let filled = flood_fill(&mut map, [5, 0, 0], Cell::SAND, 5);
+        assert!(filled.is_some());
+        assert_eq!(cells_of(&map, Cell::SAND).len(), 5);
+        assert_eq!(flood_fill(&mut map, [5, 0, 0], Cell::GLASS, 0), None);
+    }
+}
@@ -1,6 +1,14 @@
 use crate::math::*;
+use crate::picking::Ray;
 use crate::renderer::CameraDTO;
 
+#[derive(Debug, Clone, Copy)]
+pub struct CameraPose {
+    pub position: Vector3,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Camera {
     aspect_ratio: f32,
@@ -43,6 +51,21 @@ impl Camera {
         &mut self.yaw
     }
 
+    pub fn pose(&self) -> CameraPose {
+        CameraPose {
+            position: self.position,
+            pitch: self.pitch,
+            yaw: self.yaw,
+        }
+    }
+
+    pub fn set_pose(&mut self, pose: CameraPose) {
+        self.position = pose.position;
+        self.pitch = pose.pitch;
+        self.yaw = pose.yaw;
+        self.update_view_matrix();
+    }
+
     pub fn position_as_slice(&mut self) -> &mut [f32] {
         self.position.as_mut_slice()
     }
@@ -67,6 +90,17 @@ impl Camera {
         self.view_matrix = Matrix4::new_translation(&self.position) * rotation;
     }
 
+    /// Ray through point in normalized device coordinates, same as the one
+    /// generated in ray tracing vertex shader.
+    pub fn ray(&self, ndc: Vector2) -> Ray {
+        let t1 = self.inverse_projection_matrix * Vector4::new(ndc.x, ndc.y, -1.0, 1.0);
+        let t2 = self.view_matrix * Vector4::new(t1.x, t1.y, t1.z, 0.0);
+        Ray {
+            origin: self.view_matrix.fixed_view::<3, 1>(0, 3).into(),
+            direction: t2.xyz(),
+        }
+    }
+
     pub fn as_dto(&self) -> CameraDTO {
         CameraDTO {
             view_matrix: self.view_matrix,
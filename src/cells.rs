@@ -0,0 +1,256 @@
+use crate::materials::Material;
+use crate::math::*;
+
+pub const MAX_CELL_TYPES: usize = 256;
+
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Cell(pub u8);
+
+impl Cell {
+    pub const NONE: Self = Self(0);
+    pub const GRASS: Self = Self(1);
+    pub const WATER: Self = Self(2);
+    pub const GROUND: Self = Self(3);
+    pub const STONE: Self = Self(4);
+    pub const SAND: Self = Self(5);
+    pub const GLASS: Self = Self(6);
+    pub const SNOW: Self = Self(7);
+    pub const LAVA: Self = Self(8);
+
+    pub fn id(&self) -> u8 {
+        self.0
+    }
+}
+
+impl From<Cell> for u8 {
+    fn from(cell: Cell) -> Self {
+        cell.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CellType {
+    pub id: Cell,
+    pub name: String,
+    pub solid: bool,
+    pub fluid: bool,
+    pub material: Material,
+}
+
+/// Table of all known cell types, indexed by cell id.
+/// Cell id is used directly as material index in the ray tracing shader, so
+/// there can be at most 256 of them.
+#[derive(Clone, Debug)]
+pub struct CellRegistry {
+    types: Vec<CellType>,
+    solid: [bool; MAX_CELL_TYPES],
+    fluid: [bool; MAX_CELL_TYPES],
+}
+
+impl Default for CellRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("air", false, false, Material::diffuse(Vector3::zeros()));
+        registry.register(
+            "grass",
+            true,
+            false,
+            Material::diffuse(Vector3::new(
+                0.44313725490196076,
+                0.6666666666666666,
+                0.20392156862745098,
+            )),
+        );
+        registry.register(
+            "water",
+            false,
+            true,
+            Material::dielectric(Vector3::new(0.5, 0.5, 0.9), 2.045),
+        );
+        registry.register(
+            "ground",
+            true,
+            false,
+            Material::metal(
+                Vector3::new(0.6274509803921569, 0.3568627450980392, 0.3254901960784314),
+                0.5,
+            ),
+        );
+        registry.register(
+            "stone",
+            true,
+            false,
+            Material::diffuse(Vector3::new(0.5, 0.5, 0.5)),
+        );
+        registry.register(
+            "sand",
+            true,
+            false,
+            Material::diffuse(Vector3::new(0.86, 0.78, 0.55)),
+        );
+        registry.register(
+            "glass",
+            true,
+            false,
+            Material::dielectric(Vector3::new(0.95, 0.95, 0.95), 1.5),
+        );
+        registry.register(
+            "snow",
+            true,
+            false,
+            Material::diffuse(Vector3::new(0.95, 0.95, 0.97)),
+        );
+        registry.register(
+            "lava",
+            false,
+            true,
+            Material::emissive(Vector3::new(1.0, 0.35, 0.05), 4.0),
+        );
+        registry
+    }
+}
+
+impl CellRegistry {
+    pub fn empty() -> Self {
+        Self {
+            types: Vec::new(),
+            solid: [false; MAX_CELL_TYPES],
+            fluid: [false; MAX_CELL_TYPES],
+        }
+    }
+
+    /// Adds new cell type with next free id. Returns None if all ids are taken
+    /// or the name is already used, names identify cell types across registries.
+    pub fn register(
+        &mut self,
+        name: &str,
+        solid: bool,
+        fluid: bool,
+        material: Material,
+    ) -> Option<Cell> {
+        if self.types.len() == MAX_CELL_TYPES || self.by_name(name).is_some() {
+            return None;
+        }
+
+        let id = Cell(self.types.len() as u8);
+        self.solid[id.0 as usize] = solid;
+        self.fluid[id.0 as usize] = fluid;
+        self.types.push(CellType {
+            id,
+            name: name.to_owned(),
+            solid,
+            fluid,
+            material,
+        });
+        Some(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    pub fn get(&self, cell: Cell) -> Option<&CellType> {
+        self.types.get(cell.0 as usize)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<Cell> {
+        self.types.iter().find(|it| it.name == name).map(|it| it.id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CellType> {
+        self.types.iter()
+    }
+
+    pub fn is_solid(&self, cell: Cell) -> bool {
+        self.solid[cell.0 as usize]
+    }
+
+    pub fn is_fluid(&self, cell: Cell) -> bool {
+        self.fluid[cell.0 as usize]
+    }
+
+    pub fn is_air(&self, cell: Cell) -> bool {
+        cell == Cell::NONE
+    }
+
+    /// Materials in cell id order, ready to be uploaded to the renderer.
+    pub fn materials(&self) -> Vec<Material> {
+        self.types.iter().map(|it| it.material).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Material {
+        Material::diffuse(Vector3::repeat(0.5))
+    }
+
+    #[test]
+    fn default_registry_matches_cell_constants() {
+        let registry = CellRegistry::default();
+        let names = [
+            (Cell::NONE, "air"),
+            (Cell::GRASS, "grass"),
+            (Cell::WATER, "water"),
+            (Cell::GROUND, "ground"),
+            (Cell::STONE, "stone"),
+            (Cell::SAND, "sand"),
+            (Cell::GLASS, "glass"),
+            (Cell::SNOW, "snow"),
+            (Cell::LAVA, "lava"),
+        ];
+        assert_eq!(registry.len(), names.len());
+        for (cell, name) in names {
+            assert_eq!(registry.by_name(name), Some(cell));
+            assert_eq!(registry.get(cell).unwrap().name, name);
+        }
+        assert_eq!(registry.materials().len(), names.len());
+    }
+
+    #[test]
+    fn flags_follow_registration() {
+        let mut registry = CellRegistry::default();
+        let mud = registry.register("mud", false, true, material()).unwrap();
+        let ice = registry.register("ice", true, false, material()).unwrap();
+
+        assert_eq!(mud, Cell(9));
+        assert!(registry.is_fluid(mud) && !registry.is_solid(mud));
+        assert!(registry.is_solid(ice) && !registry.is_fluid(ice));
+        assert!(registry.is_fluid(Cell::WATER) && registry.is_fluid(Cell::LAVA));
+        assert!(registry.is_solid(Cell::STONE));
+        assert!(registry.is_air(Cell::NONE));
+        assert!(!registry.is_solid(Cell::NONE) && !registry.is_fluid(Cell::NONE));
+        // ids that were never registered are neither
+        assert!(!registry.is_solid(Cell(200)) && registry.get(Cell(200)).is_none());
+        assert_eq!(registry.by_name("lava rock"), None);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let mut registry = CellRegistry::default();
+        assert_eq!(registry.register("stone", false, true, material()), None);
+        assert_eq!(registry.len(), 9);
+        assert_eq!(registry.by_name("stone"), Some(Cell::STONE));
+        assert!(registry.is_solid(Cell::STONE));
+    }
+
+    #[test]
+    fn at_most_256_types_are_registered() {
+        let mut registry = CellRegistry::empty();
+        assert!(registry.is_empty());
+        for i in 0..MAX_CELL_TYPES {
+            let cell = registry.register(&format!("type {i}"), false, false, material());
+            assert_eq!(cell, Some(Cell(i as u8)));
+        }
+        assert_eq!(registry.register("one more", true, false, material()), None);
+        assert_eq!(registry.len(), MAX_CELL_TYPES);
+        assert!(!registry.is_solid(Cell(255)));
+    }
+}
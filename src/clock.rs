@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+// How long the lag indicator stays on after steps were dropped
+const BEHIND_HOLD_SECONDS: f64 = 0.5;
+
+/// Fixed timestep simulation clock, decides how many steps to run per frame
+/// so that simulation speed does not depend on frame rate.
+#[derive(Clone, Debug)]
+pub struct SimClock {
+    pub steps_per_second: f32,
+    /// Steps run in one frame at most, the rest of the backlog is dropped
+    pub max_catch_up: usize,
+    /// Multiplier of wall time
+    pub speed: f32,
+    pub paused: bool,
+
+    // wall time not yet turned into steps
+    accumulator: f64,
+    single_step: bool,
+    behind_hold: f64,
+}
+
+impl SimClock {
+    pub fn new(steps_per_second: f32) -> Self {
+        Self {
+            steps_per_second,
+            max_catch_up: 8,
+            speed: 1.0,
+            paused: true,
+            accumulator: 0.0,
+            single_step: false,
+            behind_hold: 0.0,
+        }
+    }
+
+    /// Runs one step on the next `advance`, even when paused.
+    pub fn request_step(&mut self) {
+        self.single_step = true;
+    }
+
+    /// Drops accumulated time, e.g. after the scene was replaced.
+    pub fn reset(&mut self) {
+        self.accumulator = 0.0;
+        self.single_step = false;
+        self.behind_hold = 0.0;
+    }
+
+    /// True if steps had to be dropped recently because simulation could
+    /// not keep up with requested rate.
+    pub fn is_behind(&self) -> bool {
+        self.behind_hold > 0.0
+    }
+
+    /// Number of steps to run for `frame_time` of wall time.
+    pub fn advance(&mut self, frame_time: Duration) -> usize {
+        let seconds = frame_time.as_secs_f64();
+        self.behind_hold = (self.behind_hold - seconds).max(0.0);
+        if std::mem::take(&mut self.single_step) {
+            self.accumulator = 0.0;
+            return 1;
+        }
+        if self.paused || self.steps_per_second <= 0.0 || self.speed <= 0.0 {
+            self.accumulator = 0.0;
+            return 0;
+        }
+
+        let step = 1.0 / self.steps_per_second as f64;
+        self.accumulator += seconds * self.speed as f64;
+        let steps = (self.accumulator / step).floor() as usize;
+        if steps > self.max_catch_up {
+            // catching up would only make next frame slower
+            self.accumulator = 0.0;
+            self.behind_hold = BEHIND_HOLD_SECONDS;
+            return self.max_catch_up;
+        }
+        self.accumulator -= steps as f64 * step;
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running(steps_per_second: f32) -> SimClock {
+        let mut clock = SimClock::new(steps_per_second);
+        clock.paused = false;
+        clock
+    }
+
+    fn secs(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn accumulator_carries_time_between_frames() {
+        let mut clock = running(8.0);
+        // half a step per frame
+        let steps = (0..8)
+            .map(|_| clock.advance(secs(0.0625)))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, [0, 1, 0, 1, 0, 1, 0, 1]);
+
+        // remainder of 1.5 steps is kept
+        assert_eq!(clock.advance(secs(0.1875)), 1);
+        assert_eq!(clock.advance(secs(0.0625)), 1);
+
+        clock.speed = 2.0;
+        assert_eq!(clock.advance(secs(0.25)), 4);
+        assert!(!clock.is_behind());
+    }
+
+    #[test]
+    fn paused_clock_runs_only_requested_steps() {
+        let mut clock = SimClock::new(8.0);
+        assert_eq!(clock.advance(secs(1.0)), 0);
+        clock.request_step();
+        assert_eq!(clock.advance(secs(1.0)), 1);
+        assert_eq!(clock.advance(secs(1.0)), 0);
+    }
+
+    #[test]
+    fn steps_are_clamped_and_reported_behind() {
+        let mut clock = running(8.0);
+        clock.max_catch_up = 3;
+        assert_eq!(clock.advance(secs(2.0)), 3);
+        assert!(clock.is_behind());
+
+        // the dropped backlog is not caught up later
+        assert_eq!(clock.advance(secs(0.125)), 1);
+        assert!(clock.is_behind());
+        assert_eq!(clock.advance(secs(0.375)), 3);
+        assert!(!clock.is_behind());
+    }
+}
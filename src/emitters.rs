@@ -0,0 +1,185 @@
+use crate::brush::Bounds;
+use crate::solver::FluidSolver;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmitterKind {
+    /// Adds `rate` mass per tick
+    Source,
+    /// Takes up to `rate` mass per tick
+    Sink,
+    /// Empties its cells every tick, `rate` is ignored
+    Drain,
+}
+
+impl EmitterKind {
+    pub const ALL: [Self; 3] = [Self::Source, Self::Sink, Self::Drain];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Source => "source",
+            Self::Sink => "sink",
+            Self::Drain => "drain",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmitterShape {
+    Point,
+    /// Box of cells starting at emitter position
+    Box {
+        size: [usize; 3],
+    },
+}
+
+/// Adds or removes fluid in a group of cells every simulation tick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Emitter {
+    pub kind: EmitterKind,
+    /// Cell in padded grid coordinates
+    pub position: [usize; 3],
+    pub shape: EmitterShape,
+    /// Mass per tick for the whole emitter, split evenly between its cells
+    pub rate: f32,
+    pub enabled: bool,
+}
+
+impl Emitter {
+    pub fn new(kind: EmitterKind, position: [usize; 3]) -> Self {
+        Self {
+            kind,
+            position,
+            shape: EmitterShape::Point,
+            rate: 1.0,
+            enabled: true,
+        }
+    }
+
+    /// Covered cells, clipped to part of the grid that can hold fluid.
+    pub fn bounds(&self, sim: &dyn FluidSolver) -> Bounds {
+        let size = match self.shape {
+            EmitterShape::Point => [1; 3],
+            EmitterShape::Box { size } => size,
+        };
+        let editable = sim.editable();
+        Bounds {
+            min: [0, 1, 2].map(|i| self.position[i].clamp(editable.min[i], editable.max[i])),
+            max: [0, 1, 2]
+                .map(|i| (self.position[i] + size[i]).clamp(editable.min[i], editable.max[i])),
+        }
+    }
+
+    /// Non-solid cells the emitter works on.
+    pub fn cells(&self, sim: &dyn FluidSolver) -> Vec<[usize; 3]> {
+        let bounds = self.bounds(sim);
+        let mut cells = Vec::new();
+        for z in bounds.min[2]..bounds.max[2] {
+            for y in bounds.min[1]..bounds.max[1] {
+                for x in bounds.min[0]..bounds.max[0] {
+                    if !sim.registry().is_solid(sim.get([x, y, z])) {
+                        cells.push([x, y, z]);
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    /// Mass change per cell and tick, negative for absorbers. Drains take everything.
+    pub fn rate_per_cell(&self, cell_count: usize) -> f32 {
+        let rate = self.rate.max(0.0) / cell_count.max(1) as f32;
+        match self.kind {
+            EmitterKind::Source => rate,
+            EmitterKind::Sink => -rate,
+            EmitterKind::Drain => f32::NEG_INFINITY,
+        }
+    }
+
+    /// Runs one tick. Returns mass that was added, negative if it was removed.
+    pub fn apply(&self, sim: &mut dyn FluidSolver) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let cells = self.cells(sim);
+        let rate = self.rate_per_cell(cells.len());
+        let mut total = 0.0;
+        for p in cells {
+            total += if rate >= 0.0 {
+                sim.inject(p, rate)
+            } else {
+                -sim.remove(p, -rate)
+            };
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::{Cell, CellRegistry};
+    use crate::map::{Map, WaterSim};
+
+    fn sim() -> WaterSim {
+        let mut map = Map::empty(6, 4, 6);
+        *map.at_mut(2, 0, 2) = Cell::STONE;
+        WaterSim::new(map, &CellRegistry::default())
+    }
+
+    fn box_emitter(kind: EmitterKind, rate: f32) -> Emitter {
+        Emitter {
+            shape: EmitterShape::Box { size: [2, 1, 2] },
+            rate,
+            ..Emitter::new(kind, [2, 1, 2])
+        }
+    }
+
+    #[test]
+    fn source_adds_rate_split_between_free_cells() {
+        let mut sim = sim();
+        let source = box_emitter(EmitterKind::Source, 0.3);
+        // padded coordinates, stone is at (3, 1, 3)
+        assert_eq!(source.cells(&sim).len(), 3);
+
+        let added = source.apply(&mut sim);
+        assert!((added - 0.3).abs() < 1e-6, "{added}");
+        assert!((sim.mass_at([2, 1, 2]) - 0.1).abs() < 1e-6);
+        assert_eq!(sim.mass_at([3, 1, 3]), 0.0);
+
+        let disabled = Emitter {
+            enabled: false,
+            ..source
+        };
+        assert_eq!(disabled.apply(&mut sim), 0.0);
+    }
+
+    #[test]
+    fn sink_takes_at_most_rate() {
+        let mut sim = sim();
+        box_emitter(EmitterKind::Source, 0.6).apply(&mut sim);
+        let sink = box_emitter(EmitterKind::Sink, 0.3);
+
+        let removed = sink.apply(&mut sim);
+        assert!((removed + 0.3).abs() < 1e-6, "{removed}");
+        assert!((sim.mass_at([2, 1, 2]) - 0.1).abs() < 1e-6);
+        // less is left than the sink would take
+        let removed = box_emitter(EmitterKind::Sink, 3.0).apply(&mut sim);
+        assert!((removed + 0.3).abs() < 1e-6, "{removed}");
+        assert_eq!(sim.mass_at([2, 1, 2]), 0.0);
+    }
+
+    #[test]
+    fn drain_empties_its_cells() {
+        let mut sim = sim();
+        box_emitter(EmitterKind::Source, 3.0).apply(&mut sim);
+        let outside = sim.inject(1, 1, 1, 0.5);
+        let drain = box_emitter(EmitterKind::Drain, 0.0);
+
+        let removed = drain.apply(&mut sim);
+        assert!((removed + 3.0).abs() < 1e-5, "{removed}");
+        for p in drain.cells(&sim) {
+            assert_eq!(sim.mass_at(p), 0.0);
+        }
+        assert_eq!(sim.mass_at([1, 1, 1]), outside);
+    }
+}
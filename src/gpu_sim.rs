@@ -0,0 +1,648 @@
+use crate::boundary::{Boundaries, Boundary, Face};
+use crate::cells::Cell;
+use crate::emitters::{Emitter, EmitterKind};
+use crate::map::{CellState, WaterSimParams};
+use crate::solver::{FluidSolver, Restorable};
+use wgpu::util::DeviceExt;
+
+// Must match flags in water_sim.wgsl
+const FLAG_SOLID: u32 = 1;
+const FLAG_FLUID: u32 = 2;
+// Must match workgroup sizes in water_sim.wgsl
+const CELL_GROUP: u32 = 4;
+const PACK_GROUP: u32 = 64;
+// Must match constants in water_sim.wgsl
+pub const MAX_EMITTERS: usize = 64;
+const EMITTER_ADD: u32 = 0;
+const EMITTER_REMOVE: u32 = 1;
+const EMITTER_DRAIN: u32 = 2;
+
+/// Must match `Params` in water_sim.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ParamsDTO {
+    size: [u32; 3],
+    ocean_level_x: u32,
+    max_mass: f32,
+    max_compress: f32,
+    min_mass: f32,
+    min_flow: f32,
+    max_speed: f32,
+    spread: f32,
+    row_words: u32,
+    emitter_count: u32,
+    ocean_level_z: u32,
+    water: u32,
+    _padding: [u32; 2],
+}
+
+/// Must match `Emitter` in water_sim.wgsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct EmitterDTO {
+    min: [u32; 3],
+    kind: u32,
+    max: [u32; 3],
+    amount: f32,
+}
+
+/// Water simulation running in compute shaders. Cells and mass stay on the GPU,
+/// after every step cells and fill levels are copied straight into the renderer's
+/// voxel textures.
+/// Follows the same rules as `WaterSim`, which stays the reference implementation.
+pub struct GpuWaterSim {
+    size: [usize; 3],
+    // of the +x and +z faces, 0 where the face drains
+    ocean_levels: [usize; 2],
+    // cell type that cells gaining mass turn into
+    water: Cell,
+    row_words: u32,
+    params: WaterSimParams,
+    emitter_count: usize,
+    // enabled emitters past `MAX_EMITTERS`, warned about when it changes
+    dropped_emitters: usize,
+    params_buffer: wgpu::Buffer,
+    emitters_buffer: wgpu::Buffer,
+    cells_buffer: wgpu::Buffer,
+    mass_buffers: [wgpu::Buffer; 2],
+    packed_buffer: wgpu::Buffer,
+    packed_fill_buffer: wgpu::Buffer,
+    // bind group `i` reads mass from `mass_buffers[i]` and writes the other one
+    bind_groups: [wgpu::BindGroup; 2],
+    current: usize,
+    emitters_pipeline: wgpu::ComputePipeline,
+    flows_pipeline: wgpu::ComputePipeline,
+    apply_pipeline: wgpu::ComputePipeline,
+    pack_pipeline: wgpu::ComputePipeline,
+}
+
+impl GpuWaterSim {
+    /// Checks that the GPU can simulate `sim`: water has to be the only fluid
+    /// and boundaries the default ones, except that the far x and z faces can
+    /// be oceans.
+    pub fn supports(sim: &dyn FluidSolver) -> Result<(), &'static str> {
+        let registry = sim.registry();
+        let water = water_cell(sim)?;
+        if sim
+            .cells()
+            .iter()
+            .any(|it| registry.is_fluid(*it) && *it != water)
+        {
+            return Err("GPU simulation supports only water, remove lava first");
+        }
+        let boundaries = sim.boundaries().unwrap_or_default();
+        let supported = Face::ALL.iter().all(|face| match boundaries.get(*face) {
+            Boundary::Ocean { .. } => matches!(face, Face::PosX | Face::PosZ),
+            it => it == Boundaries::default().get(*face),
+        });
+        if !supported {
+            return Err("GPU simulation supports only default boundaries and oceans at +x and +z");
+        }
+        Ok(())
+    }
+
+    /// Takes parameters from `sim`, its state has to be uploaded separately.
+    /// Boundaries stay the default ones, except that the far x and z faces
+    /// can be oceans. Fails if `sim` is not supported.
+    pub fn new(device: &wgpu::Device, sim: &dyn FluidSolver) -> Result<Self, &'static str> {
+        Self::supports(sim)?;
+        let water = water_cell(sim)?;
+        let params = &sim.params().unwrap_or_default();
+        let boundaries = sim.boundaries().unwrap_or_default();
+        let ocean_levels = [Face::PosX, Face::PosZ].map(|face| match boundaries.get(face) {
+            Boundary::Ocean { level } => level,
+            _ => 0,
+        });
+        let size = sim.size();
+        let [x, y, z] = size.map(|it| it as u32);
+        let cell_count = size.iter().product::<usize>();
+        let row_words = align(x, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) / 4;
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("water sim params"),
+            contents: bytemuck::cast_slice(&[params_dto(
+                size,
+                ocean_levels,
+                water,
+                row_words,
+                params,
+                0,
+            )]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let emitters_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("water sim emitters"),
+            contents: bytemuck::cast_slice(&[EmitterDTO::default(); MAX_EMITTERS]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let registry = sim.registry();
+        let flags = (0..crate::cells::MAX_CELL_TYPES)
+            .map(|i| {
+                let cell = Cell(i as u8);
+                let mut flags = 0;
+                // only water moves on the GPU, `water_cell` rejects maps with other fluids
+                if registry.is_solid(cell) || (registry.is_fluid(cell) && cell != water) {
+                    flags |= FLAG_SOLID;
+                }
+                if registry.is_fluid(cell) {
+                    flags |= FLAG_FLUID;
+                }
+                flags
+            })
+            .collect::<Vec<u32>>();
+        let flags_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("water sim flags"),
+            contents: bytemuck::cast_slice(&flags),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let storage = |label, bytes: usize| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: bytes as u64,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let cells_buffer = storage("water sim cells", cell_count * 4);
+        let mass_buffers = [
+            storage("water sim mass 0", cell_count * 4),
+            storage("water sim mass 1", cell_count * 4),
+        ];
+        let flows_buffer = storage("water sim flows", cell_count * 6 * 4);
+        let packed_buffer = storage("water sim packed cells", (row_words * y * z) as usize * 4);
+        let packed_fill_buffer = storage("water sim packed fill", (row_words * y * z) as usize * 4);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Water sim shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../shaders/water_sim.wgsl").into()),
+        });
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("water sim bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(1, true),
+                storage_entry(2, false),
+                storage_entry(3, false),
+                storage_entry(4, false),
+                storage_entry(5, false),
+                storage_entry(6, false),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(8, false),
+            ],
+        });
+        let bind_group = |mass: &wgpu::Buffer, new_mass: &wgpu::Buffer| {
+            let buffers = [
+                &params_buffer,
+                &flags_buffer,
+                &cells_buffer,
+                mass,
+                &flows_buffer,
+                new_mass,
+                &packed_buffer,
+                &emitters_buffer,
+                &packed_fill_buffer,
+            ];
+            let entries = buffers
+                .iter()
+                .enumerate()
+                .map(|(i, buffer)| wgpu::BindGroupEntry {
+                    binding: i as u32,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect::<Vec<_>>();
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("water sim bind group"),
+                layout: &bind_group_layout,
+                entries: &entries,
+            })
+        };
+        let bind_groups = [
+            bind_group(&mass_buffers[0], &mass_buffers[1]),
+            bind_group(&mass_buffers[1], &mass_buffers[0]),
+        ];
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Water sim pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+
+        Ok(Self {
+            size,
+            ocean_levels,
+            water,
+            row_words,
+            params: *params,
+            emitter_count: 0,
+            dropped_emitters: 0,
+            params_buffer,
+            emitters_buffer,
+            cells_buffer,
+            mass_buffers,
+            packed_buffer,
+            packed_fill_buffer,
+            bind_groups,
+            current: 0,
+            emitters_pipeline: pipeline("apply_emitters"),
+            flows_pipeline: pipeline("compute_flows"),
+            apply_pipeline: pipeline("apply_flows"),
+            pack_pipeline: pipeline("pack_cells"),
+        })
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// Takes effect from the next step.
+    pub fn set_params(&mut self, queue: &wgpu::Queue, params: &WaterSimParams) {
+        self.params = *params;
+        self.write_params(queue);
+    }
+
+    /// Replaces emitters applied before every step, only the first `MAX_EMITTERS`
+    /// enabled ones are used. Cells of `sim` are used to skip solids.
+    pub fn set_emitters(
+        &mut self,
+        queue: &wgpu::Queue,
+        emitters: &[Emitter],
+        sim: &dyn FluidSolver,
+    ) {
+        let enabled = emitters.iter().filter(|it| it.enabled).count();
+        let dropped = enabled.saturating_sub(MAX_EMITTERS);
+        if dropped != self.dropped_emitters && dropped > 0 {
+            log::warn!(
+                "{} emitters are enabled, GPU simulation ignores {} after the first {}",
+                enabled,
+                dropped,
+                MAX_EMITTERS
+            );
+        }
+        self.dropped_emitters = dropped;
+        let dto = emitters
+            .iter()
+            .filter(|it| it.enabled)
+            .take(MAX_EMITTERS)
+            .map(|it| {
+                let bounds = it.bounds(sim);
+                let amount = it.rate_per_cell(it.cells(sim).len()).abs();
+                let (kind, amount) = match it.kind {
+                    EmitterKind::Source => (EMITTER_ADD, amount),
+                    EmitterKind::Sink => (EMITTER_REMOVE, amount),
+                    EmitterKind::Drain => (EMITTER_DRAIN, 0.0),
+                };
+                EmitterDTO {
+                    min: bounds.min.map(|it| it as u32),
+                    kind,
+                    max: bounds.max.map(|it| it as u32),
+                    amount,
+                }
+            })
+            .collect::<Vec<_>>();
+        if !dto.is_empty() {
+            queue.write_buffer(&self.emitters_buffer, 0, bytemuck::cast_slice(&dto));
+        }
+        if dto.len() != self.emitter_count {
+            self.emitter_count = dto.len();
+            self.write_params(queue);
+        }
+    }
+
+    fn write_params(&self, queue: &wgpu::Queue) {
+        let dto = params_dto(
+            self.size,
+            self.ocean_levels,
+            self.water,
+            self.row_words,
+            &self.params,
+            self.emitter_count,
+        );
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[dto]));
+    }
+
+    /// Replaces GPU state with cells and mass of `sim`, sizes must match.
+    pub fn upload(&mut self, queue: &wgpu::Queue, sim: &dyn FluidSolver) {
+        assert_eq!(sim.size(), self.size);
+        let cells = sim
+            .cells()
+            .iter()
+            .map(|it| it.0 as u32)
+            .collect::<Vec<u32>>();
+        queue.write_buffer(&self.cells_buffer, 0, bytemuck::cast_slice(&cells));
+        queue.write_buffer(
+            &self.mass_buffers[self.current],
+            0,
+            bytemuck::cast_slice(sim.mass()),
+        );
+    }
+
+    /// Records one simulation step followed by copy of cells into `voxel_texture`
+    /// and of their fill levels into `fill_texture`.
+    pub fn encode_step(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        voxel_texture: &wgpu::Texture,
+        fill_texture: &wgpu::Texture,
+    ) {
+        let [x, y, z] = self.size.map(|it| it as u32);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Water sim pass"),
+            });
+            pass.set_bind_group(0, &self.bind_groups[self.current], &[]);
+            let groups = [x, y, z].map(|it| align(it, CELL_GROUP) / CELL_GROUP);
+            if self.emitter_count > 0 {
+                pass.set_pipeline(&self.emitters_pipeline);
+                pass.dispatch_workgroups(groups[0], groups[1], groups[2]);
+            }
+            pass.set_pipeline(&self.flows_pipeline);
+            pass.dispatch_workgroups(groups[0], groups[1], groups[2]);
+            pass.set_pipeline(&self.apply_pipeline);
+            pass.dispatch_workgroups(groups[0], groups[1], groups[2]);
+            pass.set_pipeline(&self.pack_pipeline);
+            pass.dispatch_workgroups(align(self.row_words, PACK_GROUP) / PACK_GROUP, y, z);
+        }
+        self.current = 1 - self.current;
+
+        let copies = [
+            (&self.packed_buffer, voxel_texture),
+            (&self.packed_fill_buffer, fill_texture),
+        ];
+        for (buffer, texture) in copies {
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: Some(self.row_words * 4),
+                        rows_per_image: Some(y),
+                    },
+                },
+                wgpu::ImageCopyTexture {
+                    texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::Extent3d {
+                    width: x,
+                    height: y,
+                    depth_or_array_layers: z,
+                },
+            );
+        }
+    }
+
+    /// Copies GPU state back into `sim`. Blocks until the GPU is done, so it is
+    /// meant for edits and saving, not for every frame. Returns false if the
+    /// readback failed, `sim` is left untouched then.
+    pub fn download(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sim: &mut dyn Restorable,
+    ) -> bool {
+        assert_eq!(sim.size(), self.size);
+        let bytes = (self.size.iter().product::<usize>() * 4) as u64;
+        let staging = |label| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: bytes,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        let cells_staging = staging("water sim cells readback");
+        let mass_staging = staging("water sim mass readback");
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Water sim readback encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.cells_buffer, 0, &cells_staging, 0, bytes);
+        encoder.copy_buffer_to_buffer(&self.mass_buffers[self.current], 0, &mass_staging, 0, bytes);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for buffer in [&cells_staging, &mass_staging] {
+            let sender = sender.clone();
+            buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    let _ = sender.send(result.is_ok());
+                });
+        }
+        device.poll(wgpu::Maintain::Wait);
+        if receiver.try_iter().filter(|ok| *ok).count() != 2 {
+            return false;
+        }
+
+        {
+            let cells = cells_staging.slice(..).get_mapped_range();
+            let mass = mass_staging.slice(..).get_mapped_range();
+            let cells: &[u32] = bytemuck::cast_slice(&cells);
+            let mass: &[f32] = bytemuck::cast_slice(&mass);
+            for (i, (cell, mass)) in cells.iter().zip(mass).enumerate() {
+                // only water moves on the GPU, the rest of the cell state is kept
+                let state = CellState {
+                    cell: Cell(*cell as u8),
+                    water: *mass,
+                    ..sim.cell_state(i)
+                };
+                sim.restore(i, &state);
+            }
+        }
+        cells_staging.unmap();
+        mass_staging.unmap();
+        true
+    }
+}
+
+fn params_dto(
+    size: [usize; 3],
+    ocean_levels: [usize; 2],
+    water: Cell,
+    row_words: u32,
+    params: &WaterSimParams,
+    emitter_count: usize,
+) -> ParamsDTO {
+    ParamsDTO {
+        size: size.map(|it| it as u32),
+        ocean_level_x: ocean_levels[0] as u32,
+        ocean_level_z: ocean_levels[1] as u32,
+        water: water.id() as u32,
+        _padding: [0; 2],
+        max_mass: params.max_mass,
+        max_compress: params.max_compress,
+        min_mass: params.min_mass,
+        min_flow: params.min_flow,
+        max_speed: params.max_speed,
+        spread: params.spread,
+        row_words,
+        emitter_count: emitter_count as u32,
+    }
+}
+
+/// Water cell type of the registry of `sim`.
+fn water_cell(sim: &dyn FluidSolver) -> Result<Cell, &'static str> {
+    sim.registry()
+        .by_name("water")
+        .ok_or("GPU simulation needs a water cell type")
+}
+
+fn align(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::CellRegistry;
+    use crate::map::{Map, WaterSim};
+    use crate::terrain::{self, Sea, TerrainParams};
+
+    /// Software adapter, so that results do not depend on the driver.
+    fn fallback_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: Default::default(),
+            force_fallback_adapter: true,
+            compatible_surface: None,
+        }))?;
+        if !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+        {
+            return None;
+        }
+        pollster::block_on(adapter.request_device(&Default::default(), None)).ok()
+    }
+
+    #[test]
+    fn lava_is_not_supported() {
+        let registry = CellRegistry::default();
+        let mut map = Map::empty(4, 4, 4);
+        *map.at_mut(1, 1, 1) = Cell::WATER;
+        assert!(GpuWaterSim::supports(&WaterSim::new(map.clone(), &registry)).is_ok());
+        *map.at_mut(2, 1, 1) = Cell::LAVA;
+        assert!(GpuWaterSim::supports(&WaterSim::new(map, &registry)).is_err());
+    }
+
+    #[test]
+    fn only_default_boundaries_and_far_oceans_are_supported() {
+        let mut sim = WaterSim::new(Map::empty(4, 4, 4), &CellRegistry::default());
+        let supports = |sim: &mut WaterSim, face: Face, boundary: Boundary| {
+            let mut boundaries = Boundaries::default();
+            boundaries.set(face, boundary);
+            sim.set_boundaries(boundaries).unwrap();
+            GpuWaterSim::supports(sim).is_ok()
+        };
+        let ocean = Boundary::Ocean { level: 2 };
+        assert!(supports(&mut sim, Face::PosX, ocean));
+        assert!(supports(&mut sim, Face::PosZ, ocean));
+        assert!(supports(&mut sim, Face::NegX, Boundary::Closed));
+        assert!(!supports(&mut sim, Face::NegX, ocean));
+        assert!(!supports(&mut sim, Face::NegZ, Boundary::Drain));
+        assert!(!supports(&mut sim, Face::PosX, Boundary::Closed));
+        assert!(!supports(&mut sim, Face::NegY, Boundary::Closed));
+        assert!(!supports(&mut sim, Face::PosY, ocean));
+    }
+
+    #[test]
+    fn matches_cpu_sim() {
+        let Some((device, queue)) = fallback_device() else {
+            eprintln!("no software adapter with compute shaders, skipping");
+            return;
+        };
+        let registry = CellRegistry::default();
+        let params = TerrainParams {
+            size: [20, 12, 20],
+            sea: Sea::Level(4),
+            ..Default::default()
+        };
+        let basin = terrain::generators().pop().unwrap();
+        let mut cpu = WaterSim::new(basin.generate(&params), &registry);
+        // every cell is simulated on the GPU
+        let no_sleep = WaterSimParams {
+            sleep_threshold: 0.0,
+            ..Default::default()
+        };
+        cpu.set_params(no_sleep).unwrap();
+        let mut gpu = GpuWaterSim::new(&device, &cpu).unwrap();
+        gpu.upload(&queue, &cpu);
+
+        let [x, y, z] = [cpu.x(), cpu.y(), cpu.z()].map(|it| it as u32);
+        let texture = |format| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width: x,
+                    height: y,
+                    depth_or_array_layers: z,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                format,
+                usage: wgpu::TextureUsages::COPY_DST,
+                label: None,
+                view_formats: &[],
+            })
+        };
+        let voxel_texture = texture(wgpu::TextureFormat::R8Uint);
+        let fill_texture = texture(wgpu::TextureFormat::R8Unorm);
+        for _ in 0..30 {
+            cpu.simulate();
+            let mut encoder = device.create_command_encoder(&Default::default());
+            gpu.encode_step(&mut encoder, &voxel_texture, &fill_texture);
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+
+        let mut result = cpu.clone();
+        assert!(gpu.download(&device, &queue, &mut result));
+        // only summation order differs
+        let min_mass = WaterSimParams::default().min_mass;
+        for (i, (a, b)) in cpu.mass().iter().zip(result.mass()).enumerate() {
+            assert!((a - b).abs() < 1e-3, "mass of cell {i}: {a} != {b}");
+            if (a - min_mass).abs() > 1e-3 {
+                assert_eq!(cpu.cells()[i], result.cells()[i], "cell {i}");
+            }
+        }
+    }
+}
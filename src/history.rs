@@ -0,0 +1,407 @@
+use crate::brush::Bounds;
+use crate::map::CellState;
+use crate::solver::Restorable;
+use crate::xorshift32::Xorshift32;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+pub const DEFAULT_HISTORY_BYTES: usize = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Edit,
+    SimStep,
+}
+
+struct Change {
+    index: u32,
+    before: CellState,
+    after: CellState,
+}
+
+/// Sparse difference between two simulation states, only changed cells are stored.
+struct Entry {
+    kind: EntryKind,
+    changes: Vec<Change>,
+    rng_before: Xorshift32,
+    rng_after: Xorshift32,
+}
+
+impl Entry {
+    fn bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.changes.capacity() * std::mem::size_of::<Change>()
+    }
+}
+
+/// Undo and redo stacks of simulation changes. Oldest entries are dropped
+/// once stored diffs take more than `max_bytes`.
+pub struct History {
+    undo: VecDeque<Entry>,
+    redo: Vec<Entry>,
+    max_bytes: usize,
+    used_bytes: usize,
+
+    // copy of the simulation, only regions it reports as touched are compared
+    // and refreshed, so that a step of a mostly settled grid stays cheap
+    states: Vec<CellState>,
+    synced: bool,
+    rng: Xorshift32,
+    recording: bool,
+}
+
+impl History {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            max_bytes,
+            used_bytes: 0,
+            states: Vec::new(),
+            synced: false,
+            rng: Xorshift32::new(0),
+            recording: false,
+        }
+    }
+
+    pub fn undo_len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_len(&self) -> usize {
+        self.redo.len()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.trim();
+    }
+
+    /// Must be called when simulation is replaced, stored diffs are only
+    /// valid for the grid they were recorded on.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.used_bytes = 0;
+        self.synced = false;
+        self.recording = false;
+    }
+
+    /// Remembers current state, changes made until `commit` become one entry.
+    pub fn begin(&mut self, sim: &mut dyn Restorable) {
+        let regions = sim.take_touched_regions();
+        if !self.synced || self.states.len() != sim.cells().len() {
+            self.states.clear();
+            self.states
+                .extend((0..sim.cells().len()).map(|i| sim.cell_state(i)));
+            self.synced = true;
+        } else {
+            let [x, y, _] = sim.size();
+            for i in regions.iter().flat_map(|it| rows(it, [x, y])).flatten() {
+                self.states[i] = sim.cell_state(i);
+            }
+        }
+        self.rng = sim.rng();
+        self.recording = true;
+    }
+
+    /// Stores difference from the state at `begin`. Returns false if nothing
+    /// changed. Redo stack is discarded, because it continued from the old state.
+    pub fn commit(&mut self, sim: &mut dyn Restorable, kind: EntryKind) -> bool {
+        if !self.recording {
+            return false;
+        }
+        self.recording = false;
+        if !self.synced || self.states.len() != sim.cells().len() {
+            self.clear();
+            return false;
+        }
+
+        let mut changes = Vec::new();
+        let [x, y, _] = sim.size();
+        for region in sim.take_touched_regions() {
+            for i in rows(&region, [x, y]).flatten() {
+                let before = self.states[i];
+                let after = sim.cell_state(i);
+                if !before.same_as(&after) {
+                    changes.push(Change {
+                        index: i as u32,
+                        before,
+                        after,
+                    });
+                    self.states[i] = after;
+                }
+            }
+        }
+        let rng_after = sim.rng();
+        if changes.is_empty() && self.rng.state() == rng_after.state() {
+            return false;
+        }
+
+        changes.shrink_to_fit();
+        for it in self.redo.drain(..) {
+            self.used_bytes -= it.bytes();
+        }
+        let entry = Entry {
+            kind,
+            changes,
+            rng_before: self.rng,
+            rng_after,
+        };
+        self.used_bytes += entry.bytes();
+        self.undo.push_back(entry);
+        self.trim();
+        true
+    }
+
+    /// Kind of entry that `undo` would revert.
+    pub fn last(&self) -> Option<EntryKind> {
+        self.undo.back().map(|it| it.kind)
+    }
+
+    /// Reverts last entry. Returns region of cells whose type or mass changed.
+    pub fn undo(&mut self, sim: &mut dyn Restorable) -> Option<Bounds> {
+        let entry = self.undo.pop_back()?;
+        let dirty = self.apply(sim, &entry, false);
+        self.redo.push(entry);
+        dirty
+    }
+
+    /// Applies last reverted entry again. Returns region of cells whose type or mass changed.
+    pub fn redo(&mut self, sim: &mut dyn Restorable) -> Option<Bounds> {
+        let entry = self.redo.pop()?;
+        let dirty = self.apply(sim, &entry, true);
+        self.undo.push_back(entry);
+        dirty
+    }
+
+    fn trim(&mut self) {
+        while self.used_bytes > self.max_bytes {
+            let Some(entry) = self.undo.pop_front() else {
+                break;
+            };
+            self.used_bytes -= entry.bytes();
+        }
+    }
+
+    fn apply(&mut self, sim: &mut dyn Restorable, entry: &Entry, forward: bool) -> Option<Bounds> {
+        let [x, y, _] = sim.size();
+        let synced = self.synced && self.states.len() == sim.cells().len();
+        let mut dirty: Option<Bounds> = None;
+        for it in entry.changes.iter() {
+            let state = if forward { it.after } else { it.before };
+            let i = it.index as usize;
+            // fill level follows mass, so mass alone changes how the cell looks
+            let current = sim.cell_state(i);
+            if current.cell != state.cell
+                || current.water.to_bits() != state.water.to_bits()
+                || current.lava.to_bits() != state.lava.to_bits()
+            {
+                let p = Bounds::point([i % x, i / x % y, i / (x * y)]);
+                dirty = Some(dirty.map_or(p, |it| it.union(&p)));
+            }
+            sim.restore(i, &state);
+            if synced {
+                self.states[i] = state;
+            }
+        }
+        sim.set_rng(if forward {
+            entry.rng_after
+        } else {
+            entry.rng_before
+        });
+        dirty
+    }
+}
+
+/// Linear index ranges of the rows along x that make up the region, `size`
+/// is the size of the grid in x and y.
+fn rows(region: &Bounds, size: [usize; 2]) -> impl Iterator<Item = Range<usize>> + '_ {
+    let [x, y] = size;
+    (region.min[2]..region.max[2]).flat_map(move |zi| {
+        (region.min[1]..region.max[1]).map(move |yi| {
+            let row = (zi * y + yi) * x;
+            row + region.min[0]..row + region.max[0]
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cells::{Cell, CellRegistry};
+    use crate::map::{ErosionParams, Map, WaterSim};
+
+    fn sim() -> WaterSim {
+        let mut map = Map::empty(20, 10, 20);
+        for x in 0..20 {
+            for z in 0..20 {
+                *map.at_mut(x, 0, z) = Cell::STONE;
+            }
+        }
+        WaterSim::new(map, &CellRegistry::default())
+    }
+
+    /// Every cell with bits of all its fields, lava and erosion included.
+    fn state(sim: &WaterSim) -> Vec<(Cell, [u32; 5])> {
+        (0..sim.cells().len())
+            .map(|i| {
+                let it = sim.cell_state(i);
+                let values = [it.water, it.lava, it.sediment, it.settled, it.wear];
+                (it.cell, values.map(f32::to_bits))
+            })
+            .collect()
+    }
+
+    fn edit(history: &mut History, sim: &mut WaterSim, p: [usize; 3], cell: Cell) {
+        history.begin(sim);
+        sim.set_cell(p[0], p[1], p[2], cell);
+        assert!(history.commit(sim, EntryKind::Edit));
+    }
+
+    #[test]
+    fn undo_and_redo_restore_exact_states() {
+        let mut sim = sim();
+        let mut history = History::new(DEFAULT_HISTORY_BYTES);
+        let initial = state(&sim);
+        for y in 2..6 {
+            edit(&mut history, &mut sim, [5, y, 5], Cell::WATER);
+        }
+        let edited = state(&sim);
+        for _ in 0..30 {
+            history.begin(&mut sim);
+            sim.simulate();
+            history.commit(&mut sim, EntryKind::SimStep);
+        }
+        let stepped = state(&sim);
+        assert!(stepped != edited);
+
+        let steps = history.undo_len() - 4;
+        for _ in 0..steps {
+            assert_eq!(history.last(), Some(EntryKind::SimStep));
+            history.undo(&mut sim);
+        }
+        assert!(state(&sim) == edited);
+        while history.undo_len() > 0 {
+            history.undo(&mut sim);
+        }
+        assert!(state(&sim) == initial);
+
+        while history.redo_len() > 0 {
+            history.redo(&mut sim);
+        }
+        assert!(state(&sim) == stepped);
+
+        // recording continues from the restored state
+        history.begin(&mut sim);
+        sim.simulate();
+        history.commit(&mut sim, EntryKind::SimStep);
+        history.undo(&mut sim);
+        assert!(state(&sim) == stepped);
+    }
+
+    #[test]
+    fn undo_and_redo_restore_lava_and_erosion() {
+        let mut map = Map::empty(16, 10, 16);
+        for x in 0..16 {
+            for z in 0..16 {
+                *map.at_mut(x, 0, z) = Cell::GROUND;
+            }
+        }
+        let mut sim = WaterSim::new(map, &CellRegistry::default());
+        sim.set_erosion(ErosionParams {
+            enabled: true,
+            erosion_rate: 0.5,
+            min_speed: 0.0,
+            ..ErosionParams::default()
+        })
+        .unwrap();
+        let mut history = History::new(DEFAULT_HISTORY_BYTES);
+        for y in 1..9 {
+            edit(&mut history, &mut sim, [4, y, 4], Cell::WATER);
+            edit(&mut history, &mut sim, [8, y, 8], Cell::LAVA);
+        }
+        let edited = state(&sim);
+        for _ in 0..40 {
+            history.begin(&mut sim);
+            sim.simulate();
+            history.commit(&mut sim, EntryKind::SimStep);
+        }
+        let stepped = state(&sim);
+        // both fluids and the erosion state took part
+        let has = |field: usize| stepped.iter().any(|it| it.1[field] != 0);
+        assert!(has(0) && has(1) && (2..5).any(has));
+
+        while history.last() == Some(EntryKind::SimStep) {
+            history.undo(&mut sim);
+        }
+        assert!(state(&sim) == edited);
+        while history.redo_len() > 0 {
+            history.redo(&mut sim);
+        }
+        assert!(state(&sim) == stepped);
+    }
+
+    #[test]
+    fn new_entry_discards_redo() {
+        let mut sim = sim();
+        let mut history = History::new(DEFAULT_HISTORY_BYTES);
+        edit(&mut history, &mut sim, [3, 3, 3], Cell::STONE);
+        edit(&mut history, &mut sim, [4, 3, 3], Cell::STONE);
+        history.undo(&mut sim);
+        assert_eq!(history.redo_len(), 1);
+
+        edit(&mut history, &mut sim, [6, 3, 3], Cell::STONE);
+        assert_eq!(history.redo_len(), 0);
+        assert!(history.redo(&mut sim).is_none());
+        assert_eq!(sim.cells()[sim.index(4, 3, 3)], Cell::NONE);
+        assert_eq!(history.undo_len(), 2);
+    }
+
+    #[test]
+    fn mass_change_is_reported_dirty() {
+        let mut sim = sim();
+        let mut history = History::new(DEFAULT_HISTORY_BYTES);
+        edit(&mut history, &mut sim, [4, 2, 4], Cell::WATER);
+        history.begin(&mut sim);
+        sim.inject(4, 2, 4, 0.25);
+        assert!(history.commit(&mut sim, EntryKind::Edit));
+
+        let dirty = history.undo(&mut sim);
+        assert_eq!(sim.cells()[sim.index(4, 2, 4)], Cell::WATER);
+        assert!(dirty.is_some_and(|it| it.contains([4, 2, 4])));
+        let dirty = history.redo(&mut sim);
+        assert!(dirty.is_some_and(|it| it.contains([4, 2, 4])));
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped_over_budget() {
+        let mut sim = sim();
+        let mut history = History::new(DEFAULT_HISTORY_BYTES);
+        let mut states = Vec::new();
+        for x in 2..7 {
+            edit(&mut history, &mut sim, [x, 3, 3], Cell::STONE);
+            states.push(state(&sim));
+        }
+        let entry_bytes = history.used_bytes() / 5;
+        assert_eq!(history.used_bytes(), 5 * entry_bytes);
+
+        history.set_max_bytes(5 * entry_bytes - 1);
+        assert_eq!(history.undo_len(), 4);
+        assert_eq!(history.used_bytes(), 4 * entry_bytes);
+
+        history.set_max_bytes(2 * entry_bytes);
+        assert_eq!(history.undo_len(), 2);
+        history.undo(&mut sim);
+        history.undo(&mut sim);
+        assert!(history.undo(&mut sim).is_none());
+        assert!(state(&sim) == states[2]);
+    }
+}
@@ -4,13 +4,27 @@ use wasm_bindgen::prelude::*;
 use winit::{event_loop::EventLoop, window::WindowBuilder};
 
 mod app;
+pub mod boundary;
+mod brush;
 mod camera;
+pub mod cells;
+mod clock;
+mod emitters;
+mod gpu_sim;
+mod history;
 mod input;
-mod map;
+pub mod map;
 mod materials;
 mod math;
 mod perlin;
+mod picking;
 mod renderer;
+mod scene;
+mod settings;
+mod solver;
+pub mod terrain;
+mod vox;
+mod weather;
 mod xorshift32;
 
 use app::App;
@@ -1,105 +1,434 @@
-use crate::math::*;
-use crate::perlin::Perlin;
+use crate::boundary::{Boundaries, Boundary, Face};
+use crate::brush::Bounds;
+use crate::cells::{Cell, CellRegistry, MAX_CELL_TYPES};
 use crate::renderer::MapDTO;
+use crate::xorshift32::Xorshift32;
 use rand::Rng;
 
-#[repr(u8)]
-#[derive(Clone, Copy, Debug)]
-pub enum Cell {
-    None = 0,
-    Grass = 1,
-    Water = 2,
-    Ground = 3,
+// Thickness of z slabs simulated in parallel, has to be at least 2
+const SLAB_PLANES: usize = 4;
+/// Edge of the cubic blocks of interior cells that sleep together
+pub const BLOCK_SIZE: usize = 8;
+
+/// Constants of the cellular automaton.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaterSimParams {
+    /// Mass of a full cell at rest
+    pub max_mass: f32,
+    /// Extra mass a cell can hold per cell of water above it
+    pub max_compress: f32,
+    /// Cells with less mass become air
+    pub min_mass: f32,
+    /// Flows above this are halved to smooth the motion
+    pub min_flow: f32,
+    /// Maximum vertical flow per step
+    pub max_speed: f32,
+    /// Horizontal flow to a neighbour is the mass difference divided by this,
+    /// higher values make water spread slower
+    pub spread: f32,
+    /// Blocks where no cell mass changed by more than this in a step are not
+    /// simulated until something wakes them, 0 simulates every block
+    pub sleep_threshold: f32,
 }
 
-impl From<Cell> for u8 {
-    fn from(cell: Cell) -> Self {
-        cell as Self
+impl Default for WaterSimParams {
+    fn default() -> Self {
+        Self {
+            max_mass: 1.0,
+            max_compress: 0.02,
+            min_mass: 0.0001,
+            min_flow: 0.01,
+            max_speed: 1.0,
+            spread: 4.0,
+            sleep_threshold: 0.00001,
+        }
     }
 }
 
-impl Cell {
-    fn is_water(&self) -> bool {
-        if let Self::Water = self {
-            return true;
+impl WaterSimParams {
+    /// Viscous fluid that flows slower and spreads less than water.
+    pub fn lava() -> Self {
+        Self {
+            max_compress: 0.01,
+            min_flow: 0.005,
+            max_speed: 0.2,
+            spread: 16.0,
+            ..Self::default()
         }
-
-        false
     }
-    fn is_air(&self) -> bool {
-        if let Self::None = self {
-            return true;
+
+    /// Checks that the simulation stays stable with these values.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let values = [
+            self.max_mass,
+            self.max_compress,
+            self.min_mass,
+            self.min_flow,
+            self.max_speed,
+            self.spread,
+            self.sleep_threshold,
+        ];
+        if values.iter().any(|it| !it.is_finite()) {
+            return Err("parameters must be finite");
+        }
+        if self.max_mass <= 0.0 {
+            return Err("max mass must be positive");
         }
+        if self.max_compress < 0.0 {
+            return Err("max compress must not be negative");
+        }
+        if self.min_mass < 0.0 || self.min_mass >= self.max_mass {
+            return Err("min mass must be in [0, max mass)");
+        }
+        if self.min_flow < 0.0 {
+            return Err("min flow must not be negative");
+        }
+        if self.max_speed <= 0.0 {
+            return Err("max speed must be positive");
+        }
+        // below 2 neighbouring cells swap their levels instead of evening out
+        if self.spread < 2.0 {
+            return Err("spread must be at least 2");
+        }
+        if self.sleep_threshold < 0.0 {
+            return Err("sleep threshold must not be negative");
+        }
+        Ok(())
+    }
+}
 
-        false
+/// Wearing of terrain by flowing water. Cells touching fast water wear out
+/// and are carried away as sediment, which settles where water is slow and
+/// builds up `deposit` cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErosionParams {
+    pub enabled: bool,
+    /// Wear per step of an erodible cell touching water that moves one cell
+    /// per step, the cell is washed away when its wear reaches 1
+    pub erosion_rate: f32,
+    /// Fraction of carried sediment that settles per step in slow water
+    pub deposition_rate: f32,
+    /// Water slower than this, in cells per step, erodes nothing and lets sediment settle
+    pub min_speed: f32,
+    /// Cell types worn away by water
+    pub erodible: Vec<Cell>,
+    /// Solid cell type built from settled sediment
+    pub deposit: Cell,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            erosion_rate: 0.05,
+            deposition_rate: 0.2,
+            min_speed: 0.05,
+            erodible: vec![Cell::GROUND, Cell::GRASS],
+            deposit: Cell::SAND,
+        }
     }
-    fn is_solid(&self) -> bool {
-        match self {
-            Self::Grass | Self::Ground => true,
-            _ => false,
+}
+
+impl ErosionParams {
+    pub fn validate(&self, registry: &CellRegistry) -> Result<(), &'static str> {
+        let values = [self.erosion_rate, self.deposition_rate, self.min_speed];
+        if values.iter().any(|it| !it.is_finite()) {
+            return Err("erosion parameters must be finite");
+        }
+        if self.erosion_rate < 0.0 || self.min_speed < 0.0 {
+            return Err("erosion rate and min speed must not be negative");
+        }
+        if !(0.0..=1.0).contains(&self.deposition_rate) {
+            return Err("deposition rate must be in [0, 1]");
         }
+        if self.erodible.iter().any(|it| registry.get(*it).is_none()) {
+            return Err("unknown erodible cell type");
+        }
+        if !registry.is_solid(self.deposit) {
+            return Err("deposit must be a solid cell type");
+        }
+        Ok(())
+    }
+}
+
+/// Cells changed by erosion since the simulation was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ErosionStats {
+    /// Erodible cells washed away
+    pub eroded: usize,
+    /// Cells built from settled sediment
+    pub deposited: usize,
+}
+
+/// Complete state of one cell, as kept by history and scene files.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CellState {
+    pub cell: Cell,
+    pub water: f32,
+    pub lava: f32,
+    /// Erosion state, zero while erosion is disabled
+    pub sediment: f32,
+    pub settled: f32,
+    pub wear: f32,
+}
+
+impl CellState {
+    /// Compares bit patterns, so that restored state is exactly the same.
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.cell == other.cell
+            && self.values().map(f32::to_bits) == other.values().map(f32::to_bits)
+    }
+
+    fn values(&self) -> [f32; 5] {
+        [
+            self.water,
+            self.lava,
+            self.sediment,
+            self.settled,
+            self.wear,
+        ]
     }
 }
 
+/// Which blocks of `BLOCK_SIZE` interior cells are simulated, for debugging.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockActivity<'a> {
+    /// Number of blocks along each axis
+    pub counts: [usize; 3],
+    /// Indexed by z * (x * y) + y * x + x in block coordinates
+    pub active: &'a [bool],
+}
+
+/// Mass balance of the last `simulate` call. Sums are kept in f64, so that
+/// `drift` shows error of the simulation and not of the summation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MassStats {
+    /// Total mass after the step
+    pub total: f64,
+    /// Added by ocean faces and `inject` since the previous step
+    pub created: f64,
+    /// Flowed out through open faces of the border, turned into stone by lava, pushed
+    /// out by sediment or taken by `remove` since the previous step
+    pub removed: f64,
+    /// Change of total mass during the step not explained by ocean faces and border
+    pub drift: f64,
+}
+
+#[derive(Clone)]
 pub struct WaterSim {
     x: usize,
     y: usize,
     z: usize,
-    max_mass: f32,
-    max_compress: f32,
-    min_mass: f32,
-    min_flow: f32,
-    max_speed: f32,
+    params: WaterSimParams,
     mass: Vec<f32>,
     new_mass: Vec<f32>,
+    // lava flows like water with its own mass and parameters, its mass vectors
+    // stay empty until the first lava cell appears
+    lava: Option<Cell>,
+    // lava touching water turns with it into stone, both are resolved by name
+    // like lava and there is no reaction without them
+    water: Option<Cell>,
+    stone: Option<Cell>,
+    lava_params: WaterSimParams,
+    lava_mass: Vec<f32>,
+    lava_new_mass: Vec<f32>,
+    // water and lava mass together, kept only while there is lava
+    cell_mass: Vec<f32>,
+    // outflow of the last step divided by mass, in cells per step
+    velocity: Vec<[f32; 3]>,
     cells: Vec<Cell>,
-
-    water_height: usize,
+    // quantized fluid level of every cell for rendering, full for other cells
+    fill: Vec<u8>,
+    registry: CellRegistry,
+    rng: Xorshift32,
+    threads: usize,
+    stats: MassStats,
+    // `inject` and `remove` calls since the last step
+    injected: f64,
+    withdrawn: f64,
+    boundaries: Boundaries,
+    erosion: ErosionParams,
+    // sediment carried by water, sediment settled in non-solid cells and wear of
+    // erodible cells, all in cells of deposit; empty while erosion is disabled
+    sediment: Vec<f32>,
+    new_sediment: Vec<f32>,
+    settled: Vec<f32>,
+    wear: Vec<f32>,
+    erosion_stats: ErosionStats,
+    // cells that changed type since the last `take_changed_regions`, per slab
+    changed: Vec<Option<Bounds>>,
+    // cells with velocity written by the last step, per slab
+    velocity_bounds: Vec<Option<Bounds>>,
+    // cells whose velocity changed since the last `take_velocity_regions`, per slab
+    velocity_changed: Vec<Option<Bounds>>,
+    block_counts: [usize; 3],
+    // blocks simulated in the next step
+    active: Vec<bool>,
+    // blocks whose cells or mass could have changed since the last `take_touched_regions`
+    touched: Vec<bool>,
 }
 
 impl WaterSim {
-    pub fn new(map: Map) -> Self {
-        let max_mass = 1.0;
-        let max_compress = 0.02;
-        let min_mass = 0.0001;
-        let max_speed = 1.0;
-        let min_flow = 0.01;
+    /// Water of the map is kept at its level by ocean faces where the default
+    /// boundaries would drain it, see `Boundaries::with_sea`.
+    pub fn new(map: Map, registry: &CellRegistry) -> Self {
         let x = map.x + 2;
         let y = map.y + 2;
         let z = map.z + 2;
-        let mut new_cells = vec![Cell::None; x * y * z];
+        let mut cells = vec![Cell::NONE; x * y * z];
         let mut mass = vec![0.0; x * y * z];
-        let new_mass = vec![0.0; x * y * z];
         let mut water_height = 0;
+        let lava = registry.by_name("lava");
         for xi in 0..map.x {
             for yi in 0..map.y {
                 for zi in 0..map.z {
                     let c = map.at(xi, yi, zi);
-                    new_cells[(zi + 1) * (x * y) + (yi + 1) * x + (xi + 1)] = c;
-                    if c.is_water() {
-                        mass[(zi + 1) * (x * y) + (yi + 1) * x + (xi + 1)] = max_mass;
-                        water_height = yi + 1;
+                    cells[(zi + 1) * (x * y) + (yi + 1) * x + (xi + 1)] = c;
+                    if registry.is_fluid(c) {
+                        mass[(zi + 1) * (x * y) + (yi + 1) * x + (xi + 1)] = 1.0;
+                        if Some(c) != lava {
+                            water_height = yi + 1;
+                        }
                     }
                 }
             }
         }
 
-        let cells = new_cells;
-        Self {
+        let mut sim = Self::from_parts(x, y, z, cells, mass, registry);
+        if water_height > 0 {
+            sim.boundaries = sim.boundaries.with_sea(water_height + 1);
+        }
+        sim
+    }
+
+    /// Builds simulation directly from padded grid, as returned by `cells` and `mass`.
+    pub fn from_parts(
+        x: usize,
+        y: usize,
+        z: usize,
+        cells: Vec<Cell>,
+        mass: Vec<f32>,
+        registry: &CellRegistry,
+    ) -> Self {
+        assert_eq!(cells.len(), x * y * z);
+        assert_eq!(mass.len(), x * y * z);
+        let new_mass = mass.clone();
+        let block_counts = [x, y, z].map(|it| it.saturating_sub(2).div_ceil(BLOCK_SIZE));
+        let mut sim = Self {
             x,
             y,
             z,
-
-            max_mass,
-            max_compress,
-            min_mass,
-            min_flow,
-            max_speed,
+            params: WaterSimParams::default(),
             mass,
             new_mass,
+            lava: registry.by_name("lava"),
+            water: registry.by_name("water"),
+            stone: registry.by_name("stone"),
+            lava_params: WaterSimParams::lava(),
+            lava_mass: Vec::new(),
+            lava_new_mass: Vec::new(),
+            cell_mass: Vec::new(),
+            velocity: vec![[0.0; 3]; x * y * z],
             cells,
-            water_height,
+            fill: vec![u8::MAX; x * y * z],
+            registry: registry.clone(),
+            rng: Xorshift32::new(0),
+            threads: default_threads(),
+            stats: MassStats::default(),
+            injected: 0.0,
+            withdrawn: 0.0,
+            boundaries: Boundaries::default(),
+            erosion: ErosionParams::default(),
+            sediment: Vec::new(),
+            new_sediment: Vec::new(),
+            settled: Vec::new(),
+            wear: Vec::new(),
+            erosion_stats: ErosionStats::default(),
+            changed: vec![None; z.saturating_sub(2).div_ceil(SLAB_PLANES)],
+            velocity_bounds: vec![None; z.saturating_sub(2).div_ceil(SLAB_PLANES)],
+            velocity_changed: vec![None; z.saturating_sub(2).div_ceil(SLAB_PLANES)],
+            block_counts,
+            active: vec![true; block_counts.iter().product()],
+            touched: vec![true; block_counts.iter().product()],
+        };
+        for i in 0..sim.cells.len() {
+            let cell = sim.cells[i];
+            if sim.is_lava(cell) {
+                sim.set_fluid_mass(i, cell, sim.mass[i]);
+            }
+        }
+        for i in 0..sim.fill.len() {
+            sim.fill[i] = sim.compute_fill(i);
+        }
+        sim
+    }
+
+    pub fn params(&self) -> &WaterSimParams {
+        &self.params
+    }
+
+    /// Takes effect from the next step. Invalid parameters are rejected.
+    pub fn set_params(&mut self, params: WaterSimParams) -> Result<(), &'static str> {
+        params.validate()?;
+        self.params = params;
+        self.wake_all();
+        Ok(())
+    }
+
+    pub fn lava_params(&self) -> &WaterSimParams {
+        &self.lava_params
+    }
+
+    /// Takes effect from the next step. Invalid parameters are rejected.
+    pub fn set_lava_params(&mut self, params: WaterSimParams) -> Result<(), &'static str> {
+        params.validate()?;
+        self.lava_params = params;
+        self.wake_all();
+        Ok(())
+    }
+
+    pub fn boundaries(&self) -> &Boundaries {
+        &self.boundaries
+    }
+
+    /// Takes effect from the next step. Periodic faces have to come in pairs
+    /// and ocean levels have to be inside of the grid.
+    pub fn set_boundaries(&mut self, boundaries: Boundaries) -> Result<(), &'static str> {
+        boundaries.validate(self.y)?;
+        self.boundaries = boundaries;
+        self.wake_all();
+        Ok(())
+    }
+
+    pub fn erosion(&self) -> &ErosionParams {
+        &self.erosion
+    }
+
+    /// Takes effect from the next step. Disabling erosion drops carried
+    /// sediment and accumulated wear.
+    pub fn set_erosion(&mut self, params: ErosionParams) -> Result<(), &'static str> {
+        params.validate(&self.registry)?;
+        let len = self.cells.len();
+        let buffers = [
+            &mut self.sediment,
+            &mut self.new_sediment,
+            &mut self.settled,
+            &mut self.wear,
+        ];
+        for it in buffers {
+            if !params.enabled {
+                *it = Vec::new();
+            } else if it.is_empty() {
+                *it = vec![0.0; len];
+            }
         }
+        self.erosion = params;
+        self.wake_all();
+        Ok(())
+    }
+
+    pub fn erosion_stats(&self) -> &ErosionStats {
+        &self.erosion_stats
     }
 
     pub fn x(&self) -> usize {
@@ -112,6 +441,94 @@ impl WaterSim {
         self.z
     }
 
+    pub fn registry(&self) -> &CellRegistry {
+        &self.registry
+    }
+
+    /// Seeds random generator used by stochastic parts of simulation.
+    pub fn reseed(&mut self, seed: u32) {
+        self.rng = Xorshift32::new(seed);
+    }
+
+    pub fn rng(&self) -> &Xorshift32 {
+        &self.rng
+    }
+
+    pub fn set_rng(&mut self, rng: Xorshift32) {
+        self.rng = rng;
+    }
+
+    pub fn stats(&self) -> &MassStats {
+        &self.stats
+    }
+
+    /// Mass of water, lava is counted separately by `total_lava_mass`.
+    pub fn total_mass(&self) -> f64 {
+        self.mass.iter().map(|it| *it as f64).sum()
+    }
+
+    pub fn total_lava_mass(&self) -> f64 {
+        self.lava_mass.iter().map(|it| *it as f64).sum()
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// Mass of the fluid in every cell, water and lava together.
+    pub fn mass(&self) -> &[f32] {
+        if self.has_lava() {
+            &self.cell_mass
+        } else {
+            &self.mass
+        }
+    }
+
+    /// Velocity of water in every cell during the last step, in cells per step,
+    /// indexed the same way as `mass`. It is the mass that flowed out of the
+    /// cell towards each direction divided by the mass the cell had, so it is
+    /// zero for solids, empty cells and sleeping blocks.
+    pub fn velocity(&self) -> &[[f32; 3]] {
+        &self.velocity
+    }
+
+    /// State of the cell at linear index `i`.
+    pub fn cell_state(&self, i: usize) -> CellState {
+        let value = |it: &[f32]| it.get(i).copied().unwrap_or(0.0);
+        CellState {
+            cell: self.cells[i],
+            water: self.mass[i],
+            lava: value(&self.lava_mass),
+            sediment: value(&self.sediment),
+            settled: value(&self.settled),
+            wear: value(&self.wear),
+        }
+    }
+
+    /// Overwrites complete state of the cell at linear index `i`. Erosion
+    /// state is dropped while erosion is disabled.
+    pub fn restore(&mut self, i: usize, state: &CellState) {
+        self.cells[i] = state.cell;
+        if state.lava != 0.0 || self.is_lava(state.cell) {
+            self.alloc_lava();
+        }
+        self.mass[i] = state.water;
+        self.new_mass[i] = state.water;
+        if self.has_lava() {
+            self.lava_mass[i] = state.lava;
+            self.lava_new_mass[i] = state.lava;
+        }
+        self.sync_cell_mass(i);
+        if self.erosion.enabled {
+            self.sediment[i] = state.sediment;
+            self.new_sediment[i] = state.sediment;
+            self.settled[i] = state.settled;
+            self.wear[i] = state.wear;
+        }
+        self.wake(i % self.x, i / self.x % self.y, i / (self.x * self.y));
+        self.refresh_fill_around(i);
+    }
+
     pub fn at(&self, x: usize, y: usize, z: usize) -> Cell {
         self.cells[z * (self.x * self.y) + y * self.x + x]
     }
@@ -120,26 +537,59 @@ impl WaterSim {
         &mut self.cells[z * (self.x * self.y) + y * self.x + x]
     }
 
-    pub fn set_mass(&mut self, x: usize, y: usize, z: usize) {
+    /// Changes cell type keeping mass consistent with it: fluid cells are
+    /// filled completely, other cells are emptied.
+    pub fn set_cell(&mut self, x: usize, y: usize, z: usize, cell: Cell) {
         let i = self.index(x, y, z);
-        self.mass[i] = self.max_mass;
+        let mass = if self.is_lava(cell) {
+            self.lava_params.max_mass
+        } else if self.registry.is_fluid(cell) {
+            self.params.max_mass
+        } else {
+            0.0
+        };
+        self.cells[i] = cell;
+        self.set_fluid_mass(i, cell, mass);
+        self.wake(x, y, z);
+        self.refresh_fill_around(i);
     }
 
-    fn index(&self, x: usize, y: usize, z: usize) -> usize {
-        z * (self.x * self.y) + y * self.x + x
+    pub fn set_mass(&mut self, x: usize, y: usize, z: usize) {
+        let i = self.index(x, y, z);
+        self.wake(x, y, z);
+        self.mass[i] = self.params.max_mass;
+        self.sync_cell_mass(i);
+        self.refresh_fill_around(i);
     }
 
-    fn get_stable_state_b(&self, mass: f32) -> f32 {
-        if mass <= 1.0 {
-            return 1.0;
+    /// Adds water to non-solid cell. Returns amount that was actually added.
+    pub fn inject(&mut self, x: usize, y: usize, z: usize, amount: f32) -> f32 {
+        let i = self.index(x, y, z);
+        if self.registry.is_solid(self.cells[i]) || amount <= 0.0 {
+            return 0.0;
         }
+        self.mass[i] += amount;
+        self.sync_cell_mass(i);
+        self.injected += amount as f64;
+        self.wake(x, y, z);
+        amount
+    }
 
-        if mass < 2.0 * self.max_mass + self.max_compress {
-            return (self.max_mass * self.max_mass + mass * self.max_compress)
-                / (self.max_mass + self.max_compress);
+    /// Takes water from the cell. Returns amount that was actually removed.
+    pub fn remove(&mut self, x: usize, y: usize, z: usize, amount: f32) -> f32 {
+        let i = self.index(x, y, z);
+        let removed = amount.clamp(0.0, self.mass[i].max(0.0));
+        self.mass[i] -= removed;
+        self.sync_cell_mass(i);
+        self.withdrawn += removed as f64;
+        if removed > 0.0 {
+            self.wake(x, y, z);
         }
+        removed
+    }
 
-        (mass + self.max_compress) * 0.5
+    pub fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        z * (self.x * self.y) + y * self.x + x
     }
 
     pub fn as_dto<'a>(&'a self) -> MapDTO<'a> {
@@ -151,278 +601,1751 @@ impl WaterSim {
             y: self.y,
             z: self.z,
             cells,
+            fill: Some(&self.fill),
         }
     }
 
-    pub fn simulate(&mut self) -> bool {
-        for x in 1..self.x - 1 {
-            for z in 1..self.z - 1 {
-                for y in 1..self.y - 1 {
-                    if self.cells[self.index(x, y, z)].is_solid() {
-                        continue;
-                    }
+    /// Number of threads used by `simulate`, 1 runs everything on the calling thread.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
 
-                    let mut remaining_mass = self.mass[self.index(x, y, z)];
-                    if remaining_mass <= 0.0 {
-                        continue;
-                    }
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
 
-                    if !self.cells[self.index(x, y - 1, z)].is_solid() {
-                        let mut flow = self.get_stable_state_b(
-                            remaining_mass + self.mass[self.index(x, y - 1, z)],
-                        ) - self.mass[self.index(x, y - 1, z)];
-                        if flow > self.min_flow {
-                            flow *= 0.5;
-                        }
-                        let flow = flow.clamp(0.0, self.max_speed.min(remaining_mass));
+    pub fn simulate(&mut self) -> bool {
+        // mass could have been changed from outside since the last step
+        self.new_mass.copy_from_slice(&self.mass);
+        self.lava_new_mass.copy_from_slice(&self.lava_mass);
+        let start_total = self.total_mass();
+        self.touch_active();
+        let slabs = self.slabs();
+        let flow = FlowContext {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            boundaries: self.boundaries.0,
+            cells: &self.cells,
+            mass: &self.mass,
+            registry: &self.registry,
+            params: &self.params,
+            block_counts: self.block_counts,
+            active: &self.active,
+        };
+        let velocity_bounds = flow_pass(
+            self.threads,
+            &slabs,
+            &flow,
+            &mut self.new_mass,
+            Some(&mut self.velocity),
+        );
+        // velocity of cells written by the previous step was reset
+        for (slab, region) in velocity_bounds.into_iter().enumerate() {
+            let previous = std::mem::replace(&mut self.velocity_bounds[slab], region);
+            for region in [previous, region].into_iter().flatten() {
+                let it = &mut self.velocity_changed[slab];
+                *it = Some(it.map_or(region, |it| it.union(&region)));
+            }
+        }
+        wrap_periodic(
+            &mut self.new_mass,
+            [self.x, self.y, self.z],
+            &self.boundaries,
+        );
+        if self.has_lava() {
+            // the ocean holds only water
+            let boundaries = self.boundaries.0.map(|it| match it {
+                Boundary::Ocean { .. } => Boundary::Drain,
+                it => it,
+            });
+            let flow = FlowContext {
+                mass: &self.lava_mass,
+                params: &self.lava_params,
+                boundaries,
+                ..flow
+            };
+            flow_pass(self.threads, &slabs, &flow, &mut self.lava_new_mass, None);
+            let size = [self.x, self.y, self.z];
+            wrap_periodic(&mut self.lava_new_mass, size, &self.boundaries);
+        }
+        std::mem::swap(&mut self.mass, &mut self.new_mass);
+        std::mem::swap(&mut self.lava_mass, &mut self.lava_new_mass);
+        self.update_activity();
 
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x, y - 1, z);
-                        self.new_mass[i] += flow;
-                        remaining_mass -= flow;
+        let plane = self.x * self.y;
+        let changed = {
+            let update = |z0: usize,
+                          cells: &mut [Cell],
+                          fill: &mut [u8],
+                          mass: &[f32],
+                          lava: Option<&[f32]>| {
+                let mut changed: Option<Bounds> = None;
+                for (i, (cell, fill)) in cells.iter_mut().zip(fill).enumerate() {
+                    let (x, y) = (i % self.x, i / self.x % self.y);
+                    if x == 0 || y == 0 || x == self.x - 1 || y == self.y - 1 {
+                        continue;
                     }
-
-                    if remaining_mass <= 0.0 {
+                    if self.registry.is_solid(*cell) {
                         continue;
                     }
-
-                    // left
-                    if x - 1 != 0 && !self.cells[self.index(x - 1, y, z)].is_solid() {
-                        let mut flow = (self.mass[self.index(x, y, z)]
-                            - self.mass[self.index(x - 1, y, z)])
-                            / 4.0;
-                        if flow > self.min_flow {
-                            flow *= 0.5;
-                        }
-                        flow = flow.clamp(0.0, remaining_mass);
-
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x - 1, y, z);
-                        self.new_mass[i] += flow;
-                        remaining_mass -= flow;
+                    let lava_mass = lava.map_or(0.0, |it| it[i]);
+                    // the cell above is in the same plane, unless it is padding
+                    let above = if y + 2 < self.y {
+                        mass[i + self.x] + lava.map_or(0.0, |it| it[i + self.x])
+                    } else {
+                        0.0
+                    };
+                    // water wins over lava, the two react at the end of the step anyway
+                    let (new_cell, new_fill) = if let Some(water_cell) =
+                        self.water.filter(|_| mass[i] > self.params.min_mass)
+                    {
+                        (water_cell, fill_level(mass[i], above, &self.params))
+                    } else if let Some(lava_cell) =
+                        self.lava.filter(|_| lava_mass > self.lava_params.min_mass)
+                    {
+                        (lava_cell, fill_level(lava_mass, above, &self.lava_params))
+                    } else {
+                        (Cell::NONE, u8::MAX)
+                    };
+                    if *cell != new_cell || *fill != new_fill {
+                        let p = Bounds::point([x, y, z0 + i / plane]);
+                        changed = Some(changed.map_or(p, |it| it.union(&p)));
                     }
+                    *cell = new_cell;
+                    *fill = new_fill;
+                }
+                changed
+            };
+            let mut cells = std::mem::take(&mut self.cells);
+            let mut fill = std::mem::take(&mut self.fill);
+            let interior = &mut cells[plane..(self.z - 1) * plane];
+            let fill_interior = &mut fill[plane..(self.z - 1) * plane];
+            let mass = &self.mass[plane..(self.z - 1) * plane];
+            let lava = self
+                .has_lava()
+                .then(|| &self.lava_mass[plane..(self.z - 1) * plane]);
+            let update = &update;
+            let mut jobs = Vec::new();
+            let mut rest = interior;
+            let mut fill_rest = fill_interior;
+            for &(z0, z1) in slabs.iter() {
+                let (chunk, tail) = std::mem::take(&mut rest).split_at_mut((z1 - z0) * plane);
+                rest = tail;
+                let (fill_chunk, tail) =
+                    std::mem::take(&mut fill_rest).split_at_mut((z1 - z0) * plane);
+                fill_rest = tail;
+                let mass = &mass[(z0 - 1) * plane..(z1 - 1) * plane];
+                let lava = lava.map(|it| &it[(z0 - 1) * plane..(z1 - 1) * plane]);
+                jobs.push(move || update(z0, chunk, fill_chunk, mass, lava));
+            }
+            let changed = run_jobs(self.threads, jobs);
+            self.cells = cells;
+            self.fill = fill;
+            changed
+        };
+        let mut any_changed = false;
+        for (slab, region) in changed.into_iter().enumerate() {
+            if let Some(region) = region {
+                self.mark_changed(slab, &region);
+                any_changed = true;
+            }
+        }
+        let (solidified, reacted) = if self.has_lava() {
+            self.solidify()
+        } else {
+            (false, 0.0)
+        };
+        let (eroded, displaced) = if self.erosion.enabled {
+            self.erode()
+        } else {
+            (false, 0.0)
+        };
 
-                    if remaining_mass <= 0.0 {
-                        continue;
-                    }
-                    // right
-                    if x + 1 != self.x && !self.cells[self.index(x + 1, y, z)].is_solid() {
-                        let mut flow = (self.mass[self.index(x, y, z)]
-                            - self.mass[self.index(x + 1, y, z)])
-                            / 4.0;
-                        if flow > self.min_flow {
-                            flow *= 0.5;
-                        }
-                        flow = flow.clamp(0.0, remaining_mass);
+        let mut refilled = false;
+        let mut created = 0.0;
+        for face in Face::ALL {
+            if let Boundary::Ocean { level } = self.boundaries.get(face) {
+                let (changed, added) = self.fill_ocean(face, level);
+                refilled |= changed;
+                created += added;
+            }
+        }
 
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x + 1, y, z);
-                        self.new_mass[i] += flow;
-                        remaining_mass -= flow;
-                    }
+        let removed = self.clear_border() + reacted + displaced;
+        if self.has_lava() {
+            for ((it, water), lava) in self
+                .cell_mass
+                .iter_mut()
+                .zip(&self.mass)
+                .zip(&self.lava_mass)
+            {
+                *it = water + lava;
+            }
+        }
+        let total = self.total_mass();
+        self.stats = MassStats {
+            total,
+            created: created + std::mem::take(&mut self.injected),
+            removed: removed + std::mem::take(&mut self.withdrawn),
+            drift: total - (start_total + created - removed),
+        };
+        any_changed || refilled || solidified || eroded
+    }
 
-                    if remaining_mass <= 0.0 {
-                        continue;
-                    }
-                    // up
-                    if z - 1 != 0 && !self.cells[self.index(x, y, z - 1)].is_solid() {
-                        let mut flow = (self.mass[self.index(x, y, z)]
-                            - self.mass[self.index(x, y, z - 1)])
-                            / 4.0;
-                        if flow > self.min_flow {
-                            flow *= 0.5;
-                        }
-                        flow = flow.clamp(0.0, remaining_mass);
+    /// Moves sediment with the water, wears erodible cells next to fast water
+    /// and builds deposit cells from settled sediment. Returns whether any cell
+    /// changed and the water mass that had nowhere to go from new deposit cells.
+    fn erode(&mut self) -> (bool, f64) {
+        let mut erodible = [false; MAX_CELL_TYPES];
+        for it in self.erosion.erodible.iter() {
+            erodible[it.id() as usize] = true;
+        }
+        let ErosionParams {
+            erosion_rate,
+            deposition_rate,
+            min_speed,
+            deposit,
+            ..
+        } = self.erosion;
+        let plane = self.x * self.y;
+        let strides = [1, self.x, plane];
+        let speed = |sim: &Self, i: usize| {
+            if sim.mass[i] > sim.params.min_mass {
+                let [x, y, z] = sim.velocity[i];
+                (x * x + y * y + z * z).sqrt()
+            } else {
+                0.0
+            }
+        };
 
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x, y, z - 1);
-                        self.new_mass[i] += flow;
-                        remaining_mass -= flow;
-                    }
+        // sediment follows the water, in the direction and at the speed it moved
+        self.new_sediment.copy_from_slice(&self.sediment);
+        for i in 0..self.sediment.len() {
+            let sediment = self.sediment[i];
+            if sediment <= 0.0 || !self.is_interior(i) || speed(self, i) <= 0.0 {
+                continue;
+            }
+            let velocity = self.velocity[i];
+            let total = velocity.iter().map(|it| it.abs()).sum::<f32>().max(1.0);
+            for (v, stride) in velocity.into_iter().zip(strides) {
+                let target = if v > 0.0 { i + stride } else { i - stride };
+                if v == 0.0 || self.registry.is_solid(self.cells[target]) {
+                    continue;
+                }
+                let amount = sediment * v.abs() / total;
+                self.new_sediment[i] -= amount;
+                self.new_sediment[target] += amount;
+            }
+        }
+        std::mem::swap(&mut self.sediment, &mut self.new_sediment);
+        clear_padding(&mut self.sediment, self.x, self.y, self.z);
 
-                    if remaining_mass <= 0.0 {
+        let mut changed = false;
+        let mut displaced = 0.0;
+        for z in 1..self.z - 1 {
+            for y in 1..self.y - 1 {
+                for x in 1..self.x - 1 {
+                    let i = self.index(x, y, z);
+                    if self.registry.is_solid(self.cells[i]) {
                         continue;
                     }
-                    // down
-                    if z + 1 != self.z && !self.cells[self.index(x, y, z + 1)].is_solid() {
-                        let mut flow = (self.mass[self.index(x, y, z)]
-                            - self.mass[self.index(x, y, z + 1)])
-                            / 4.0;
-                        if flow > self.min_flow {
-                            flow *= 0.5;
+                    let speed = speed(self, i);
+                    if speed > min_speed {
+                        for j in self.face_neighbours(i) {
+                            if !self.is_interior(j) || !erodible[self.cells[j].id() as usize] {
+                                continue;
+                            }
+                            self.wear[j] += erosion_rate * speed;
+                            if self.wear[j] >= 1.0 {
+                                self.replace_cell(j, Cell::NONE);
+                                self.sediment[i] += 1.0;
+                                self.erosion_stats.eroded += 1;
+                                changed = true;
+                            }
                         }
-                        flow = flow.clamp(0.0, remaining_mass);
-
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x, y, z + 1);
-                        self.new_mass[i] += flow;
-                        remaining_mass -= flow;
+                    } else if self.sediment[i] > 0.0 {
+                        // without water everything drops at once
+                        let rate = if self.mass[i] > self.params.min_mass {
+                            deposition_rate
+                        } else {
+                            1.0
+                        };
+                        let amount = self.sediment[i] * rate;
+                        self.sediment[i] -= amount;
+                        self.settled[i] += amount;
                     }
 
-                    if remaining_mass <= 0.0 {
+                    if self.settled[i] <= 0.0 {
                         continue;
                     }
-
-                    // vertical up
-                    if !self.cells[self.index(x, y + 1, z)].is_solid() {
-                        let mut flow = remaining_mass
-                            - self.get_stable_state_b(
-                                remaining_mass + self.mass[self.index(x, y + 1, z)],
-                            );
-                        if flow > self.min_flow {
-                            flow *= 0.5;
+                    // settled sediment sinks one cell per step until it rests on a solid
+                    let below = i - self.x;
+                    if y > 1 && !self.registry.is_solid(self.cells[below]) {
+                        self.settled[below] += std::mem::take(&mut self.settled[i]);
+                    } else if self.settled[i] >= 1.0 {
+                        let settled = self.settled[i] - 1.0;
+                        let sediment = self.sediment[i];
+                        let water = self.replace_cell(i, deposit);
+                        self.erosion_stats.deposited += 1;
+                        changed = true;
+                        // what the new cell held goes to the cell above
+                        let above = i + self.x;
+                        if y + 1 < self.y - 1 && !self.registry.is_solid(self.cells[above]) {
+                            self.settled[above] += settled;
+                            self.sediment[above] += sediment;
+                            self.mass[above] += water as f32;
+                            self.sync_cell_mass(above);
+                            self.wake(x, y + 1, z);
+                        } else {
+                            displaced += water;
                         }
-                        let flow = flow.clamp(0.0, self.max_speed.min(remaining_mass));
-
-                        let i = self.index(x, y, z);
-                        self.new_mass[i] -= flow;
-                        let i = self.index(x, y + 1, z);
-                        self.new_mass[i] += flow;
-                        // remaining_mass -= flow;
                     }
                 }
             }
         }
+        (changed, displaced)
+    }
 
-        let mut changed = false;
-        self.mass = self.new_mass.clone();
-        for x in 1..self.x - 1 {
-            for z in 1..self.z - 1 {
-                for y in 1..self.y - 1 {
+    /// Turns every lava cell touching water, and the water it touches, into
+    /// stone. Returns whether any cell turned and the water mass it took.
+    fn solidify(&mut self) -> (bool, f64) {
+        let (Some(water), Some(stone)) = (self.water, self.stone) else {
+            return (false, 0.0);
+        };
+        let has_water =
+            |sim: &Self, i: usize| sim.cells[i] == water && sim.mass[i] > sim.params.min_mass;
+        let mut touching = Vec::new();
+        for z in 1..self.z - 1 {
+            for y in 1..self.y - 1 {
+                for x in 1..self.x - 1 {
                     let i = self.index(x, y, z);
-                    if self.cells[i].is_solid() {
+                    if self.lava_mass[i] <= self.lava_params.min_mass {
                         continue;
                     }
-
-                    if self.mass[i] > self.min_mass {
-                        changed = true;
-                        self.cells[i] = Cell::Water;
-                    } else {
-                        changed = true;
-                        self.cells[i] = Cell::None;
+                    let neighbours = self.face_neighbours(i);
+                    if has_water(self, i) || neighbours.into_iter().any(|j| has_water(self, j)) {
+                        touching.push(i);
                     }
                 }
             }
         }
 
-        for x in 1..self.x - 1 {
-            for z in 1..self.z - 1 {
-                let i = self.index(x, self.water_height, z);
-                if self.cells[i].is_air() {
-                    self.cells[i] = Cell::Water;
-                    self.mass[i] = self.max_mass;
+        let mut removed = 0.0;
+        for &i in touching.iter() {
+            removed += self.replace_cell(i, stone);
+            for j in self.face_neighbours(i) {
+                if self.is_interior(j) && has_water(self, j) {
+                    removed += self.replace_cell(j, stone);
                 }
             }
         }
+        (!touching.is_empty(), removed)
+    }
 
-        for x in 0..self.x {
-            for z in 0..self.z {
-                for y in 0..self.y {
-                    let i = self.index(x, y, z);
-                    if x == 0
-                        || y == 0
-                        || z == 0
-                        || x == self.x - 1
-                        || y == self.y - 1
-                        || z == self.z - 1
-                    {
-                        self.mass[i] = 0.0;
-                    }
+    /// Tops up non-solid cells next to an ocean face below its level. Returns
+    /// whether any cell became water and the added mass.
+    fn fill_ocean(&mut self, face: Face, level: usize) -> (bool, f64) {
+        // without a water cell type there is nothing to fill with
+        let Some(water) = self.water else {
+            return (false, 0.0);
+        };
+        let size = [self.x, self.y, self.z];
+        let axis = face.axis();
+        let [a, b] = [(axis + 1) % 3, (axis + 2) % 3];
+        let mut changed = false;
+        let mut created = 0.0;
+        for u in 1..size[a] - 1 {
+            for v in 1..size[b] - 1 {
+                let mut p = [0; 3];
+                p[a] = u;
+                p[b] = v;
+                p[axis] = if face.is_positive() {
+                    size[axis] - 2
+                } else {
+                    1
+                };
+                if p[1] >= level {
+                    continue;
                 }
+                let i = self.index(p[0], p[1], p[2]);
+                let cell = self.cells[i];
+                if self.registry.is_solid(cell)
+                    || self.is_lava(cell)
+                    || self.params.max_mass - self.mass[i] <= self.params.min_mass
+                {
+                    continue;
+                }
+                changed |= cell != water;
+                created += self.fill_cell(i, water);
             }
         }
+        (changed, created)
+    }
 
-        changed
+    /// Fills a non-solid interior cell with water at rest, `water` is the cell
+    /// type of the registry. Returns added mass.
+    fn fill_cell(&mut self, i: usize, water: Cell) -> f64 {
+        let created = (self.params.max_mass - self.mass[i]) as f64;
+        let [x, y, z] = self.coords(i);
+        if self.cells[i] != water {
+            self.cells[i] = water;
+            self.mark_changed((z - 1) / SLAB_PLANES, &Bounds::point([x, y, z]));
+        }
+        self.mass[i] = self.params.max_mass;
+        self.sync_cell_mass(i);
+        self.wake(x, y, z);
+        self.refresh_fill_around(i);
+        created
     }
-}
 
-#[derive(Clone)]
-pub struct Map {
-    x: usize,
-    y: usize,
-    z: usize,
-    cells: Vec<Cell>,
-}
+    /// Replaces an interior cell with a solid or air, dropping its fluid,
+    /// sediment and wear. Returns water mass the cell had.
+    fn replace_cell(&mut self, i: usize, cell: Cell) -> f64 {
+        let removed = self.mass[i] as f64;
+        self.cells[i] = cell;
+        self.set_fluid_mass(i, cell, 0.0);
+        if self.erosion.enabled {
+            self.sediment[i] = 0.0;
+            self.settled[i] = 0.0;
+            self.wear[i] = 0.0;
+        }
+        let [x, y, z] = self.coords(i);
+        self.mark_changed((z - 1) / SLAB_PLANES, &Bounds::point([x, y, z]));
+        self.wake(x, y, z);
+        self.refresh_fill_around(i);
+        removed
+    }
 
-impl Map {
-    pub fn at(&self, x: usize, y: usize, z: usize) -> Cell {
-        self.cells[z * (self.x * self.y) + y * self.x + x]
+    fn face_neighbours(&self, i: usize) -> [usize; 6] {
+        let plane = self.x * self.y;
+        [i - 1, i + 1, i - self.x, i + self.x, i - plane, i + plane]
     }
 
-    pub fn at_mut(&mut self, x: usize, y: usize, z: usize) -> &mut Cell {
-        &mut self.cells[z * (self.x * self.y) + y * self.x + x]
+    fn coords(&self, i: usize) -> [usize; 3] {
+        [i % self.x, i / self.x % self.y, i / (self.x * self.y)]
     }
 
-    pub fn cube(x: usize, y: usize, z: usize) -> Self {
-        let cells = (0..x * y * z).map(|_| Cell::Grass).collect();
-        Self { x, y, z, cells }
+    fn is_interior(&self, i: usize) -> bool {
+        let [x, y, z] = self.coords(i);
+        x > 0 && y > 0 && z > 0 && x < self.x - 1 && y < self.y - 1 && z < self.z - 1
     }
 
-    pub fn random(x: usize, y: usize, z: usize) -> Self {
-        let mut rng = rand::thread_rng();
-        let cells = (0..x * y * z)
-            .map(|_| match rng.gen::<u32>() % 4 {
-                //0 => Cell::None,
-                1 | 2 => Cell::Water,
-                //2 => Cell::Stone,
-                _ => Cell::Ground,
-            })
-            .collect();
-        Self { x, y, z, cells }
+    fn is_lava(&self, cell: Cell) -> bool {
+        self.lava == Some(cell)
     }
 
-    pub fn with_perlin(x: usize, y: usize, z: usize, perlin: &mut Perlin) -> Self {
-        let cells = vec![Cell::None; x * y * z];
-        let mut map = Self { x, y, z, cells };
-        let mut min_height = usize::MAX;
-        for px in 0..x {
-            for pz in 0..z {
-                let p = Vector3::new(px as f32 / x as f32, 0.0, pz as f32 / z as f32);
-                let perlin_value = perlin.turb(p, 4);
-                let height = (perlin_value.sin() + 1.0) * 0.5 * (y as f32);
-                let height = height as usize;
-                for py in 0..height {
-                    let cell = if py < height - 1 {
-                        Cell::Ground
-                    } else if py == height - 1 {
-                        if py < min_height {
-                            min_height = py;
-                        }
-                        Cell::Grass
-                    } else {
-                        Cell::None
-                    };
-                    *map.at_mut(px, py, pz) = cell;
-                }
-            }
+    fn has_lava(&self) -> bool {
+        !self.lava_mass.is_empty()
+    }
+
+    /// Lava mass vectors are allocated once the first lava appears.
+    fn alloc_lava(&mut self) {
+        if !self.has_lava() {
+            let len = self.mass.len();
+            self.lava_mass = vec![0.0; len];
+            self.lava_new_mass = vec![0.0; len];
+            self.cell_mass = self.mass.clone();
         }
+    }
 
-        for px in 0..x {
-            for pz in 0..z {
-                let cur = map.at(px, min_height, pz);
-                if let Cell::Grass = cur {
-                    *map.at_mut(px, min_height, pz) = Cell::Water;
-                }
+    /// Puts all fluid of the cell into the mass field of its type.
+    fn set_fluid_mass(&mut self, i: usize, cell: Cell, mass: f32) {
+        let lava = self.is_lava(cell);
+        if lava {
+            self.alloc_lava();
+        }
+        let (water, lava) = if lava { (0.0, mass) } else { (mass, 0.0) };
+        self.mass[i] = water;
+        self.new_mass[i] = water;
+        if self.has_lava() {
+            self.lava_mass[i] = lava;
+            self.lava_new_mass[i] = lava;
+        }
+        self.sync_cell_mass(i);
+    }
+
+    fn sync_cell_mass(&mut self, i: usize) {
+        if self.has_lava() {
+            self.cell_mass[i] = self.mass[i] + self.lava_mass[i];
+        }
+    }
+
+    fn mark_changed(&mut self, slab: usize, region: &Bounds) {
+        let it = &mut self.changed[slab];
+        *it = Some(it.map_or(*region, |it| it.union(region)));
+    }
+
+    /// Fill level of the cell as uploaded for rendering.
+    fn compute_fill(&self, i: usize) -> u8 {
+        if !self.registry.is_fluid(self.cells[i]) {
+            return u8::MAX;
+        }
+        let fluid_mass = |i: usize| self.mass[i] + self.lava_mass.get(i).copied().unwrap_or(0.0);
+        let y = i / self.x % self.y;
+        let above = if y + 2 < self.y {
+            fluid_mass(i + self.x)
+        } else {
+            0.0
+        };
+        if self.is_lava(self.cells[i]) {
+            fill_level(self.lava_mass[i], above, &self.lava_params)
+        } else {
+            fill_level(self.mass[i], above, &self.params)
+        }
+    }
+
+    /// Updates fill of the cell and of the one below, which depends on it.
+    fn refresh_fill_around(&mut self, i: usize) {
+        for i in [Some(i), i.checked_sub(self.x)].into_iter().flatten() {
+            let fill = self.compute_fill(i);
+            if self.fill[i] == fill {
+                continue;
+            }
+            self.fill[i] = fill;
+            let z = i / (self.x * self.y);
+            if z > 0 && z < self.z - 1 {
+                let p = Bounds::point([i % self.x, i / self.x % self.y, z]);
+                self.mark_changed((z - 1) / SLAB_PLANES, &p);
             }
         }
+    }
 
-        map
+    /// Boxes covering every cell whose type or fill level was changed by the
+    /// simulation since the last call, at most one per slab. Cells edited from
+    /// outside are not included, only fill levels changed by those edits.
+    pub fn take_changed_regions(&mut self) -> Vec<Bounds> {
+        self.changed.iter_mut().filter_map(|it| it.take()).collect()
     }
 
-    pub fn as_dto<'a>(&'a self) -> MapDTO<'a> {
-        let cells = unsafe {
-            std::slice::from_raw_parts(self.cells.as_ptr() as *const u8, self.cells.len())
+    /// Boxes covering every cell whose velocity was changed by steps since
+    /// the last call, at most one per slab.
+    pub fn take_velocity_regions(&mut self) -> Vec<Bounds> {
+        self.velocity_changed
+            .iter_mut()
+            .filter_map(|it| it.take())
+            .collect()
+    }
+
+    pub fn block_activity(&self) -> BlockActivity<'_> {
+        BlockActivity {
+            counts: self.block_counts,
+            active: &self.active,
+        }
+    }
+
+    /// Replaces sleeping state of all blocks, indexed as in `block_activity`,
+    /// so that a restored simulation continues exactly as the saved one.
+    pub fn set_block_activity(&mut self, active: &[bool]) -> Result<(), &'static str> {
+        if active.len() != self.active.len() {
+            return Err("block count does not match grid");
+        }
+        self.active.copy_from_slice(active);
+        self.touched.fill(true);
+        Ok(())
+    }
+
+    /// Block of a cell, padding cells belong to the nearest block.
+    fn block_index(&self, x: usize, y: usize, z: usize) -> usize {
+        self.block_slot(self.interior_coords([x, y, z]).map(|it| it / BLOCK_SIZE))
+    }
+
+    fn block_slot(&self, block: [usize; 3]) -> usize {
+        (block[2] * self.block_counts[1] + block[1]) * self.block_counts[0] + block[0]
+    }
+
+    /// Cell position without padding, padding cells are moved to the nearest interior cell.
+    fn interior_coords(&self, p: [usize; 3]) -> [usize; 3] {
+        let size = [self.x, self.y, self.z];
+        [0, 1, 2].map(|axis| {
+            p[axis]
+                .clamp(1, size[axis].saturating_sub(2).max(1))
+                .saturating_sub(1)
+        })
+    }
+
+    /// Block next to `block` along `axis`, across the map for periodic faces.
+    fn neighbour_block(
+        &self,
+        block: [usize; 3],
+        axis: usize,
+        positive: bool,
+    ) -> Option<[usize; 3]> {
+        let count = self.block_counts[axis];
+        let periodic = self.boundaries.get(Face::ALL[axis * 2]) == Boundary::Periodic;
+        let mut it = block;
+        it[axis] = if positive && block[axis] + 1 < count {
+            block[axis] + 1
+        } else if !positive && block[axis] > 0 {
+            block[axis] - 1
+        } else if periodic && count > 1 {
+            if positive {
+                0
+            } else {
+                count - 1
+            }
+        } else {
+            return None;
         };
-        MapDTO {
-            x: self.x,
+        Some(it)
+    }
+
+    fn wake_block(&mut self, block: [usize; 3]) {
+        let i = self.block_slot(block);
+        if i < self.active.len() {
+            self.active[i] = true;
+            self.touched[i] = true;
+        }
+    }
+
+    /// Makes the block of the cell simulate in the next step, together with
+    /// the neighbour blocks whose face the cell touches, so that fluid on both
+    /// sides of the face can flow towards the cell.
+    fn wake(&mut self, x: usize, y: usize, z: usize) {
+        let size = [self.x, self.y, self.z];
+        let p = self.interior_coords([x, y, z]);
+        let block = p.map(|it| it / BLOCK_SIZE);
+        self.wake_block(block);
+        for axis in 0..3 {
+            let last = p[axis] + 3 >= size[axis];
+            let sides = [
+                (false, p[axis].is_multiple_of(BLOCK_SIZE)),
+                (true, p[axis] % BLOCK_SIZE == BLOCK_SIZE - 1 || last),
+            ];
+            for (positive, touches) in sides {
+                if let Some(it) = self
+                    .neighbour_block(block, axis, positive)
+                    .filter(|_| touches)
+                {
+                    self.wake_block(it);
+                }
+            }
+        }
+    }
+
+    pub fn wake_all(&mut self) {
+        self.active.fill(true);
+        self.touched.fill(true);
+    }
+
+    /// Flow moves mass inside of the active blocks and into their neighbours.
+    fn touch_active(&mut self) {
+        let [cx, cy, _] = self.block_counts;
+        for i in 0..self.active.len() {
+            if !self.active[i] {
+                continue;
+            }
+            let block = [i % cx, i / cx % cy, i / (cx * cy)];
+            self.touched[i] = true;
+            for axis in 0..3 {
+                for positive in [false, true] {
+                    if let Some(it) = self.neighbour_block(block, axis, positive) {
+                        let j = self.block_slot(it);
+                        self.touched[j] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Boxes of blocks whose cells or mass could have changed since the last
+    /// call, by steps as well as by edits. Boxes of blocks at the faces of the
+    /// grid include the padding.
+    pub fn take_touched_regions(&mut self) -> Vec<Bounds> {
+        let size = [self.x, self.y, self.z];
+        let [cx, cy, _] = self.block_counts;
+        let mut regions = Vec::new();
+        for i in 0..self.touched.len() {
+            if !std::mem::take(&mut self.touched[i]) {
+                continue;
+            }
+            let block = [i % cx, i / cx % cy, i / (cx * cy)];
+            let min = [0, 1, 2].map(|axis| match block[axis] {
+                0 => 0,
+                it => it * BLOCK_SIZE + 1,
+            });
+            let max = [0, 1, 2].map(|axis| {
+                if block[axis] + 1 == self.block_counts[axis] {
+                    size[axis]
+                } else {
+                    (block[axis] + 1) * BLOCK_SIZE + 1
+                }
+            });
+            regions.push(Bounds { min, max });
+        }
+        regions
+    }
+
+    /// Blocks where mass moved in the last step and their face neighbours stay
+    /// active, flow out of them can reach the neighbours in the next step.
+    /// `mass` has to be the new state and `new_mass` the previous one.
+    fn update_activity(&mut self) {
+        let threshold = self.params.sleep_threshold;
+        if threshold <= 0.0 {
+            self.wake_all();
+            return;
+        }
+
+        let mut moving = vec![false; self.active.len()];
+        for z in 1..self.z - 1 {
+            for y in 1..self.y - 1 {
+                let row = self.index(0, y, z);
+                for x in 1..self.x - 1 {
+                    let i = row + x;
+                    let lava_moved = self.has_lava()
+                        && (self.lava_mass[i] - self.lava_new_mass[i]).abs() > threshold;
+                    if (self.mass[i] - self.new_mass[i]).abs() > threshold || lava_moved {
+                        moving[self.block_index(x, y, z)] = true;
+                    }
+                }
+            }
+        }
+
+        let [cx, cy, cz] = self.block_counts;
+        self.active.fill(false);
+        for bz in 0..cz {
+            for by in 0..cy {
+                for bx in 0..cx {
+                    if !moving[(bz * cy + by) * cx + bx] {
+                        continue;
+                    }
+                    let block = [bx, by, bz];
+                    let i = self.block_slot(block);
+                    self.active[i] = true;
+                    for axis in 0..3 {
+                        for positive in [false, true] {
+                            if let Some(it) = self.neighbour_block(block, axis, positive) {
+                                let j = self.block_slot(it);
+                                self.active[j] = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Fluid that flowed into the padding leaves the map. Returns removed water
+    /// mass, lava leaving the map is not part of the water balance.
+    fn clear_border(&mut self) -> f64 {
+        if self.has_lava() {
+            clear_padding(&mut self.lava_mass, self.x, self.y, self.z);
+        }
+        clear_padding(&mut self.mass, self.x, self.y, self.z)
+    }
+
+    /// Ranges of interior z planes that are processed as a whole by one thread.
+    fn slabs(&self) -> Vec<(usize, usize)> {
+        (1..self.z - 1)
+            .step_by(SLAB_PLANES)
+            .map(|z0| (z0, (z0 + SLAB_PLANES).min(self.z - 1)))
+            .collect()
+    }
+}
+
+/// Zeroes padding cells of a padded grid. Returns sum of their mass.
+fn clear_padding(mass: &mut [f32], x: usize, y: usize, z: usize) -> f64 {
+    let mut removed = 0.0;
+    let mut clear = |mass: &mut [f32]| {
+        for it in mass {
+            removed += *it as f64;
+            *it = 0.0;
+        }
+    };
+    let plane = x * y;
+    clear(&mut mass[..plane]);
+    clear(&mut mass[(z - 1) * plane..]);
+    for zi in 1..z - 1 {
+        let i = zi * plane;
+        clear(&mut mass[i..i + x]);
+        let i = zi * plane + (y - 1) * x;
+        clear(&mut mass[i..i + x]);
+        for yi in 1..y - 1 {
+            let i = zi * plane + yi * x;
+            clear(&mut mass[i..i + 1]);
+            clear(&mut mass[i + x - 1..i + x]);
+        }
+    }
+    removed
+}
+
+/// Moves mass that flowed into the padding of periodic faces to the interior
+/// cells at the opposite face.
+fn wrap_periodic(mass: &mut [f32], size: [usize; 3], boundaries: &Boundaries) {
+    let index = |p: [usize; 3]| (p[2] * size[1] + p[1]) * size[0] + p[0];
+    for face in Face::ALL {
+        if boundaries.get(face) != Boundary::Periodic {
+            continue;
+        }
+        let axis = face.axis();
+        let [a, b] = [(axis + 1) % 3, (axis + 2) % 3];
+        let (from, to) = if face.is_positive() {
+            (size[axis] - 1, 1)
+        } else {
+            (0, size[axis] - 2)
+        };
+        for u in 1..size[a] - 1 {
+            for v in 1..size[b] - 1 {
+                let mut p = [0; 3];
+                p[a] = u;
+                p[b] = v;
+                p[axis] = from;
+                let i = index(p);
+                p[axis] = to;
+                mass[index(p)] += std::mem::take(&mut mass[i]);
+            }
+        }
+    }
+}
+
+/// Quantized level of fluid cell for rendering. Cells with fluid above them
+/// are drawn full, so that falling streams stay connected.
+fn fill_level(mass: f32, mass_above: f32, params: &WaterSimParams) -> u8 {
+    if mass_above > params.min_mass {
+        return u8::MAX;
+    }
+    ((mass / params.max_mass).clamp(0.0, 1.0) * 255.0)
+        .round()
+        .max(1.0) as u8
+}
+
+/// Moves mass of `flow` into `new_mass`, filling `velocity` if given. Returns
+/// box of cells with velocity written per slab.
+fn flow_pass(
+    threads: usize,
+    slabs: &[(usize, usize)],
+    flow: &FlowContext,
+    new_mass: &mut [f32],
+    mut velocity: Option<&mut [[f32; 3]]>,
+) -> Vec<Option<Bounds>> {
+    // slab writes spill one plane into its neighbours, so slabs of the same parity
+    // never touch the same plane. Slabs do not depend on thread count and are
+    // always applied in the same order, so result does not depend on it either.
+    let plane = flow.x * flow.y;
+    let mut written = vec![None; slabs.len()];
+    for parity in 0..2 {
+        let mut jobs = Vec::new();
+        let mut rest: &mut [f32] = new_mass;
+        let mut offset = 0;
+        // velocity is written only for the slab itself
+        let mut velocity_rest = velocity.as_deref_mut();
+        let mut velocity_offset = 0;
+        for &(z0, z1) in slabs.iter().skip(parity).step_by(2) {
+            let start = (z0 - 1) * plane;
+            let end = (z1 + 1) * plane;
+            let (_, tail) = std::mem::take(&mut rest).split_at_mut(start - offset);
+            let (window, tail) = tail.split_at_mut(end - start);
+            rest = tail;
+            offset = end;
+            let velocity = velocity_rest.take().map(|it| {
+                let (_, tail) = it.split_at_mut(z0 * plane - velocity_offset);
+                let (velocity, tail) = tail.split_at_mut((z1 - z0) * plane);
+                velocity_rest = Some(tail);
+                velocity
+            });
+            velocity_offset = z1 * plane;
+            jobs.push(move || flow.flow_slab(z0, z1, window, start, velocity));
+        }
+        let results = run_jobs(threads, jobs);
+        for (slab, it) in (parity..slabs.len()).step_by(2).zip(results) {
+            written[slab] = it;
+        }
+    }
+    written
+}
+
+/// Runs jobs split between up to `threads` threads, in order on the calling thread if it is 1.
+fn run_jobs<T: Send>(threads: usize, jobs: Vec<impl FnOnce() -> T + Send>) -> Vec<T> {
+    if threads <= 1 || jobs.len() <= 1 {
+        return jobs.into_iter().map(|it| it()).collect();
+    }
+
+    let per_thread = jobs.len().div_ceil(threads);
+    let mut jobs = jobs.into_iter();
+    std::thread::scope(|scope| {
+        let mut handles = Vec::new();
+        loop {
+            let group = jobs.by_ref().take(per_thread).collect::<Vec<_>>();
+            if group.is_empty() {
+                break;
+            }
+            handles.push(scope.spawn(move || group.into_iter().map(|it| it()).collect::<Vec<_>>()));
+        }
+        handles
+            .into_iter()
+            .flat_map(|it| it.join().unwrap())
+            .collect()
+    })
+}
+
+/// Read only part of simulation state needed to compute flows.
+struct FlowContext<'a> {
+    x: usize,
+    y: usize,
+    z: usize,
+    boundaries: [Boundary; 6],
+    cells: &'a [Cell],
+    mass: &'a [f32],
+    registry: &'a CellRegistry,
+    params: &'a WaterSimParams,
+    block_counts: [usize; 3],
+    active: &'a [bool],
+}
+
+impl<'a> FlowContext<'a> {
+    fn index(&self, x: usize, y: usize, z: usize) -> usize {
+        z * (self.x * self.y) + y * self.x + x
+    }
+
+    /// Boundary of a padding cell together with the interior cell it wraps
+    /// to if the boundary is periodic, None for interior cells.
+    fn boundary_at(&self, p: [usize; 3]) -> Option<([usize; 3], Boundary)> {
+        let size = [self.x, self.y, self.z];
+        for axis in 0..3 {
+            let positive = p[axis] == size[axis] - 1;
+            if p[axis] == 0 || positive {
+                let mut wrapped = p;
+                wrapped[axis] = if positive { 1 } else { size[axis] - 2 };
+                return Some((wrapped, self.boundaries[axis * 2 + positive as usize]));
+            }
+        }
+        None
+    }
+
+    fn is_solid(&self, x: usize, y: usize, z: usize) -> bool {
+        match self.boundary_at([x, y, z]) {
+            None => self.registry.is_solid(self.cells[self.index(x, y, z)]),
+            Some(([x, y, z], Boundary::Periodic)) => {
+                self.registry.is_solid(self.cells[self.index(x, y, z)])
+            }
+            Some((_, boundary)) => boundary == Boundary::Closed,
+        }
+    }
+
+    /// Only for interior cells.
+    fn is_active(&self, x: usize, y: usize, z: usize) -> bool {
+        let [bx, by, bz] = [x, y, z].map(|it| (it - 1) / BLOCK_SIZE);
+        self.active[(bz * self.block_counts[1] + by) * self.block_counts[0] + bx]
+    }
+
+    fn mass(&self, x: usize, y: usize, z: usize) -> f32 {
+        match self.boundary_at([x, y, z]) {
+            None => self.mass[self.index(x, y, z)],
+            Some(([x, y, z], Boundary::Periodic)) => self.mass[self.index(x, y, z)],
+            Some((_, Boundary::Ocean { level })) if y < level => self.params.max_mass,
+            Some(_) => 0.0,
+        }
+    }
+
+    fn get_stable_state_b(&self, mass: f32) -> f32 {
+        if mass <= self.params.max_mass {
+            return self.params.max_mass;
+        }
+
+        if mass < 2.0 * self.params.max_mass + self.params.max_compress {
+            return (self.params.max_mass * self.params.max_mass + mass * self.params.max_compress)
+                / (self.params.max_mass + self.params.max_compress);
+        }
+
+        (mass + self.params.max_compress) * 0.5
+    }
+
+    /// Moves mass out of cells of planes `z0..z1`. `new_mass` starts at linear index `base`
+    /// and has to cover one more plane on both sides, `velocity` covers exactly the planes.
+    /// Returns box of cells with velocity written.
+    fn flow_slab(
+        &self,
+        z0: usize,
+        z1: usize,
+        new_mass: &mut [f32],
+        base: usize,
+        mut velocity: Option<&mut [[f32; 3]]>,
+    ) -> Option<Bounds> {
+        if let Some(velocity) = velocity.as_deref_mut() {
+            velocity.fill([0.0; 3]);
+        }
+        let velocity_base = z0 * self.x * self.y;
+        let mut written: Option<Bounds> = None;
+        for z in z0..z1 {
+            for y in 1..self.y - 1 {
+                for x in 1..self.x - 1 {
+                    if !self.is_active(x, y, z) {
+                        continue;
+                    }
+                    let i = self.index(x, y, z);
+                    let mut outflow = [0.0; 3];
+                    self.flow_cell(x, y, z, |target, flow| {
+                        new_mass[i - base] -= flow;
+                        new_mass[self.index(target[0], target[1], target[2]) - base] += flow;
+                        for (axis, it) in outflow.iter_mut().enumerate() {
+                            *it += (target[axis] as f32 - [x, y, z][axis] as f32) * flow;
+                        }
+                    });
+                    let mass = self.mass[i];
+                    if let Some(velocity) = velocity.as_deref_mut().filter(|_| mass > 0.0) {
+                        velocity[i - velocity_base] = outflow.map(|it| it / mass);
+                        let p = Bounds::point([x, y, z]);
+                        written = Some(written.map_or(p, |it| it.union(&p)));
+                    }
+                }
+            }
+        }
+        written
+    }
+
+    /// Calls `apply` with target cell and amount for each flow out of the cell.
+    fn flow_cell(&self, x: usize, y: usize, z: usize, mut apply: impl FnMut([usize; 3], f32)) {
+        // the cell itself is always interior
+        let i = self.index(x, y, z);
+        if self.registry.is_solid(self.cells[i]) {
+            return;
+        }
+
+        let mass = self.mass[i];
+        let mut remaining_mass = mass;
+        if remaining_mass <= 0.0 {
+            return;
+        }
+
+        if !self.is_solid(x, y - 1, z) {
+            let mut flow = self.get_stable_state_b(remaining_mass + self.mass(x, y - 1, z))
+                - self.mass(x, y - 1, z);
+            if flow > self.params.min_flow {
+                flow *= 0.5;
+            }
+            let flow = flow.clamp(0.0, self.params.max_speed.min(remaining_mass));
+
+            apply([x, y - 1, z], flow);
+            remaining_mass -= flow;
+        }
+
+        // left, right, up and down on the horizontal plane. Fluid flows into the
+        // padding of faces that are not closed.
+        for (nx, nz) in [(x - 1, z), (x + 1, z), (x, z - 1), (x, z + 1)] {
+            if remaining_mass <= 0.0 {
+                return;
+            }
+            if self.is_solid(nx, y, nz) {
+                continue;
+            }
+
+            let mut flow = (mass - self.mass(nx, y, nz)) / self.params.spread;
+            if flow > self.params.min_flow {
+                flow *= 0.5;
+            }
+            flow = flow.clamp(0.0, remaining_mass);
+
+            apply([nx, y, nz], flow);
+            remaining_mass -= flow;
+        }
+
+        if remaining_mass <= 0.0 {
+            return;
+        }
+
+        // vertical up
+        if !self.is_solid(x, y + 1, z) {
+            let mut flow =
+                remaining_mass - self.get_stable_state_b(remaining_mass + self.mass(x, y + 1, z));
+            if flow > self.params.min_flow {
+                flow *= 0.5;
+            }
+            let flow = flow.clamp(0.0, self.params.max_speed.min(remaining_mass));
+
+            apply([x, y + 1, z], flow);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Map {
+    x: usize,
+    y: usize,
+    z: usize,
+    cells: Vec<Cell>,
+}
+
+impl Map {
+    pub fn x(&self) -> usize {
+        self.x
+    }
+    pub fn y(&self) -> usize {
+        self.y
+    }
+    pub fn z(&self) -> usize {
+        self.z
+    }
+
+    pub fn at(&self, x: usize, y: usize, z: usize) -> Cell {
+        self.cells[z * (self.x * self.y) + y * self.x + x]
+    }
+
+    pub fn at_mut(&mut self, x: usize, y: usize, z: usize) -> &mut Cell {
+        &mut self.cells[z * (self.x * self.y) + y * self.x + x]
+    }
+
+    pub fn empty(x: usize, y: usize, z: usize) -> Self {
+        let cells = vec![Cell::NONE; x * y * z];
+        Self { x, y, z, cells }
+    }
+
+    pub fn cube(x: usize, y: usize, z: usize) -> Self {
+        let cells = (0..x * y * z).map(|_| Cell::GRASS).collect();
+        Self { x, y, z, cells }
+    }
+
+    pub fn random(
+        x: usize,
+        y: usize,
+        z: usize,
+        registry: &CellRegistry,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let types = registry
+            .iter()
+            .filter(|it| it.solid || it.fluid)
+            .map(|it| it.id)
+            .collect::<Vec<_>>();
+        let cells = (0..x * y * z)
+            .map(|_| types[rng.gen::<usize>() % types.len()])
+            .collect();
+        Self { x, y, z, cells }
+    }
+
+    pub fn as_dto<'a>(&'a self) -> MapDTO<'a> {
+        let cells = unsafe {
+            std::slice::from_raw_parts(self.cells.as_ptr() as *const u8, self.cells.len())
+        };
+        MapDTO {
+            x: self.x,
             y: self.y,
             z: self.z,
             cells,
+            fill: None,
+        }
+    }
+}
+
+fn default_threads() -> usize {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            1
+        } else {
+            std::thread::available_parallelism().map_or(1, |it| it.get())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boundary::{Boundaries, Boundary, Face};
+    use crate::terrain::{PerlinHills, TerrainGenerator, TerrainParams};
+    use crate::weather::{RainDistribution, Weather};
+    use crate::xorshift32::Xorshift32;
+
+    /// Map with stone floor and side walls, open at the top.
+    fn basin(x: usize, y: usize, z: usize) -> Map {
+        let mut map = Map::empty(x, y, z);
+        for xi in 0..x {
+            for yi in 0..y {
+                for zi in 0..z {
+                    if xi == 0 || zi == 0 || yi == 0 || xi == x - 1 || zi == z - 1 {
+                        *map.at_mut(xi, yi, zi) = Cell::STONE;
+                    }
+                }
+            }
+        }
+        map
+    }
+
+    fn fill(map: &mut Map, min: [usize; 3], max: [usize; 3], cell: Cell) {
+        for xi in min[0]..max[0] {
+            for yi in min[1]..max[1] {
+                for zi in min[2]..max[2] {
+                    *map.at_mut(xi, yi, zi) = cell;
+                }
+            }
+        }
+    }
+
+    /// Sim without ocean faces, so that nothing creates water.
+    fn closed_sim(map: Map) -> WaterSim {
+        let mut sim = WaterSim::new(map, &CellRegistry::default());
+        sim.set_boundaries(Boundaries::default()).unwrap();
+        sim
+    }
+
+    #[test]
+    fn closed_basin_conserves_mass() {
+        let mut map = basin(10, 8, 10);
+        fill(&mut map, [1, 1, 1], [4, 7, 9], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let initial = sim.total_mass();
+
+        for _ in 0..500 {
+            sim.simulate();
+            let stats = sim.stats();
+            assert_eq!(stats.created, 0.0);
+            assert_eq!(stats.removed, 0.0);
+            assert!(stats.drift.abs() < 1e-3, "drift {}", stats.drift);
+        }
+        let total = sim.total_mass();
+        assert!((total - initial).abs() < 1e-2, "{initial} -> {total}");
+    }
+
+    #[test]
+    fn closed_faces_conserve_mass() {
+        // no walls, the faces of the grid hold the water
+        let mut map = Map::empty(10, 8, 10);
+        fill(&mut map, [2, 3, 2], [7, 7, 7], Cell::WATER);
+        let mut sim = WaterSim::new(map, &CellRegistry::default());
+        sim.set_boundaries(Boundaries([Boundary::Closed; 6]))
+            .unwrap();
+        let initial = sim.total_mass();
+
+        for _ in 0..500 {
+            sim.simulate();
+            let stats = sim.stats();
+            assert_eq!(stats.created, 0.0);
+            assert_eq!(stats.removed, 0.0);
+            assert!(stats.drift.abs() < 1e-3, "drift {}", stats.drift);
+        }
+        let total = sim.total_mass();
+        assert!((total - initial).abs() < 1e-2, "{initial} -> {total}");
+        // water spread over the whole floor
+        assert!(sim.mass()[sim.index(1, 1, 1)] > 0.0);
+        assert!(sim.mass()[sim.index(10, 1, 10)] > 0.0);
+    }
+
+    #[test]
+    fn ocean_level_must_be_inside_of_map() {
+        let mut sim = closed_sim(basin(6, 6, 6));
+        let mut boundaries = Boundaries::default();
+        boundaries.set(Face::PosX, Boundary::Ocean { level: sim.y });
+        assert!(sim.set_boundaries(boundaries).is_err());
+        boundaries.set(Face::PosX, Boundary::Ocean { level: sim.y - 1 });
+        assert!(sim.set_boundaries(boundaries).is_ok());
+    }
+
+    #[test]
+    fn invalid_params_are_rejected_and_previous_ones_kept() {
+        let mut sim = closed_sim(basin(6, 6, 6));
+        let valid = WaterSimParams {
+            spread: 6.0,
+            ..WaterSimParams::default()
+        };
+        sim.set_params(valid).unwrap();
+        let invalid = [
+            WaterSimParams {
+                max_mass: 0.0,
+                ..valid
+            },
+            WaterSimParams {
+                max_mass: -1.0,
+                ..valid
+            },
+            WaterSimParams {
+                min_mass: valid.max_mass,
+                ..valid
+            },
+            WaterSimParams {
+                spread: 1.0,
+                ..valid
+            },
+            WaterSimParams {
+                spread: 0.5,
+                ..valid
+            },
+            WaterSimParams {
+                min_flow: f32::NAN,
+                ..valid
+            },
+        ];
+        for params in invalid {
+            assert!(sim.set_params(params).is_err(), "{:?}", params);
+            assert!(sim.set_lava_params(params).is_err(), "{:?}", params);
+            assert_eq!(*sim.params(), valid);
+            assert_eq!(*sim.lava_params(), WaterSimParams::lava());
+        }
+    }
+
+    #[test]
+    fn map_water_is_kept_by_ocean_faces() {
+        let mut map = basin(8, 8, 8);
+        fill(&mut map, [1, 1, 1], [7, 4, 7], Cell::WATER);
+        let sim = WaterSim::new(map, &CellRegistry::default());
+        // water reaches padded y = 4, cells below 5 are kept full
+        let ocean = Boundary::Ocean { level: 5 };
+        assert_eq!(sim.boundaries().get(Face::PosX), ocean);
+        assert_eq!(sim.boundaries().get(Face::PosZ), ocean);
+        assert_eq!(sim.boundaries().get(Face::NegX), Boundary::Closed);
+
+        let dry = WaterSim::new(basin(8, 8, 8), &CellRegistry::default());
+        assert_eq!(*dry.boundaries(), Boundaries::default());
+    }
+
+    #[test]
+    fn communicating_vessels_reach_equal_levels() {
+        // two chambers joined by a channel at the bottom
+        let mut map = basin(13, 10, 3);
+        fill(&mut map, [6, 2, 1], [7, 10, 2], Cell::STONE);
+        fill(&mut map, [1, 1, 1], [6, 9, 2], Cell::WATER);
+        let mut sim = closed_sim(map);
+
+        for _ in 0..20000 {
+            sim.simulate();
+        }
+        // padded coordinates, wall is at x = 7
+        let level = |columns: std::ops::Range<usize>| {
+            let count = columns.len() as f32;
+            let mut total = 0.0;
+            for x in columns {
+                for y in 1..sim.y() - 1 {
+                    total += sim.mass()[sim.index(x, y, 2)];
+                }
+            }
+            total / count
+        };
+        let left = level(2..7);
+        let right = level(8..13);
+        assert!(right > 1.0, "water did not pass the channel");
+        assert!((left - right).abs() < 0.05, "levels {left} and {right}");
+    }
+
+    #[test]
+    fn water_never_enters_solids() {
+        let registry = CellRegistry::default();
+        let mut rng = Xorshift32::new(7);
+        let map = Map::random(12, 12, 12, &registry, &mut rng);
+        let mut sim = WaterSim::new(map, &registry);
+        let solids = sim
+            .cells()
+            .iter()
+            .map(|it| registry.is_solid(*it))
+            .collect::<Vec<_>>();
+
+        for _ in 0..100 {
+            sim.simulate();
+            for (i, solid) in solids.iter().enumerate() {
+                let cell = sim.cells()[i];
+                // only lava meeting water makes new solids
+                if !*solid && registry.is_solid(cell) {
+                    assert_eq!(cell, Cell::STONE, "cell {i}");
+                } else {
+                    assert_eq!(registry.is_solid(cell), *solid, "cell {i}");
+                }
+                if registry.is_solid(cell) {
+                    assert_eq!(sim.mass()[i], 0.0, "mass in solid cell {i}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mass_balance_adds_up() {
+        // no walls, so ocean faces refill water that flows out through the border
+        let mut map = Map::empty(8, 6, 8);
+        fill(&mut map, [0, 0, 0], [8, 2, 8], Cell::WATER);
+        let mut sim = WaterSim::new(map, &CellRegistry::default());
+
+        let mut created = 0.0;
+        let mut removed = 0.0;
+        for _ in 0..50 {
+            let before = sim.total_mass();
+            sim.simulate();
+            let stats = *sim.stats();
+            created += stats.created;
+            removed += stats.removed;
+            let expected = before + stats.created - stats.removed;
+            assert!((stats.total - expected).abs() < 1e-3);
+            assert!(stats.drift.abs() < 1e-3);
+        }
+        assert!(created > 0.0);
+        assert!(removed > 0.0);
+    }
+
+    #[test]
+    fn changed_regions_cover_changed_cells() {
+        let mut map = basin(12, 8, 12);
+        fill(&mut map, [1, 3, 1], [4, 7, 11], Cell::WATER);
+        let mut sim = closed_sim(map);
+        assert!(sim.take_changed_regions().is_empty());
+
+        let mut saw_change = false;
+        for _ in 0..30 {
+            let before = sim.cells().to_vec();
+            sim.simulate();
+            let regions = sim.take_changed_regions();
+            assert!(sim.take_changed_regions().is_empty());
+            for z in 0..sim.z() {
+                for y in 0..sim.y() {
+                    for x in 0..sim.x() {
+                        let i = sim.index(x, y, z);
+                        if before[i] != sim.cells()[i] {
+                            saw_change = true;
+                            assert!(regions.iter().any(|it| it.contains([x, y, z])));
+                        }
+                    }
+                }
+            }
+        }
+        assert!(saw_change);
+    }
+
+    #[test]
+    fn settled_water_sleeps_until_woken() {
+        let mut map = basin(20, 8, 20);
+        fill(&mut map, [1, 1, 1], [19, 4, 19], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let active = |sim: &WaterSim| sim.block_activity().active.iter().filter(|it| **it).count();
+
+        for _ in 0..2000 {
+            sim.simulate();
+            if active(&sim) == 0 {
+                break;
+            }
+        }
+        assert_eq!(active(&sim), 0);
+        let total = sim.total_mass();
+        sim.simulate();
+        assert_eq!(sim.total_mass(), total);
+
+        let p = [10, 6, 10];
+        sim.inject(p[0], p[1], p[2], 1.0);
+        assert_eq!(active(&sim), 1);
+        assert!(sim.block_activity().active[sim.block_index(p[0], p[1], p[2])]);
+        sim.simulate();
+        assert!(active(&sim) > 1);
+    }
+
+    #[test]
+    fn edit_at_block_face_wakes_neighbour_block() {
+        // wall at padded x = 9, the first cell of the second block
+        let mut map = basin(18, 8, 10);
+        fill(&mut map, [8, 1, 1], [9, 8, 9], Cell::STONE);
+        fill(&mut map, [1, 1, 1], [8, 4, 9], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let active = |sim: &WaterSim| sim.block_activity().active.iter().filter(|it| **it).count();
+        for _ in 0..2000 {
+            sim.simulate();
+            if active(&sim) == 0 {
+                break;
+            }
+        }
+        assert_eq!(active(&sim), 0);
+
+        for y in 2..4 {
+            sim.set_cell(9, y, 5, Cell::NONE);
+        }
+        for _ in 0..200 {
+            sim.simulate();
+        }
+        assert!(sim.mass()[sim.index(9, 2, 5)] > 0.5, "hole stayed dry");
+        let right = (10..18)
+            .map(|x| sim.mass()[sim.index(x, 2, 5)])
+            .sum::<f32>();
+        assert!(right > 0.0, "water did not pass the hole");
+    }
+
+    #[test]
+    fn fill_follows_mass() {
+        let mut sim = closed_sim(basin(6, 6, 6));
+        let fill = |sim: &WaterSim, i: usize| sim.as_dto().fill.unwrap()[i];
+        let i = sim.index(3, 3, 3);
+        let above = sim.index(3, 4, 3);
+        assert_eq!(fill(&sim, i), u8::MAX);
+
+        sim.restore(
+            i,
+            &CellState {
+                cell: Cell::WATER,
+                water: 0.5,
+                ..CellState::default()
+            },
+        );
+        assert_eq!(fill(&sim, i), 128);
+        sim.restore(
+            above,
+            &CellState {
+                cell: Cell::WATER,
+                water: 0.001,
+                ..CellState::default()
+            },
+        );
+        assert_eq!(fill(&sim, i), u8::MAX);
+        assert_eq!(fill(&sim, above), 1);
+        let regions = sim.take_changed_regions();
+        assert!(regions.iter().any(|it| it.contains([3, 3, 3])));
+    }
+
+    #[test]
+    fn falling_water_moves_down() {
+        let mut map = basin(7, 8, 7);
+        fill(&mut map, [3, 5, 3], [4, 6, 4], Cell::WATER);
+        let mut sim = closed_sim(map);
+        sim.simulate();
+        let v = sim.velocity()[sim.index(4, 6, 4)];
+        assert!(v[1] < 0.0, "{v:?}");
+        assert!(v[0].abs() < 1e-6 && v[2].abs() < 1e-6, "{v:?}");
+        let still = sim.velocity()[sim.index(2, 2, 2)];
+        assert_eq!(still, [0.0; 3]);
+    }
+
+    #[test]
+    fn velocity_regions_cover_every_change() {
+        let mut map = basin(20, 8, 20);
+        fill(&mut map, [2, 3, 2], [6, 7, 6], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let mut previous = sim.velocity().to_vec();
+        for _ in 0..300 {
+            sim.simulate();
+            let regions = sim.take_velocity_regions();
+            for (i, (a, b)) in previous.iter().zip(sim.velocity()).enumerate() {
+                if a != b {
+                    let p = [i % sim.x, i / sim.x % sim.y, i / (sim.x * sim.y)];
+                    assert!(regions.iter().any(|it| it.contains(p)), "{p:?}");
+                }
+            }
+            previous.copy_from_slice(sim.velocity());
+        }
+        // once every block sleeps the last written velocity is reset once, then nothing changes
+        for _ in 0..2000 {
+            if !sim.block_activity().active.contains(&true) {
+                break;
+            }
+            sim.simulate();
+        }
+        sim.simulate();
+        sim.take_velocity_regions();
+        sim.simulate();
+        assert!(sim.take_velocity_regions().is_empty());
+    }
+
+    #[test]
+    fn lava_and_water_turn_into_stone() {
+        let mut map = basin(14, 6, 6);
+        fill(&mut map, [1, 1, 1], [5, 4, 5], Cell::WATER);
+        fill(&mut map, [9, 1, 1], [13, 4, 5], Cell::LAVA);
+        let mut sim = closed_sim(map);
+        let stone_before = sim.cells().iter().filter(|it| **it == Cell::STONE).count();
+
+        for _ in 0..300 {
+            sim.simulate();
+            assert!(sim.stats().drift.abs() < 1e-3);
+        }
+        let stone = sim.cells().iter().filter(|it| **it == Cell::STONE).count();
+        assert!(stone > stone_before);
+        assert!(sim.total_lava_mass() > 0.0);
+        for (i, cell) in sim.cells().iter().enumerate() {
+            if *cell != Cell::LAVA || !sim.is_interior(i) {
+                continue;
+            }
+            for j in sim.face_neighbours(i) {
+                assert_ne!(sim.cells()[j], Cell::WATER);
+            }
+        }
+    }
+
+    #[test]
+    fn lava_turns_into_stone_of_registry() {
+        // the built-in stone id is renamed, so "stone" is a custom type here
+        let mut registry = CellRegistry::empty();
+        for it in CellRegistry::default().iter() {
+            let name = if it.id == Cell::STONE {
+                "granite"
+            } else {
+                &it.name
+            };
+            registry.register(name, it.solid, it.fluid, it.material);
+        }
+        let material = registry.get(Cell::STONE).unwrap().material;
+        let stone = registry.register("stone", true, false, material).unwrap();
+
+        let mut map = basin(14, 6, 6);
+        fill(&mut map, [1, 1, 1], [5, 4, 5], Cell::WATER);
+        fill(&mut map, [9, 1, 1], [13, 4, 5], Cell::LAVA);
+        let mut sim = WaterSim::new(map, &registry);
+        sim.set_boundaries(Boundaries::default()).unwrap();
+        let granite = sim.cells().iter().filter(|it| **it == Cell::STONE).count();
+        for _ in 0..300 {
+            sim.simulate();
+        }
+        assert!(sim.cells().contains(&stone));
+        let after = sim.cells().iter().filter(|it| **it == Cell::STONE).count();
+        assert_eq!(after, granite);
+    }
+
+    #[test]
+    fn lava_spreads_slower_than_water() {
+        let spread = |cell: Cell| {
+            let mut map = basin(24, 8, 3);
+            fill(&mut map, [1, 1, 1], [3, 7, 2], cell);
+            let mut sim = closed_sim(map);
+            for _ in 0..40 {
+                sim.simulate();
+            }
+            sim.cells().iter().filter(|it| **it == cell).count()
+        };
+        assert!(spread(Cell::LAVA) < spread(Cell::WATER));
+    }
+
+    #[test]
+    fn fast_water_erodes_ground_and_deposits_sand() {
+        let mut map = basin(24, 10, 4);
+        fill(&mut map, [1, 1, 1], [23, 3, 3], Cell::GROUND);
+        fill(&mut map, [1, 3, 1], [5, 10, 3], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let erosion = ErosionParams {
+            enabled: true,
+            erosion_rate: 0.5,
+            ..ErosionParams::default()
+        };
+        sim.set_erosion(erosion).unwrap();
+        let count =
+            |sim: &WaterSim, cell: Cell| sim.cells().iter().filter(|it| **it == cell).count();
+        let ground = count(&sim, Cell::GROUND);
+
+        for _ in 0..1000 {
+            sim.simulate();
+            assert!(sim.stats().drift.abs() < 1e-3);
+        }
+        let stats = *sim.erosion_stats();
+        assert!(stats.eroded > 0);
+        assert!(stats.deposited > 0);
+        assert_eq!(ground - count(&sim, Cell::GROUND), stats.eroded);
+        assert_eq!(count(&sim, Cell::SAND), stats.deposited);
+    }
+
+    #[test]
+    fn periodic_faces_wrap_water_around() {
+        // no walls, water only leaves through the faces
+        let mut map = Map::empty(12, 6, 4);
+        fill(&mut map, [0, 0, 0], [12, 1, 4], Cell::STONE);
+        // full height wall, so that the low x side can only be reached around the map
+        fill(&mut map, [5, 1, 0], [6, 6, 4], Cell::STONE);
+        fill(&mut map, [9, 1, 0], [12, 6, 4], Cell::WATER);
+        let mut sim = closed_sim(map);
+        let mut boundaries = Boundaries([Boundary::Periodic; 6]);
+        boundaries.set(Face::PosY, Boundary::Closed);
+        assert!(sim.set_boundaries(boundaries).is_err());
+        boundaries.set(Face::NegY, Boundary::Closed);
+        sim.set_boundaries(boundaries).unwrap();
+        let total = sim.total_mass();
+
+        for _ in 0..200 {
+            sim.simulate();
+            assert_eq!(sim.stats().removed, 0.0);
+        }
+        assert!((sim.total_mass() - total).abs() < 1e-3);
+        assert_eq!(sim.at(1, 2, 2), Cell::WATER);
+    }
+
+    #[test]
+    fn ocean_face_fills_map_up_to_its_level() {
+        let mut map = Map::empty(16, 8, 4);
+        fill(&mut map, [0, 0, 0], [16, 1, 4], Cell::STONE);
+        let mut sim = closed_sim(map);
+        let mut boundaries = Boundaries([Boundary::Closed; 6]);
+        boundaries.set(Face::NegX, Boundary::Ocean { level: 4 });
+        sim.set_boundaries(boundaries).unwrap();
+
+        for _ in 0..1000 {
+            sim.simulate();
+        }
+        // padded coordinates, the floor is at y = 1
+        for x in 1..17 {
+            assert_eq!(sim.at(x, 3, 2), Cell::WATER, "x {x}");
+            assert_eq!(sim.at(x, 4, 2), Cell::NONE, "x {x}");
+        }
+    }
+
+    /// Terrain and sim seeded the same way as a new session.
+    fn seeded_sim(seed: u32) -> WaterSim {
+        let params = TerrainParams {
+            seed,
+            size: [16, 12, 16],
+            ..TerrainParams::default()
+        };
+        let mut sim = WaterSim::new(PerlinHills.generate(&params), &CellRegistry::default());
+        sim.reseed(seed);
+        sim
+    }
+
+    /// One tick of the app: rain from the generator of the sim, then a step.
+    fn rainy_tick(sim: &mut WaterSim) {
+        let weather = Weather {
+            enabled: true,
+            rain_rate: 0.05,
+            distribution: RainDistribution::Scattered { coverage: 0.2 },
+            ..Weather::default()
+        };
+        let mut rng = *sim.rng();
+        weather.apply(sim, &mut rng);
+        sim.set_rng(rng);
+        sim.simulate();
+    }
+
+    fn mass_bits(sim: &WaterSim) -> Vec<u32> {
+        sim.mass().iter().map(|it| it.to_bits()).collect()
+    }
+
+    #[test]
+    fn clones_with_same_seed_stay_identical() {
+        let mut a = seeded_sim(5);
+        let mut b = a.clone();
+        assert_eq!(a.cells(), seeded_sim(5).cells());
+
+        for _ in 0..100 {
+            rainy_tick(&mut a);
+            rainy_tick(&mut b);
+        }
+        assert_eq!(a.cells(), b.cells());
+        assert_eq!(mass_bits(&a), mass_bits(&b));
+        assert_eq!(a.rng().state(), b.rng().state());
+    }
+
+    #[test]
+    fn different_seed_diverges() {
+        assert_ne!(seeded_sim(5).cells(), seeded_sim(6).cells());
+
+        // same terrain, only the rain differs
+        let mut a = seeded_sim(5);
+        let mut b = a.clone();
+        b.reseed(6);
+        for _ in 0..100 {
+            rainy_tick(&mut a);
+            rainy_tick(&mut b);
+        }
+        assert_ne!(mass_bits(&a), mass_bits(&b));
+    }
+
+    #[test]
+    fn thread_count_does_not_change_results() {
+        let params = TerrainParams {
+            seed: 3,
+            size: [16, 12, 16],
+            ..TerrainParams::default()
+        };
+        let mut map = PerlinHills.generate(&params);
+        fill(&mut map, [4, 9, 4], [8, 11, 8], Cell::LAVA);
+        let mut single = WaterSim::new(map, &CellRegistry::default());
+        single.reseed(3);
+        single
+            .set_erosion(ErosionParams {
+                enabled: true,
+                ..ErosionParams::default()
+            })
+            .unwrap();
+        single.set_threads(1);
+        let mut multi = single.clone();
+        multi.set_threads(4);
+
+        for _ in 0..200 {
+            rainy_tick(&mut single);
+            rainy_tick(&mut multi);
         }
+        assert_eq!(single.cells(), multi.cells());
+        assert_eq!(mass_bits(&single), mass_bits(&multi));
     }
 }
@@ -14,6 +14,11 @@ pub enum Material {
         albedo: Vector3,
         refractive_index: f32,
     },
+    /// Glows with `albedo` scaled by `intensity`, light paths end on it
+    Emissive {
+        albedo: Vector3,
+        intensity: f32,
+    },
 }
 
 impl Material {
@@ -29,12 +34,25 @@ impl Material {
             refractive_index,
         }
     }
+    pub fn emissive(albedo: Vector3, intensity: f32) -> Self {
+        Self::Emissive { albedo, intensity }
+    }
+
+    pub fn albedo(&self) -> Vector3 {
+        match self {
+            Self::Diffuse { albedo } => *albedo,
+            Self::Metal { albedo, .. } => *albedo,
+            Self::Dielectric { albedo, .. } => *albedo,
+            Self::Emissive { albedo, .. } => *albedo,
+        }
+    }
 
     fn kind(&self) -> i32 {
         match self {
             Self::Diffuse { .. } => 0,
             Self::Metal { .. } => 1,
             Self::Dielectric { .. } => 2,
+            Self::Emissive { .. } => 3,
         }
     }
 
@@ -45,7 +63,7 @@ impl Material {
                 fuzz: 0.0,
                 refractive_index: 0.0,
                 kind: self.kind(),
-                pad0: 0.0,
+                emission: 0.0,
                 pad1: 0.0,
             },
             Material::Metal { albedo, fuzz } => MaterialDTO {
@@ -53,7 +71,7 @@ impl Material {
                 fuzz: *fuzz,
                 refractive_index: 0.0,
                 kind: self.kind(),
-                pad0: 0.0,
+                emission: 0.0,
                 pad1: 0.0,
             },
             Material::Dielectric {
@@ -64,7 +82,15 @@ impl Material {
                 fuzz: 0.0,
                 refractive_index: *refractive_index,
                 kind: self.kind(),
-                pad0: 0.0,
+                emission: 0.0,
+                pad1: 0.0,
+            },
+            Material::Emissive { albedo, intensity } => MaterialDTO {
+                albedo: *albedo,
+                fuzz: 0.0,
+                refractive_index: 0.0,
+                kind: self.kind(),
+                emission: *intensity,
                 pad1: 0.0,
             },
         }
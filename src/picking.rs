@@ -0,0 +1,135 @@
+use crate::brush::VoxelGrid;
+use crate::cells::Cell;
+use crate::math::*;
+
+/// Must match `VOXEL_SIZE` in ray_tracing.wgsl
+pub const VOXEL_SIZE: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Vector3,
+    pub direction: Vector3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pick {
+    pub voxel: [usize; 3],
+    pub cell: Cell,
+    /// Empty cell the ray went through before the hit, new voxels are placed here.
+    /// Not set if that cell is outside of the grid.
+    pub before: Option<[usize; 3]>,
+}
+
+/// CPU version of `voxel_traverse` from the ray tracing shader, so that picked
+/// voxel is the one visible under the cursor. Cells outside of the grid are air.
+pub fn voxel_traverse(grid: &(impl VoxelGrid + ?Sized), ray: &Ray, max_steps: i32) -> Option<Pick> {
+    let origin = ray.origin;
+    let direction = ray.direction.normalize();
+
+    let step = direction.map(|it| if it <= 0.0 { -1.0 } else { 1.0 });
+    let stepi = step.map(|it| it as i32);
+    let mut current_voxel = (origin / VOXEL_SIZE).map(|it| it.floor() as i32);
+    let next_bound = current_voxel
+        .zip_map(&stepi, |voxel, step| voxel + (step + 1) / 2)
+        .map(|it| it as f32 * VOXEL_SIZE);
+
+    // axis parallel rays never cross planes on that axis
+    let div = |a: f32, b: f32| if b == 0.0 { f32::INFINITY } else { a / b };
+    let mut t_max = (next_bound - origin).zip_map(&direction, div);
+    let t_delta = (step * VOXEL_SIZE).zip_map(&direction, div);
+
+    for _ in 0..max_steps {
+        let previous = current_voxel;
+        let axis = if t_max.x < t_max.y && t_max.x < t_max.z {
+            0
+        } else if t_max.y < t_max.z {
+            1
+        } else {
+            2
+        };
+        t_max[axis] += t_delta[axis];
+        current_voxel[axis] += stepi[axis];
+
+        let Some(voxel) = grid_coords(grid, current_voxel) else {
+            continue;
+        };
+        let cell = grid.get(voxel);
+        if cell != Cell::NONE {
+            return Some(Pick {
+                voxel,
+                cell,
+                // rays starting inside of a solid have no empty cell before the hit
+                before: grid_coords(grid, previous).filter(|it| grid.get(*it) == Cell::NONE),
+            });
+        }
+    }
+
+    None
+}
+
+fn grid_coords(grid: &(impl VoxelGrid + ?Sized), p: nalgebra::Vector3<i32>) -> Option<[usize; 3]> {
+    let size = grid.size();
+    let mut result = [0; 3];
+    for i in 0..3 {
+        if p[i] < 0 || p[i] as usize >= size[i] {
+            return None;
+        }
+        result[i] = p[i] as usize;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::Map;
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray {
+            origin: Vector3::from(origin) * VOXEL_SIZE,
+            direction: Vector3::from(direction),
+        }
+    }
+
+    #[test]
+    fn axis_aligned_ray_along_voxel_edge_hits() {
+        let mut map = Map::empty(6, 4, 4);
+        *map.at_mut(4, 2, 1) = Cell::STONE;
+
+        // runs exactly on the edge between y 1 and 2 and z 1 and 2
+        let pick = voxel_traverse(&map, &ray([0.5, 2.0, 1.0], [1.0, 0.0, 0.0]), 20).unwrap();
+        assert_eq!(pick.voxel, [4, 2, 1]);
+        assert_eq!(pick.cell, Cell::STONE);
+        assert_eq!(pick.before, Some([3, 2, 1]));
+
+        // from outside of the grid
+        let pick = voxel_traverse(&map, &ray([4.5, 2.5, -3.0], [0.0, 0.0, 1.0]), 20).unwrap();
+        assert_eq!(pick.voxel, [4, 2, 1]);
+        assert_eq!(pick.before, Some([4, 2, 0]));
+    }
+
+    #[test]
+    fn ray_starting_inside_solid_hits_next_solid() {
+        let mut map = Map::empty(6, 4, 4);
+        *map.at_mut(1, 1, 1) = Cell::STONE;
+        *map.at_mut(2, 1, 1) = Cell::SAND;
+
+        let pick = voxel_traverse(&map, &ray([1.5, 1.5, 1.5], [1.0, 0.0, 0.0]), 20).unwrap();
+        assert_eq!(pick.voxel, [2, 1, 1]);
+        assert_eq!(pick.cell, Cell::SAND);
+        assert_eq!(pick.before, None);
+    }
+
+    #[test]
+    fn ray_missing_solids_picks_nothing() {
+        let mut map = Map::empty(6, 4, 4);
+        *map.at_mut(4, 2, 1) = Cell::STONE;
+
+        // pointing away
+        assert!(voxel_traverse(&map, &ray([2.5, 2.5, 1.5], [-1.0, 0.0, 0.0]), 20).is_none());
+        // passing next to it
+        assert!(voxel_traverse(&map, &ray([0.5, 0.5, 1.5], [1.0, 0.0, 0.0]), 20).is_none());
+        // too few steps to reach it
+        assert!(voxel_traverse(&map, &ray([0.5, 2.5, 1.5], [1.0, 0.0, 0.0]), 3).is_none());
+    }
+}
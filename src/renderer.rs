@@ -1,4 +1,9 @@
+use crate::brush::Bounds;
+use crate::emitters::Emitter;
+use crate::gpu_sim::GpuWaterSim;
+use crate::map::WaterSimParams;
 use crate::math::*;
+use crate::solver::{FluidSolver, Restorable};
 use wgpu::util::DeviceExt;
 use winit::window::Window;
 
@@ -19,6 +24,15 @@ pub struct MapDTO<'a> {
     pub y: usize,
     pub z: usize,
     pub cells: &'a [u8],
+    /// Fluid level of every cell, 255 is a full cube. None draws all cells full
+    pub fill: Option<&'a [u8]>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct HighlightDTO {
+    pub voxel: [i32; 3],
+    pub enabled: u32,
 }
 
 #[repr(C)]
@@ -27,7 +41,8 @@ pub struct SettingsDTO {
     pub max_bounce_count: i32,
     pub maximum_traversal_distance: i32,
     pub reproject: f32,
-    pub pad: f32,
+    /// Foam amount per unit of fluid speed, 0 disables foam
+    pub foam: f32,
 }
 
 #[repr(C)]
@@ -37,7 +52,7 @@ pub struct MaterialDTO {
     pub fuzz: f32,
     pub refractive_index: f32,
     pub kind: i32,
-    pub pad0: f32,
+    pub emission: f32,
     pub pad1: f32,
 }
 
@@ -181,7 +196,12 @@ pub struct Renderer {
 
     voxel_texture_size: wgpu::Extent3d,
     voxel_texture: wgpu::Texture,
+    fill_texture: wgpu::Texture,
+    velocity_texture: wgpu::Texture,
+    voxel_bind_group_layout: wgpu::BindGroupLayout,
+    voxel_bind_group: wgpu::BindGroup,
     rng_buffer: wgpu::Buffer,
+    highlight_buffer: wgpu::Buffer,
     inverse_projection_matrix: wgpu::Buffer,
     projection_matrix: wgpu::Buffer,
     view_matrix: wgpu::Buffer,
@@ -211,6 +231,7 @@ pub struct Renderer {
     egui_platform: Platform,
     egui_render_pass: RenderPass,
     gauss_enabled: bool,
+    compute_supported: bool,
 }
 
 impl Renderer {
@@ -247,6 +268,12 @@ impl Renderer {
             .await
             .unwrap();
 
+        // WebGL has no compute shaders
+        let compute_supported = adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS);
+
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps
             .formats
@@ -290,6 +317,12 @@ impl Renderer {
             contents: bytemuck::bytes_of(&rng_seed),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        let highlight = HighlightDTO::default();
+        let highlight_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("highlight buffer"),
+            contents: bytemuck::bytes_of(&highlight),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
         let inverse_projection_matrix =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("inverse projection matrix"),
@@ -313,7 +346,7 @@ impl Renderer {
                 fuzz: 0.0,
                 refractive_index: 0.0,
                 kind: 0,
-                pad0: 0.0,
+                emission: 0.0,
                 pad1: 0.0,
             });
             for (i, it) in dto.materials.iter().enumerate() {
@@ -332,37 +365,9 @@ impl Renderer {
             contents: bytemuck::bytes_of(&dto.settings),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
-        let voxel_texture_size = wgpu::Extent3d {
-            width: dto.map.x as u32,
-            height: dto.map.y as u32,
-            depth_or_array_layers: dto.map.z as u32,
-        };
-        let voxel_texture = device.create_texture(&wgpu::TextureDescriptor {
-            size: voxel_texture_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D3,
-            format: wgpu::TextureFormat::R8Uint,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            label: Some("voxel texture"),
-            view_formats: &[],
-        });
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                texture: &voxel_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            bytemuck::cast_slice(dto.map.cells),
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: voxel_texture_size.width.into(),
-                rows_per_image: voxel_texture_size.height.into(),
-            },
-            voxel_texture_size,
-        );
-        let voxel_texture_view = voxel_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let (voxel_texture, fill_texture, voxel_texture_size) =
+            create_voxel_texture(&device, &queue, &dto.map);
+        let velocity_texture = create_velocity_texture(&device, voxel_texture_size);
         let prev_texture_size = wgpu::Extent3d {
             width: size.width,
             height: size.height,
@@ -410,16 +415,6 @@ impl Renderer {
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("ray tracing bind group layout"),
                 entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            sample_type: wgpu::TextureSampleType::Uint,
-                            view_dimension: wgpu::TextureViewDimension::D3,
-                            multisampled: false,
-                        },
-                        count: None,
-                    },
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
@@ -496,16 +491,22 @@ impl Renderer {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
             });
         let ray_tracing_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("ray tracing bind group"),
             layout: &ray_tracing_bind_group_layout,
             entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&voxel_texture_view),
-                },
                 wgpu::BindGroupEntry {
                     binding: 1,
                     resource: rng_buffer.as_entire_binding(),
@@ -538,8 +539,53 @@ impl Renderer {
                     binding: 8,
                     resource: settings_buffer.as_entire_binding(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: highlight_buffer.as_entire_binding(),
+                },
             ],
         });
+        let voxel_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("voxel bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Uint,
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D3,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let voxel_bind_group = create_voxel_bind_group(
+            &device,
+            &voxel_bind_group_layout,
+            [&voxel_texture, &fill_texture, &velocity_texture],
+        );
         let targets_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("targets group layout"),
@@ -656,7 +702,11 @@ impl Renderer {
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("render pipeline layout"),
-                bind_group_layouts: &[&ray_tracing_bind_group_layout, &targets_bind_group_layout],
+                bind_group_layouts: &[
+                    &ray_tracing_bind_group_layout,
+                    &targets_bind_group_layout,
+                    &voxel_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
         let present_pipeline_layout =
@@ -849,7 +899,12 @@ impl Renderer {
 
             voxel_texture_size,
             voxel_texture,
+            fill_texture,
+            velocity_texture,
+            voxel_bind_group_layout,
+            voxel_bind_group,
             rng_buffer,
+            highlight_buffer,
             inverse_projection_matrix,
             projection_matrix,
             view_matrix,
@@ -878,6 +933,7 @@ impl Renderer {
             egui_platform,
             egui_render_pass,
             gauss_enabled: true,
+            compute_supported,
         }
     }
 
@@ -885,6 +941,10 @@ impl Renderer {
         &self.window
     }
 
+    pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
+        self.size
+    }
+
     pub fn handle_lost_frame(&mut self) {
         self.resize(self.size);
     }
@@ -945,6 +1005,7 @@ impl Renderer {
                 &self.targets_bind_groups[!self.targets_ping_pong as usize],
                 &[],
             );
+            render_pass.set_bind_group(2, &self.voxel_bind_group, &[]);
             render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
             render_pass.draw(0..DISPLAY_VERTICES.len() as u32, 0..1);
         }
@@ -1051,6 +1112,18 @@ impl Renderer {
         self.queue
             .write_buffer(&self.rng_buffer, 0, bytemuck::bytes_of(&seed));
     }
+    /// Marks voxel under cursor, `None` removes the mark.
+    pub fn update_highlight(&mut self, voxel: Option<[usize; 3]>) {
+        let highlight = match voxel {
+            Some(voxel) => HighlightDTO {
+                voxel: voxel.map(|it| it as i32),
+                enabled: 1,
+            },
+            None => HighlightDTO::default(),
+        };
+        self.queue
+            .write_buffer(&self.highlight_buffer, 0, bytemuck::bytes_of(&highlight));
+    }
     pub fn update_camera(&mut self, camera: &CameraDTO, camera_was_changed: bool) {
         if self.should_update_last_view_matrix && !camera_was_changed {
             self.last_view_matrix = camera.view_matrix.try_inverse().unwrap();
@@ -1087,23 +1160,125 @@ impl Renderer {
         }
     }
 
+    /// Replaces the whole map, velocity of the old one is cleared.
     pub fn update_map(&mut self, dto: MapDTO) {
+        if self.voxel_texture_size.width != dto.x as u32
+            || self.voxel_texture_size.height != dto.y as u32
+            || self.voxel_texture_size.depth_or_array_layers != dto.z as u32
+        {
+            let (voxel_texture, fill_texture, voxel_texture_size) =
+                create_voxel_texture(&self.device, &self.queue, &dto);
+            let velocity_texture = create_velocity_texture(&self.device, voxel_texture_size);
+            self.voxel_bind_group = create_voxel_bind_group(
+                &self.device,
+                &self.voxel_bind_group_layout,
+                [&voxel_texture, &fill_texture, &velocity_texture],
+            );
+            self.voxel_texture = voxel_texture;
+            self.fill_texture = fill_texture;
+            self.velocity_texture = velocity_texture;
+            self.voxel_texture_size = voxel_texture_size;
+            return;
+        }
+
+        let region = Bounds {
+            min: [0; 3],
+            max: [dto.x, dto.y, dto.z],
+        };
+        write_voxel_region(
+            &self.queue,
+            &self.voxel_texture,
+            &self.fill_texture,
+            &dto,
+            &region,
+        );
+        self.update_velocity(None);
+    }
+    /// Uploads only cells inside `region`, map size must not change.
+    pub fn update_map_region(&mut self, dto: MapDTO, region: &Bounds) {
+        write_voxel_region(
+            &self.queue,
+            &self.voxel_texture,
+            &self.fill_texture,
+            &dto,
+            region,
+        );
+    }
+    /// Uploads fluid velocity for foam, indexed like map cells. None clears it.
+    pub fn update_velocity(&mut self, velocity: Option<&[[f32; 3]]>) {
+        let size = self.voxel_texture_size;
+        let count = (size.width * size.height * size.depth_or_array_layers) as usize;
+        let data = match velocity {
+            Some(velocity) => {
+                assert_eq!(velocity.len(), count);
+                velocity
+                    .iter()
+                    .map(|it| [it[0], it[1], it[2], 0.0])
+                    .collect::<Vec<_>>()
+            }
+            None => vec![[0.0; 4]; count],
+        };
         self.queue.write_texture(
             wgpu::ImageCopyTexture {
-                texture: &self.voxel_texture,
+                texture: &self.velocity_texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::All,
             },
-            bytemuck::cast_slice(dto.cells),
+            bytemuck::cast_slice(&data),
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: self.voxel_texture_size.width.into(),
-                rows_per_image: self.voxel_texture_size.height.into(),
+                bytes_per_row: (size.width * 16).into(),
+                rows_per_image: size.height.into(),
             },
-            self.voxel_texture_size,
+            size,
         );
     }
+    /// Uploads velocity of cells inside `region` only, map size must not change.
+    pub fn update_velocity_region(&mut self, velocity: &[[f32; 3]], region: &Bounds) {
+        let size = self.voxel_texture_size;
+        let [x, y] = [size.width as usize, size.height as usize];
+        assert_eq!(velocity.len(), x * y * size.depth_or_array_layers as usize);
+        if region.is_empty() {
+            return;
+        }
+        let [width, height, depth] = region.size();
+        let mut data = Vec::with_capacity(width * height * depth);
+        for zi in region.min[2]..region.max[2] {
+            for yi in region.min[1]..region.max[1] {
+                let row = (zi * y + yi) * x;
+                data.extend(
+                    velocity[row + region.min[0]..row + region.max[0]]
+                        .iter()
+                        .map(|it| [it[0], it[1], it[2], 0.0]),
+                );
+            }
+        }
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.velocity_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: region.min[0] as u32,
+                    y: region.min[1] as u32,
+                    z: region.min[2] as u32,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: ((width * 16) as u32).into(),
+                rows_per_image: (height as u32).into(),
+            },
+            wgpu::Extent3d {
+                width: width as u32,
+                height: height as u32,
+                depth_or_array_layers: depth as u32,
+            },
+        );
+    }
+
     pub fn update_settings(&mut self, settings: SettingsDTO) {
         self.queue
             .write_buffer(&self.settings_buffer, 0, bytemuck::bytes_of(&settings));
@@ -1127,7 +1302,7 @@ impl Renderer {
                 fuzz: 0.0,
                 refractive_index: 0.0,
                 kind: 0,
-                pad0: 0.0,
+                emission: 0.0,
                 pad1: 0.0,
             });
             for (i, it) in materials.iter().enumerate() {
@@ -1142,6 +1317,177 @@ impl Renderer {
             bytemuck::cast_slice(&textures_vec),
         );
     }
+
+    pub fn compute_supported(&self) -> bool {
+        self.compute_supported
+    }
+
+    /// Moves simulation state of `sim` to the GPU. Fails if the adapter can
+    /// not run compute shaders or the GPU can not simulate `sim`.
+    pub fn create_gpu_sim(&self, sim: &dyn FluidSolver) -> Result<GpuWaterSim, &'static str> {
+        if !self.compute_supported {
+            return Err("adapter can not run compute shaders");
+        }
+        let mut gpu_sim = GpuWaterSim::new(&self.device, sim)?;
+        gpu_sim.upload(&self.queue, sim);
+        Ok(gpu_sim)
+    }
+
+    pub fn upload_gpu_sim(&self, gpu_sim: &mut GpuWaterSim, sim: &dyn FluidSolver) {
+        gpu_sim.upload(&self.queue, sim);
+    }
+
+    pub fn set_gpu_sim_params(&self, gpu_sim: &mut GpuWaterSim, params: &WaterSimParams) {
+        gpu_sim.set_params(&self.queue, params);
+    }
+
+    pub fn set_gpu_sim_emitters(
+        &self,
+        gpu_sim: &mut GpuWaterSim,
+        emitters: &[Emitter],
+        sim: &dyn FluidSolver,
+    ) {
+        gpu_sim.set_emitters(&self.queue, emitters, sim);
+    }
+
+    /// Runs one step on the GPU and shows its result, voxel texture must have
+    /// the size of the simulation.
+    pub fn step_gpu_sim(&mut self, gpu_sim: &mut GpuWaterSim) {
+        let size = gpu_sim.size();
+        assert_eq!(
+            [
+                self.voxel_texture_size.width,
+                self.voxel_texture_size.height,
+                self.voxel_texture_size.depth_or_array_layers,
+            ],
+            size.map(|it| it as u32)
+        );
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Water sim encoder"),
+            });
+        gpu_sim.encode_step(&mut encoder, &self.voxel_texture, &self.fill_texture);
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Copies GPU simulation state into `sim`, blocks until the GPU is done.
+    pub fn download_gpu_sim(&self, gpu_sim: &GpuWaterSim, sim: &mut dyn Restorable) -> bool {
+        gpu_sim.download(&self.device, &self.queue, sim)
+    }
+}
+
+/// Cell ids and fill levels of the map, both indexed by voxel.
+fn create_voxel_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    map: &MapDTO,
+) -> (wgpu::Texture, wgpu::Texture, wgpu::Extent3d) {
+    let voxel_texture_size = wgpu::Extent3d {
+        width: map.x as u32,
+        height: map.y as u32,
+        depth_or_array_layers: map.z as u32,
+    };
+    let texture = |format, label| {
+        device.create_texture(&wgpu::TextureDescriptor {
+            size: voxel_texture_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some(label),
+            view_formats: &[],
+        })
+    };
+    let voxel_texture = texture(wgpu::TextureFormat::R8Uint, "voxel texture");
+    let fill_texture = texture(wgpu::TextureFormat::R8Unorm, "fill texture");
+    let region = Bounds {
+        min: [0; 3],
+        max: [map.x, map.y, map.z],
+    };
+    write_voxel_region(queue, &voxel_texture, &fill_texture, map, &region);
+    (voxel_texture, fill_texture, voxel_texture_size)
+}
+
+fn write_voxel_region(
+    queue: &wgpu::Queue,
+    voxel_texture: &wgpu::Texture,
+    fill_texture: &wgpu::Texture,
+    map: &MapDTO,
+    region: &Bounds,
+) {
+    let full;
+    let fill = match map.fill {
+        Some(fill) => fill,
+        None => {
+            full = vec![u8::MAX; map.cells.len()];
+            &full
+        }
+    };
+    let size = region.size();
+    for (texture, data) in [(voxel_texture, map.cells), (fill_texture, fill)] {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: region.min[0] as u32,
+                    y: region.min[1] as u32,
+                    z: region.min[2] as u32,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: (region.min[2] * map.x * map.y + region.min[1] * map.x + region.min[0])
+                    as u64,
+                bytes_per_row: (map.x as u32).into(),
+                rows_per_image: (map.y as u32).into(),
+            },
+            wgpu::Extent3d {
+                width: size[0] as u32,
+                height: size[1] as u32,
+                depth_or_array_layers: size[2] as u32,
+            },
+        );
+    }
+}
+
+/// Starts out zeroed, filled by `Renderer::update_velocity`.
+fn create_velocity_texture(device: &wgpu::Device, size: wgpu::Extent3d) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        format: wgpu::TextureFormat::Rgba32Float,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        label: Some("velocity texture"),
+        view_formats: &[],
+    })
+}
+
+/// Textures in order of their bindings: cells, fill levels, velocity.
+fn create_voxel_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    textures: [&wgpu::Texture; 3],
+) -> wgpu::BindGroup {
+    let views = textures.map(|it| it.create_view(&wgpu::TextureViewDescriptor::default()));
+    let entries = views
+        .iter()
+        .enumerate()
+        .map(|(i, view)| wgpu::BindGroupEntry {
+            binding: i as u32,
+            resource: wgpu::BindingResource::TextureView(view),
+        })
+        .collect::<Vec<_>>();
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("voxel bind group"),
+        layout,
+        entries: &entries,
+    })
 }
 
 #[repr(C)]
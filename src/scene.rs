@@ -0,0 +1,836 @@
+use crate::boundary::{Boundaries, Boundary};
+use crate::camera::CameraPose;
+use crate::cells::{Cell, CellRegistry, MAX_CELL_TYPES};
+use crate::emitters::{Emitter, EmitterKind, EmitterShape};
+use crate::map::{CellState, ErosionParams, WaterSim, WaterSimParams};
+use crate::materials::Material;
+use crate::math::*;
+use crate::settings::Settings;
+use crate::solver::{FluidSolver, Restorable};
+use crate::weather::{RainDistribution, Weather};
+use crate::xorshift32::{Xorshift32, Xorshift32Seed};
+use rand::SeedableRng;
+use std::io::{Read, Write};
+
+// Scene file layout, all values little-endian:
+//   magic, version
+//   settings with foam, camera pose, session seed
+//   cell types: count, then (name, solid, fluid, material) for each
+//   grid: padded dimensions
+//   cells, water mass bits and lava mass bits, run-length encoded as
+//   (run length, value) pairs
+//   simulation random generator state
+//   water and lava parameters, sleep threshold included
+//   boundaries: (kind, ocean level) for each face
+//   erosion: enabled, erosion and deposition rates, min speed, deposit cell,
+//   erodible cells count and ids, then carried sediment, settled sediment and
+//   wear bits per cell, run-length encoded
+//   sleeping state of every block, run-length encoded
+//   emitters: count, then (kind, position, shape, box size, rate, enabled) for each
+//   weather: enabled, rain rate, distribution with all its fields, evaporation rate
+const MAGIC: [u8; 4] = *b"VXWS";
+pub const SCENE_VERSION: u32 = 1;
+const MAX_GRID_CELLS: usize = 1 << 28;
+const MAX_EMITTERS: usize = 1 << 16;
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    SizeMismatch { expected: usize, actual: usize },
+    Invalid(&'static str),
+    UnsupportedSolver(&'static str),
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {}", err),
+            Self::BadMagic => write!(f, "not a scene file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported scene version {} (expected {})",
+                version, SCENE_VERSION
+            ),
+            Self::Truncated => write!(f, "scene file is truncated"),
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "grid size mismatch: expected {} cells, got {}",
+                expected, actual
+            ),
+            Self::Invalid(what) => write!(f, "invalid scene data: {}", what),
+            Self::UnsupportedSolver(name) => write!(f, "{} state can not be saved", name),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<std::io::Error> for SceneError {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+pub struct Scene {
+    pub settings: Settings,
+    pub camera: CameraPose,
+    pub session_seed: u32,
+    /// Materials indexed by cell id. These may differ from registry defaults
+    /// because they can be edited at runtime.
+    pub materials: Vec<Material>,
+    /// Scenes are loaded as the cellular automaton, any backend whose state
+    /// is restorable and has parameters can be saved.
+    pub sim: Box<dyn FluidSolver>,
+    pub emitters: Vec<Emitter>,
+    pub weather: Weather,
+}
+
+impl Scene {
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), SceneError> {
+        let file = std::fs::File::create(path)?;
+        let mut writer = std::io::BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, SceneError> {
+        let file = std::fs::File::open(path)?;
+        Self::read(&mut std::io::BufReader::new(file))
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<(), SceneError> {
+        let (Some(sim), Some(params), Some(lava_params), Some(blocks)) = (
+            self.sim.restorable(),
+            self.sim.params(),
+            self.sim.lava_params(),
+            self.sim.block_activity(),
+        ) else {
+            return Err(SceneError::UnsupportedSolver(self.sim.name()));
+        };
+
+        w.write_all(&MAGIC)?;
+        write_u32(w, SCENE_VERSION)?;
+
+        write_i32(w, self.settings.max_bounce_count)?;
+        write_i32(w, self.settings.maximum_traversal_distance)?;
+        write_bool(w, self.settings.enable_reproject)?;
+        write_bool(w, self.settings.enable_gauss)?;
+        write_bool(w, self.settings.foam)?;
+
+        write_vector3(w, self.camera.position)?;
+        write_f32(w, self.camera.pitch)?;
+        write_f32(w, self.camera.yaw)?;
+        write_u32(w, self.session_seed)?;
+
+        let registry = sim.registry();
+        write_u32(w, registry.len() as u32)?;
+        for it in registry.iter() {
+            let name = it.name.as_bytes();
+            write_u32(w, name.len() as u32)?;
+            w.write_all(name)?;
+            write_bool(w, it.solid)?;
+            write_bool(w, it.fluid)?;
+            let material = self
+                .materials
+                .get(it.id.id() as usize)
+                .unwrap_or(&it.material);
+            write_material(w, material)?;
+        }
+
+        for it in sim.size() {
+            write_u32(w, it as u32)?;
+        }
+        write_runs(w, sim.cells(), |w, cell| w.write_all(&[cell.id()]))?;
+        write_state_field(w, sim, |it| it.water)?;
+        write_state_field(w, sim, |it| it.lava)?;
+        write_u32(w, sim.rng().state())?;
+        write_params(w, &params)?;
+        write_params(w, &lava_params)?;
+
+        for it in sim.boundaries().unwrap_or_default().0 {
+            let (kind, level) = match it {
+                Boundary::Closed => (0, 0),
+                Boundary::Drain => (1, 0),
+                Boundary::Ocean { level } => (2, level),
+                Boundary::Periodic => (3, 0),
+            };
+            write_u32(w, kind)?;
+            write_u32(w, level as u32)?;
+        }
+
+        let erosion = sim.erosion().unwrap_or_default();
+        write_bool(w, erosion.enabled)?;
+        write_f32(w, erosion.erosion_rate)?;
+        write_f32(w, erosion.deposition_rate)?;
+        write_f32(w, erosion.min_speed)?;
+        w.write_all(&[erosion.deposit.id()])?;
+        write_u32(w, erosion.erodible.len() as u32)?;
+        for it in erosion.erodible.iter() {
+            w.write_all(&[it.id()])?;
+        }
+        write_state_field(w, sim, |it| it.sediment)?;
+        write_state_field(w, sim, |it| it.settled)?;
+        write_state_field(w, sim, |it| it.wear)?;
+        write_runs(w, blocks.active, |w, it| write_bool(w, *it))?;
+
+        write_u32(w, self.emitters.len() as u32)?;
+        for it in self.emitters.iter() {
+            let kind = EmitterKind::ALL.iter().position(|kind| *kind == it.kind);
+            write_u32(w, kind.unwrap() as u32)?;
+            for v in it.position {
+                write_u32(w, v as u32)?;
+            }
+            let (shape, size) = match it.shape {
+                EmitterShape::Point => (0, [1; 3]),
+                EmitterShape::Box { size } => (1, size),
+            };
+            write_u32(w, shape)?;
+            for v in size {
+                write_u32(w, v as u32)?;
+            }
+            write_f32(w, it.rate)?;
+            write_bool(w, it.enabled)?;
+        }
+
+        write_bool(w, self.weather.enabled)?;
+        write_f32(w, self.weather.rain_rate)?;
+        let (tag, coverage, center, radius) = match self.weather.distribution {
+            RainDistribution::Uniform => (0, 1.0, [0; 2], 0.0),
+            RainDistribution::Scattered { coverage } => (1, coverage, [0; 2], 0.0),
+            RainDistribution::Cloud { center, radius } => (2, 1.0, center, radius),
+        };
+        write_u32(w, tag)?;
+        write_f32(w, coverage)?;
+        write_u32(w, center[0] as u32)?;
+        write_u32(w, center[1] as u32)?;
+        write_f32(w, radius)?;
+        write_f32(w, self.weather.evaporation_rate)?;
+        Ok(())
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self, SceneError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SceneError::BadMagic);
+        }
+        let version = read_u32(r)?;
+        if version != SCENE_VERSION {
+            return Err(SceneError::UnsupportedVersion(version));
+        }
+
+        let settings = Settings {
+            max_bounce_count: read_i32(r)?,
+            maximum_traversal_distance: read_i32(r)?,
+            enable_reproject: read_bool(r)?,
+            enable_gauss: read_bool(r)?,
+            foam: read_bool(r)?,
+        };
+        let camera = CameraPose {
+            position: read_vector3(r)?,
+            pitch: read_f32(r)?,
+            yaw: read_f32(r)?,
+        };
+        let session_seed = read_u32(r)?;
+
+        let type_count = read_u32(r)? as usize;
+        if type_count == 0 || type_count > crate::cells::MAX_CELL_TYPES {
+            return Err(SceneError::Invalid("cell type count"));
+        }
+        let mut registry = CellRegistry::empty();
+        let mut materials = Vec::with_capacity(type_count);
+        for _ in 0..type_count {
+            let name_len = read_u32(r)? as usize;
+            if name_len > 1024 {
+                return Err(SceneError::Invalid("cell type name"));
+            }
+            let mut name = vec![0u8; name_len];
+            r.read_exact(&mut name)?;
+            let name =
+                String::from_utf8(name).map_err(|_| SceneError::Invalid("cell type name"))?;
+            let solid = read_bool(r)?;
+            let fluid = read_bool(r)?;
+            let material = read_material(r)?;
+            registry
+                .register(&name, solid, fluid, material)
+                .ok_or(SceneError::Invalid("cell type name"))?;
+            materials.push(material);
+        }
+
+        let x = read_u32(r)? as usize;
+        let y = read_u32(r)? as usize;
+        let z = read_u32(r)? as usize;
+        if x < 3 || y < 3 || z < 3 {
+            return Err(SceneError::Invalid("grid dimensions"));
+        }
+        let count = x
+            .checked_mul(y)
+            .and_then(|it| it.checked_mul(z))
+            .filter(|it| *it <= MAX_GRID_CELLS)
+            .ok_or(SceneError::Invalid("grid dimensions"))?;
+
+        let cells = read_runs(r, count, |r| {
+            let mut id = [0u8];
+            r.read_exact(&mut id)?;
+            Ok(Cell(id[0]))
+        })?;
+        if cells.iter().any(|it| it.id() as usize >= type_count) {
+            return Err(SceneError::Invalid("unknown cell type"));
+        }
+        let water = read_state_field(r, count)?;
+        let lava = read_state_field(r, count)?;
+        if registry.by_name("lava").is_none() && lava.iter().any(|it| *it != 0.0) {
+            return Err(SceneError::Invalid("lava mass without lava cell type"));
+        }
+
+        // fluid is restored per cell below, together with erosion state
+        let mut sim = WaterSim::from_parts(x, y, z, cells, vec![0.0; count], &registry);
+        let state = read_u32(r)?;
+        if state == 0 {
+            return Err(SceneError::Invalid("random generator state"));
+        }
+        sim.set_rng(Xorshift32::from_seed(Xorshift32Seed(state.to_le_bytes())));
+        sim.set_params(read_params(r)?)
+            .map_err(SceneError::Invalid)?;
+        sim.set_lava_params(read_params(r)?)
+            .map_err(SceneError::Invalid)?;
+
+        let mut boundaries = Boundaries::default();
+        for it in boundaries.0.iter_mut() {
+            let kind = read_u32(r)?;
+            let level = read_u32(r)? as usize;
+            *it = match kind {
+                0 => Boundary::Closed,
+                1 => Boundary::Drain,
+                2 => Boundary::Ocean { level },
+                3 => Boundary::Periodic,
+                _ => return Err(SceneError::Invalid("boundary kind")),
+            };
+        }
+        sim.set_boundaries(boundaries)
+            .map_err(SceneError::Invalid)?;
+
+        let enabled = read_bool(r)?;
+        let erosion_rate = read_f32(r)?;
+        let deposition_rate = read_f32(r)?;
+        let min_speed = read_f32(r)?;
+        let mut deposit = [0u8];
+        r.read_exact(&mut deposit)?;
+        let erodible_count = read_u32(r)? as usize;
+        if erodible_count > MAX_CELL_TYPES {
+            return Err(SceneError::Invalid("erodible cell count"));
+        }
+        let mut erodible = Vec::with_capacity(erodible_count);
+        for _ in 0..erodible_count {
+            let mut id = [0u8];
+            r.read_exact(&mut id)?;
+            erodible.push(Cell(id[0]));
+        }
+        let erosion = ErosionParams {
+            enabled,
+            erosion_rate,
+            deposition_rate,
+            min_speed,
+            erodible,
+            deposit: Cell(deposit[0]),
+        };
+        sim.set_erosion(erosion).map_err(SceneError::Invalid)?;
+        let sediment = read_state_field(r, count)?;
+        let settled = read_state_field(r, count)?;
+        let wear = read_state_field(r, count)?;
+        if !enabled
+            && [&sediment, &settled, &wear]
+                .iter()
+                .any(|it| it.iter().any(|it| *it != 0.0))
+        {
+            return Err(SceneError::Invalid(
+                "erosion state while erosion is disabled",
+            ));
+        }
+        for i in 0..count {
+            let state = CellState {
+                cell: sim.cells()[i],
+                water: water[i],
+                lava: lava[i],
+                sediment: sediment[i],
+                settled: settled[i],
+                wear: wear[i],
+            };
+            if !state.same_as(&CellState {
+                cell: state.cell,
+                ..CellState::default()
+            }) {
+                sim.restore(i, &state);
+            }
+        }
+        let block_count = sim.block_activity().active.len();
+        let active = read_runs(r, block_count, read_bool)?;
+        sim.set_block_activity(&active)
+            .map_err(SceneError::Invalid)?;
+
+        let emitter_count = read_u32(r)? as usize;
+        if emitter_count > MAX_EMITTERS {
+            return Err(SceneError::Invalid("emitter count"));
+        }
+        let mut emitters = Vec::with_capacity(emitter_count);
+        for _ in 0..emitter_count {
+            let kind = *EmitterKind::ALL
+                .get(read_u32(r)? as usize)
+                .ok_or(SceneError::Invalid("emitter kind"))?;
+            let mut position = [0; 3];
+            for (i, it) in position.iter_mut().enumerate() {
+                *it = read_u32(r)? as usize;
+                if *it >= [x, y, z][i] {
+                    return Err(SceneError::Invalid("emitter position"));
+                }
+            }
+            let shape = read_u32(r)?;
+            let mut size = [0; 3];
+            for (i, it) in size.iter_mut().enumerate() {
+                *it = read_u32(r)? as usize;
+                if *it == 0 || *it > [x, y, z][i] {
+                    return Err(SceneError::Invalid("emitter size"));
+                }
+            }
+            let shape = match shape {
+                0 => EmitterShape::Point,
+                1 => EmitterShape::Box { size },
+                _ => return Err(SceneError::Invalid("emitter shape")),
+            };
+            let rate = read_f32(r)?;
+            if !rate.is_finite() || rate < 0.0 {
+                return Err(SceneError::Invalid("emitter rate"));
+            }
+            emitters.push(Emitter {
+                kind,
+                position,
+                shape,
+                rate,
+                enabled: read_bool(r)?,
+            });
+        }
+
+        let mut weather = Weather {
+            enabled: read_bool(r)?,
+            rain_rate: read_f32(r)?,
+            ..Weather::default()
+        };
+        let tag = read_u32(r)?;
+        let coverage = read_f32(r)?;
+        let center = [read_u32(r)? as usize, read_u32(r)? as usize];
+        if center[0] >= x || center[1] >= z {
+            return Err(SceneError::Invalid("cloud center"));
+        }
+        let radius = read_f32(r)?;
+        weather.distribution = match tag {
+            0 => RainDistribution::Uniform,
+            1 => RainDistribution::Scattered { coverage },
+            2 => RainDistribution::Cloud { center, radius },
+            _ => return Err(SceneError::Invalid("rain distribution")),
+        };
+        weather.evaporation_rate = read_f32(r)?;
+        let rates = [
+            weather.rain_rate,
+            coverage,
+            radius,
+            weather.evaporation_rate,
+        ];
+        if rates.iter().any(|it| !it.is_finite() || *it < 0.0) {
+            return Err(SceneError::Invalid("weather"));
+        }
+
+        Ok(Self {
+            settings,
+            camera,
+            session_seed,
+            materials,
+            sim: Box::new(sim),
+            emitters,
+            weather,
+        })
+    }
+}
+
+fn write_runs<W: Write, T: Copy + PartialEq>(
+    w: &mut W,
+    values: &[T],
+    mut write_value: impl FnMut(&mut W, &T) -> std::io::Result<()>,
+) -> Result<(), SceneError> {
+    let mut runs = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((len, last)) if last == value && *len < u32::MAX => *len += 1,
+            _ => runs.push((1u32, *value)),
+        }
+    }
+
+    write_u32(w, runs.len() as u32)?;
+    for (len, value) in runs.iter() {
+        write_u32(w, *len)?;
+        write_value(w, value)?;
+    }
+    Ok(())
+}
+
+/// Writes one field of every cell state as bits, so that it is restored exactly.
+fn write_state_field(
+    w: &mut impl Write,
+    sim: &dyn Restorable,
+    field: impl Fn(&CellState) -> f32,
+) -> Result<(), SceneError> {
+    let bits = (0..sim.cells().len())
+        .map(|i| field(&sim.cell_state(i)).to_bits())
+        .collect::<Vec<_>>();
+    write_runs(w, &bits, |w, bits| write_u32(w, *bits))
+}
+
+fn read_state_field(r: &mut impl Read, count: usize) -> Result<Vec<f32>, SceneError> {
+    let values = read_runs(r, count, read_u32)?
+        .into_iter()
+        .map(f32::from_bits)
+        .collect::<Vec<_>>();
+    if values.iter().any(|it| !it.is_finite()) {
+        return Err(SceneError::Invalid("cell state"));
+    }
+    Ok(values)
+}
+
+fn read_runs<R: Read, T: Copy>(
+    r: &mut R,
+    count: usize,
+    mut read_value: impl FnMut(&mut R) -> std::io::Result<T>,
+) -> Result<Vec<T>, SceneError> {
+    let run_count = read_u32(r)? as usize;
+    let mut values = Vec::with_capacity(count.min(MAX_GRID_CELLS));
+    for _ in 0..run_count {
+        let len = read_u32(r)? as usize;
+        let value = read_value(r)?;
+        if values.len() + len > count {
+            return Err(SceneError::SizeMismatch {
+                expected: count,
+                actual: values.len() + len,
+            });
+        }
+        values.resize(values.len() + len, value);
+    }
+
+    if values.len() != count {
+        return Err(SceneError::SizeMismatch {
+            expected: count,
+            actual: values.len(),
+        });
+    }
+    Ok(values)
+}
+
+fn write_params(w: &mut impl Write, params: &WaterSimParams) -> std::io::Result<()> {
+    write_f32(w, params.max_mass)?;
+    write_f32(w, params.max_compress)?;
+    write_f32(w, params.min_mass)?;
+    write_f32(w, params.min_flow)?;
+    write_f32(w, params.max_speed)?;
+    write_f32(w, params.spread)?;
+    write_f32(w, params.sleep_threshold)
+}
+
+fn read_params(r: &mut impl Read) -> std::io::Result<WaterSimParams> {
+    Ok(WaterSimParams {
+        max_mass: read_f32(r)?,
+        max_compress: read_f32(r)?,
+        min_mass: read_f32(r)?,
+        min_flow: read_f32(r)?,
+        max_speed: read_f32(r)?,
+        spread: read_f32(r)?,
+        sleep_threshold: read_f32(r)?,
+    })
+}
+
+fn write_material(w: &mut impl Write, material: &Material) -> std::io::Result<()> {
+    match material {
+        Material::Diffuse { albedo } => {
+            w.write_all(&[0])?;
+            write_vector3(w, *albedo)?;
+            write_f32(w, 0.0)
+        }
+        Material::Metal { albedo, fuzz } => {
+            w.write_all(&[1])?;
+            write_vector3(w, *albedo)?;
+            write_f32(w, *fuzz)
+        }
+        Material::Dielectric {
+            albedo,
+            refractive_index,
+        } => {
+            w.write_all(&[2])?;
+            write_vector3(w, *albedo)?;
+            write_f32(w, *refractive_index)
+        }
+        Material::Emissive { albedo, intensity } => {
+            w.write_all(&[3])?;
+            write_vector3(w, *albedo)?;
+            write_f32(w, *intensity)
+        }
+    }
+}
+
+fn read_material(r: &mut impl Read) -> Result<Material, SceneError> {
+    let mut kind = [0u8];
+    r.read_exact(&mut kind)?;
+    let albedo = read_vector3(r)?;
+    let param = read_f32(r)?;
+    match kind[0] {
+        0 => Ok(Material::diffuse(albedo)),
+        1 => Ok(Material::metal(albedo, param)),
+        2 => Ok(Material::dielectric(albedo, param)),
+        3 => Ok(Material::emissive(albedo, param)),
+        _ => Err(SceneError::Invalid("material kind")),
+    }
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_i32(w: &mut impl Write, v: i32) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_f32(w: &mut impl Write, v: f32) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_bool(w: &mut impl Write, v: bool) -> std::io::Result<()> {
+    w.write_all(&[v as u8])
+}
+
+fn write_vector3(w: &mut impl Write, v: Vector3) -> std::io::Result<()> {
+    write_f32(w, v.x)?;
+    write_f32(w, v.y)?;
+    write_f32(w, v.z)
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32(r: &mut impl Read) -> std::io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_f32(r: &mut impl Read) -> std::io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_bool(r: &mut impl Read) -> std::io::Result<bool> {
+    let mut bytes = [0u8];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0] != 0)
+}
+
+fn read_vector3(r: &mut impl Read) -> std::io::Result<Vector3> {
+    Ok(Vector3::new(read_f32(r)?, read_f32(r)?, read_f32(r)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boundary::Face;
+    use crate::map::Map;
+
+    fn scene() -> Scene {
+        let registry = CellRegistry::default();
+        let mut map = Map::empty(5, 3, 4);
+        *map.at_mut(1, 0, 1) = Cell::STONE;
+        *map.at_mut(2, 1, 1) = Cell::WATER;
+        *map.at_mut(3, 0, 2) = Cell::LAVA;
+        let mut sim = WaterSim::new(map, &registry);
+        sim.restore(
+            sim.index(2, 2, 2),
+            &CellState {
+                cell: Cell::WATER,
+                water: 0.37,
+                ..CellState::default()
+            },
+        );
+        sim.set_rng(Xorshift32::new(1234));
+        let lava = WaterSimParams {
+            spread: 11.0,
+            ..*sim.lava_params()
+        };
+        sim.set_lava_params(lava).unwrap();
+        let mut boundaries = Boundaries::default();
+        boundaries.set(Face::PosX, Boundary::Ocean { level: 3 });
+        sim.set_boundaries(boundaries).unwrap();
+        let erosion = ErosionParams {
+            enabled: true,
+            ..ErosionParams::default()
+        };
+        sim.set_erosion(erosion).unwrap();
+        // both fluids in one cell, with erosion state
+        sim.restore(
+            sim.index(3, 1, 2),
+            &CellState {
+                cell: Cell::LAVA,
+                water: 0.21,
+                lava: 0.63,
+                sediment: 0.05,
+                settled: 0.1,
+                wear: 0.3,
+            },
+        );
+
+        let mut materials = registry.materials();
+        materials[1] = Material::metal(Vector3::new(0.1, 0.2, 0.3), 0.5);
+        Scene {
+            settings: Settings {
+                foam: true,
+                ..Settings::default()
+            },
+            camera: CameraPose {
+                position: Vector3::new(1.0, 2.0, 3.0),
+                pitch: 0.25,
+                yaw: -1.5,
+            },
+            session_seed: 99,
+            materials,
+            sim: Box::new(sim),
+            emitters: vec![Emitter {
+                kind: EmitterKind::Drain,
+                position: [2, 3, 4],
+                shape: EmitterShape::Box { size: [2, 1, 2] },
+                rate: 0.5,
+                enabled: false,
+            }],
+            weather: Weather {
+                enabled: true,
+                rain_rate: 0.01,
+                distribution: RainDistribution::Scattered { coverage: 0.3 },
+                evaporation_rate: 0.002,
+            },
+        }
+    }
+
+    fn bytes(scene: &Scene) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        scene.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip_keeps_everything() {
+        let scene = scene();
+        let saved = bytes(&scene);
+        let loaded = Scene::read(&mut saved.as_slice()).unwrap();
+        // every saved field is compared through the bytes written again
+        assert_eq!(bytes(&loaded), saved);
+
+        assert_eq!(loaded.session_seed, 99);
+        assert!(loaded.settings.foam);
+        let same_cells = |a: &dyn FluidSolver, b: &dyn FluidSolver| {
+            let (a, b) = (a.restorable().unwrap(), b.restorable().unwrap());
+            (0..a.cells().len()).all(|i| a.cell_state(i).same_as(&b.cell_state(i)))
+        };
+        assert!(same_cells(loaded.sim.as_ref(), scene.sim.as_ref()));
+        let [x, y, _] = loaded.sim.size();
+        let mixed = loaded
+            .sim
+            .restorable()
+            .unwrap()
+            .cell_state((2 * y + 1) * x + 3);
+        assert_eq!(mixed.water.to_bits(), 0.21f32.to_bits());
+        assert_eq!(mixed.lava.to_bits(), 0.63f32.to_bits());
+        assert_eq!(mixed.wear.to_bits(), 0.3f32.to_bits());
+        let rng = |it: &Scene| it.sim.restorable().unwrap().rng().state();
+        assert_eq!(rng(&loaded), rng(&scene));
+        assert_eq!(loaded.sim.lava_params(), scene.sim.lava_params());
+        assert_eq!(loaded.sim.boundaries(), scene.sim.boundaries());
+        assert_eq!(loaded.sim.erosion(), scene.sim.erosion());
+        assert_eq!(loaded.emitters.len(), 1);
+
+        // the loaded simulation continues exactly as the saved one
+        let (mut saved_sim, mut loaded_sim) = (scene.sim, loaded.sim);
+        for _ in 0..20 {
+            saved_sim.step();
+            loaded_sim.step();
+        }
+        assert!(same_cells(loaded_sim.as_ref(), saved_sim.as_ref()));
+    }
+
+    #[test]
+    fn emitters_and_clouds_must_be_inside_of_grid() {
+        let mut wide = scene();
+        wide.emitters[0].shape = EmitterShape::Box { size: [2, 9, 2] };
+        let result = Scene::read(&mut bytes(&wide).as_slice());
+        assert!(matches!(result, Err(SceneError::Invalid("emitter size"))));
+
+        let mut cloudy = scene();
+        cloudy.weather.distribution = RainDistribution::Cloud {
+            center: [3, 6],
+            radius: 2.0,
+        };
+        let result = Scene::read(&mut bytes(&cloudy).as_slice());
+        assert!(matches!(result, Err(SceneError::Invalid("cloud center"))));
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let saved = bytes(&scene());
+        for len in [2, 6, saved.len() / 2, saved.len() - 1] {
+            let result = Scene::read(&mut &saved[..len]);
+            assert!(matches!(result, Err(SceneError::Truncated)), "{len}");
+        }
+    }
+
+    #[test]
+    fn cell_count_must_match_dimensions() {
+        let mut saved = bytes(&scene());
+        // padded dimensions 7, 5, 6 stand right before the cells
+        let dimensions = [7u32, 5, 6].map(u32::to_le_bytes).concat();
+        let offset = saved
+            .windows(dimensions.len())
+            .position(|it| it == dimensions)
+            .unwrap();
+        saved[offset..offset + 4].copy_from_slice(&8u32.to_le_bytes());
+        let result = Scene::read(&mut saved.as_slice());
+        assert!(
+            matches!(
+                result,
+                Err(SceneError::SizeMismatch {
+                    expected: 240,
+                    actual: 210
+                })
+            ),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut saved = bytes(&scene());
+        for version in [0, SCENE_VERSION + 1] {
+            saved[4..8].copy_from_slice(&version.to_le_bytes());
+            let result = Scene::read(&mut saved.as_slice());
+            assert!(matches!(result, Err(SceneError::UnsupportedVersion(it)) if it == version));
+        }
+        saved[..4].copy_from_slice(b"VXWT");
+        assert!(matches!(
+            Scene::read(&mut saved.as_slice()),
+            Err(SceneError::BadMagic)
+        ));
+    }
+}
@@ -0,0 +1,37 @@
+use crate::renderer::SettingsDTO;
+
+// Fluid velocity in cells per step at which water is covered with foam
+const FOAM_SPEED: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub max_bounce_count: i32,
+    pub maximum_traversal_distance: i32,
+    pub enable_reproject: bool,
+    pub enable_gauss: bool,
+    /// Fast moving water is drawn with foam, needs velocity from the CPU simulation
+    pub foam: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_bounce_count: 4,
+            maximum_traversal_distance: 64,
+            enable_reproject: true,
+            enable_gauss: true,
+            foam: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn as_dto(&self) -> SettingsDTO {
+        SettingsDTO {
+            max_bounce_count: self.max_bounce_count,
+            maximum_traversal_distance: self.maximum_traversal_distance,
+            reproject: if self.enable_reproject { 1.0 } else { 0.0 },
+            foam: if self.foam { 1.0 / FOAM_SPEED } else { 0.0 },
+        }
+    }
+}
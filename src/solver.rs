@@ -0,0 +1,300 @@
+use crate::boundary::Boundaries;
+use crate::brush::{Bounds, VoxelGrid};
+use crate::cells::{Cell, CellRegistry};
+use crate::map::{
+    BlockActivity, CellState, ErosionParams, ErosionStats, Map, MassStats, WaterSim, WaterSimParams,
+};
+use crate::renderer::MapDTO;
+use crate::xorshift32::Xorshift32;
+
+/// Fluid simulation backend. State of every backend is the padded cell grid
+/// together with fluid mass per cell, so that editing and rendering work the
+/// same for all of them.
+pub trait FluidSolver: VoxelGrid {
+    fn name(&self) -> &'static str;
+
+    fn registry(&self) -> &CellRegistry;
+
+    /// Advances simulation by one tick. Returns true if cells have to be uploaded again.
+    fn step(&mut self) -> bool;
+
+    /// Regions whose cells were changed by steps since the last call, for
+    /// partial uploads. Backends that don't track it report the whole grid.
+    fn take_changed_regions(&mut self) -> Vec<Bounds> {
+        vec![Bounds {
+            min: [0; 3],
+            max: self.size(),
+        }]
+    }
+
+    fn mass_at(&self, p: [usize; 3]) -> f32;
+
+    /// Water part of `mass_at`, backends without other fluids report all of it.
+    fn water_at(&self, p: [usize; 3]) -> f32 {
+        self.mass_at(p)
+    }
+
+    fn total_mass(&self) -> f32 {
+        self.mass().iter().sum()
+    }
+
+    /// Mass balance of the last step, if the backend keeps track of it.
+    fn mass_stats(&self) -> Option<MassStats> {
+        None
+    }
+
+    /// Blocks simulated in the next step, if the backend lets settled blocks sleep.
+    fn block_activity(&self) -> Option<BlockActivity<'_>> {
+        None
+    }
+
+    /// Adds fluid to the cell. Returns amount that was actually added, solids
+    /// can not hold fluid.
+    fn inject(&mut self, p: [usize; 3], amount: f32) -> f32;
+
+    /// Takes fluid from the cell. Returns amount that was actually removed.
+    fn remove(&mut self, p: [usize; 3], amount: f32) -> f32;
+
+    /// Cells for rendering, indexed the same way as `cells`.
+    fn as_dto(&self) -> MapDTO<'_>;
+
+    fn cells(&self) -> &[Cell];
+
+    fn mass(&self) -> &[f32];
+
+    /// Fluid velocity per cell during the last step, if the backend computes it.
+    fn velocity(&self) -> Option<&[[f32; 3]]> {
+        None
+    }
+
+    /// Regions whose velocity was changed by steps since the last call.
+    /// Backends that don't track it report the whole grid.
+    fn take_velocity_regions(&mut self) -> Vec<Bounds> {
+        vec![Bounds {
+            min: [0; 3],
+            max: self.size(),
+        }]
+    }
+
+    /// Boundary of every face, if the backend lets them be changed.
+    fn boundaries(&self) -> Option<Boundaries> {
+        None
+    }
+
+    /// Takes effect from the next step. Backends with fixed boundaries reject it.
+    fn set_boundaries(&mut self, _boundaries: Boundaries) -> Result<(), &'static str> {
+        Err("solver has fixed boundaries")
+    }
+
+    /// Erosion settings, if the backend erodes terrain.
+    fn erosion(&self) -> Option<ErosionParams> {
+        None
+    }
+
+    /// Takes effect from the next step. Backends without erosion reject it.
+    fn set_erosion(&mut self, _params: ErosionParams) -> Result<(), &'static str> {
+        Err("solver does not erode terrain")
+    }
+
+    fn erosion_stats(&self) -> Option<ErosionStats> {
+        None
+    }
+
+    /// Water parameters, if the backend can be tuned.
+    fn params(&self) -> Option<WaterSimParams> {
+        None
+    }
+
+    /// Takes effect from the next step. Backends without parameters reject it.
+    fn set_params(&mut self, _params: WaterSimParams) -> Result<(), &'static str> {
+        Err("solver has no parameters")
+    }
+
+    /// Lava parameters, if the backend simulates lava.
+    fn lava_params(&self) -> Option<WaterSimParams> {
+        None
+    }
+
+    fn set_lava_params(&mut self, _params: WaterSimParams) -> Result<(), &'static str> {
+        Err("solver has no lava parameters")
+    }
+
+    /// Per-cell state access, if the backend supports history and scene files.
+    fn restorable(&self) -> Option<&dyn Restorable> {
+        None
+    }
+
+    fn restorable_mut(&mut self) -> Option<&mut dyn Restorable> {
+        None
+    }
+
+    fn boxed_clone(&self) -> Box<dyn FluidSolver>;
+}
+
+/// Backend whose whole state is kept per cell and driven by its own random
+/// generator, so that it can be recorded, saved and restored exactly.
+pub trait Restorable: FluidSolver {
+    fn cell_state(&self, i: usize) -> CellState;
+
+    fn restore(&mut self, i: usize, state: &CellState);
+
+    /// Regions whose cells were changed by steps or edits since the last call.
+    fn take_touched_regions(&mut self) -> Vec<Bounds>;
+
+    fn rng(&self) -> Xorshift32;
+
+    fn set_rng(&mut self, rng: Xorshift32);
+
+    /// Sets which blocks are simulated in the next step, `active` must have
+    /// an entry for every block of `block_activity`.
+    fn set_block_activity(&mut self, _active: &[bool]) -> Result<(), &'static str> {
+        Err("solver does not let blocks sleep")
+    }
+}
+
+/// Solver used for new maps, `seed` drives stochastic parts of the simulation.
+pub fn default_solver(map: Map, registry: &CellRegistry, seed: u32) -> Box<dyn FluidSolver> {
+    let mut sim = WaterSim::new(map, registry);
+    sim.reseed(seed);
+    Box::new(sim)
+}
+
+impl FluidSolver for WaterSim {
+    fn name(&self) -> &'static str {
+        "cellular automaton"
+    }
+
+    fn registry(&self) -> &CellRegistry {
+        WaterSim::registry(self)
+    }
+
+    fn step(&mut self) -> bool {
+        self.simulate()
+    }
+
+    fn take_changed_regions(&mut self) -> Vec<Bounds> {
+        WaterSim::take_changed_regions(self)
+    }
+
+    fn mass_at(&self, p: [usize; 3]) -> f32 {
+        WaterSim::mass(self)[self.index(p[0], p[1], p[2])]
+    }
+
+    fn water_at(&self, p: [usize; 3]) -> f32 {
+        WaterSim::cell_state(self, self.index(p[0], p[1], p[2])).water
+    }
+
+    fn total_mass(&self) -> f32 {
+        WaterSim::total_mass(self) as f32
+    }
+
+    fn mass_stats(&self) -> Option<MassStats> {
+        Some(*self.stats())
+    }
+
+    fn block_activity(&self) -> Option<BlockActivity<'_>> {
+        Some(WaterSim::block_activity(self))
+    }
+
+    fn inject(&mut self, p: [usize; 3], amount: f32) -> f32 {
+        WaterSim::inject(self, p[0], p[1], p[2], amount)
+    }
+
+    fn remove(&mut self, p: [usize; 3], amount: f32) -> f32 {
+        WaterSim::remove(self, p[0], p[1], p[2], amount)
+    }
+
+    fn as_dto(&self) -> MapDTO<'_> {
+        WaterSim::as_dto(self)
+    }
+
+    fn cells(&self) -> &[Cell] {
+        WaterSim::cells(self)
+    }
+
+    fn mass(&self) -> &[f32] {
+        WaterSim::mass(self)
+    }
+
+    fn velocity(&self) -> Option<&[[f32; 3]]> {
+        Some(WaterSim::velocity(self))
+    }
+
+    fn take_velocity_regions(&mut self) -> Vec<Bounds> {
+        WaterSim::take_velocity_regions(self)
+    }
+
+    fn boundaries(&self) -> Option<Boundaries> {
+        Some(*WaterSim::boundaries(self))
+    }
+
+    fn set_boundaries(&mut self, boundaries: Boundaries) -> Result<(), &'static str> {
+        WaterSim::set_boundaries(self, boundaries)
+    }
+
+    fn erosion(&self) -> Option<ErosionParams> {
+        Some(WaterSim::erosion(self).clone())
+    }
+
+    fn set_erosion(&mut self, params: ErosionParams) -> Result<(), &'static str> {
+        WaterSim::set_erosion(self, params)
+    }
+
+    fn erosion_stats(&self) -> Option<ErosionStats> {
+        Some(*WaterSim::erosion_stats(self))
+    }
+
+    fn params(&self) -> Option<WaterSimParams> {
+        Some(*WaterSim::params(self))
+    }
+
+    fn set_params(&mut self, params: WaterSimParams) -> Result<(), &'static str> {
+        WaterSim::set_params(self, params)
+    }
+
+    fn lava_params(&self) -> Option<WaterSimParams> {
+        Some(*WaterSim::lava_params(self))
+    }
+
+    fn set_lava_params(&mut self, params: WaterSimParams) -> Result<(), &'static str> {
+        WaterSim::set_lava_params(self, params)
+    }
+
+    fn restorable(&self) -> Option<&dyn Restorable> {
+        Some(self)
+    }
+
+    fn restorable_mut(&mut self) -> Option<&mut dyn Restorable> {
+        Some(self)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn FluidSolver> {
+        Box::new(self.clone())
+    }
+}
+
+impl Restorable for WaterSim {
+    fn cell_state(&self, i: usize) -> CellState {
+        WaterSim::cell_state(self, i)
+    }
+
+    fn restore(&mut self, i: usize, state: &CellState) {
+        WaterSim::restore(self, i, state)
+    }
+
+    fn take_touched_regions(&mut self) -> Vec<Bounds> {
+        WaterSim::take_touched_regions(self)
+    }
+
+    fn rng(&self) -> Xorshift32 {
+        *WaterSim::rng(self)
+    }
+
+    fn set_rng(&mut self, rng: Xorshift32) {
+        WaterSim::set_rng(self, rng)
+    }
+
+    fn set_block_activity(&mut self, active: &[bool]) -> Result<(), &'static str> {
+        WaterSim::set_block_activity(self, active)
+    }
+}
@@ -0,0 +1,376 @@
+use crate::cells::Cell;
+use crate::map::Map;
+use crate::math::*;
+use crate::perlin::Perlin;
+use crate::xorshift32::Xorshift32;
+
+/// Where generated terrain gets fluid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sea {
+    None,
+    /// Top cells of the lowest columns turn into fluid
+    LowestGrass,
+    /// Empty cells up to this height are filled with fluid
+    Level(usize),
+}
+
+impl Sea {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::LowestGrass => "lowest grass",
+            Self::Level(_) => "level",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TerrainParams {
+    pub seed: u32,
+    pub size: [usize; 3],
+    pub octaves: usize,
+    /// Number of noise periods across the map
+    pub frequency: f32,
+    /// Fraction of map height used by terrain relief
+    pub amplitude: f32,
+    pub sea: Sea,
+    pub top_thickness: usize,
+    pub soil_thickness: usize,
+    pub top: Cell,
+    pub soil: Cell,
+    pub bedrock: Cell,
+    pub fluid: Cell,
+}
+
+impl Default for TerrainParams {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            size: [40, 20, 40],
+            octaves: 4,
+            frequency: 1.0,
+            amplitude: 1.0,
+            sea: Sea::LowestGrass,
+            top_thickness: 1,
+            soil_thickness: 3,
+            top: Cell::GRASS,
+            soil: Cell::GROUND,
+            bedrock: Cell::STONE,
+            fluid: Cell::WATER,
+        }
+    }
+}
+
+pub trait TerrainGenerator {
+    fn name(&self) -> &'static str;
+
+    /// Same parameters always produce the same map.
+    fn generate(&self, params: &TerrainParams) -> Map;
+}
+
+pub fn generators() -> Vec<Box<dyn TerrainGenerator>> {
+    vec![
+        Box::new(PerlinHills),
+        Box::new(RidgedMountains),
+        Box::new(Terraces { steps: 5 }),
+        Box::new(Basin),
+    ]
+}
+
+/// Smooth hills, heights are mapped through sine of turbulence.
+pub struct PerlinHills;
+
+impl TerrainGenerator for PerlinHills {
+    fn name(&self) -> &'static str {
+        "perlin hills"
+    }
+
+    fn generate(&self, params: &TerrainParams) -> Map {
+        let mut perlin = Perlin::new(&mut Xorshift32::new(params.seed));
+        heightmap(params, |_, _, p| {
+            let value = perlin.turb(p, params.octaves);
+            (value.sin() + 1.0) * 0.5
+        })
+    }
+}
+
+/// Sharp ridges made by folding noise around zero.
+pub struct RidgedMountains;
+
+impl TerrainGenerator for RidgedMountains {
+    fn name(&self) -> &'static str {
+        "ridged mountains"
+    }
+
+    fn generate(&self, params: &TerrainParams) -> Map {
+        let mut perlin = Perlin::new(&mut Xorshift32::new(params.seed));
+        heightmap(params, |_, _, p| {
+            let mut accum = 0.0;
+            let mut weight = 0.5;
+            let mut p = p;
+            for _ in 0..params.octaves {
+                let ridge = 1.0 - perlin.noise(p).abs();
+                accum += weight * ridge * ridge;
+                weight *= 0.5;
+                p *= 2.0;
+            }
+            accum
+        })
+    }
+}
+
+/// Perlin hills quantized to a fixed number of flat steps.
+pub struct Terraces {
+    pub steps: usize,
+}
+
+impl TerrainGenerator for Terraces {
+    fn name(&self) -> &'static str {
+        "terraces"
+    }
+
+    fn generate(&self, params: &TerrainParams) -> Map {
+        let mut perlin = Perlin::new(&mut Xorshift32::new(params.seed));
+        let steps = self.steps.max(1) as f32;
+        heightmap(params, |_, _, p| {
+            let value = (perlin.turb(p, params.octaves).sin() + 1.0) * 0.5;
+            (value * steps).floor() / steps
+        })
+    }
+}
+
+/// Noisy floor surrounded by walls, so that water can not leave the map.
+pub struct Basin;
+
+impl TerrainGenerator for Basin {
+    fn name(&self) -> &'static str {
+        "basin"
+    }
+
+    fn generate(&self, params: &TerrainParams) -> Map {
+        let mut perlin = Perlin::new(&mut Xorshift32::new(params.seed));
+        let [x, _, z] = params.size;
+        heightmap(params, |px, pz, p| {
+            if px == 0 || pz == 0 || px == x - 1 || pz == z - 1 {
+                1.0
+            } else {
+                perlin.turb(p, params.octaves) * 0.3
+            }
+        })
+    }
+}
+
+/// Builds map from height function returning values in [0, 1] for each column.
+/// Height function gets column coordinates and noise sampling point.
+pub fn heightmap(
+    params: &TerrainParams,
+    mut height_at: impl FnMut(usize, usize, Vector3) -> f32,
+) -> Map {
+    let [x, y, z] = params.size;
+    let mut map = Map::empty(x, y, z);
+    let mut min_height = usize::MAX;
+    for px in 0..x {
+        for pz in 0..z {
+            let p = Vector3::new(px as f32 / x as f32, 0.0, pz as f32 / z as f32);
+            let value = height_at(px, pz, p * params.frequency).clamp(0.0, 1.0);
+            let height = (value * params.amplitude * y as f32) as usize;
+            let height = height.min(y);
+            if height == 0 {
+                continue;
+            }
+
+            min_height = min_height.min(height - 1);
+            for py in 0..height {
+                let depth = height - 1 - py;
+                let cell = if depth < params.top_thickness {
+                    params.top
+                } else if depth < params.top_thickness + params.soil_thickness {
+                    params.soil
+                } else {
+                    params.bedrock
+                };
+                *map.at_mut(px, py, pz) = cell;
+            }
+        }
+    }
+
+    match params.sea {
+        Sea::None => {}
+        Sea::Level(sea_level) => {
+            for px in 0..x {
+                for pz in 0..z {
+                    for py in 0..(sea_level + 1).min(y) {
+                        if map.at(px, py, pz) == Cell::NONE {
+                            *map.at_mut(px, py, pz) = params.fluid;
+                        }
+                    }
+                }
+            }
+        }
+        Sea::LowestGrass if min_height != usize::MAX => {
+            for px in 0..x {
+                for pz in 0..z {
+                    if map.at(px, min_height, pz) == params.top {
+                        *map.at_mut(px, min_height, pz) = params.fluid;
+                    }
+                }
+            }
+        }
+        Sea::LowestGrass => {}
+    }
+
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> TerrainParams {
+        TerrainParams {
+            seed: 7,
+            size: [24, 16, 20],
+            ..TerrainParams::default()
+        }
+    }
+
+    fn cells(map: &Map) -> Vec<Cell> {
+        let mut cells = Vec::new();
+        for z in 0..map.z() {
+            for y in 0..map.y() {
+                for x in 0..map.x() {
+                    cells.push(map.at(x, y, z));
+                }
+            }
+        }
+        cells
+    }
+
+    /// Number of solid cells from the bottom of the column.
+    fn column_height(map: &Map, x: usize, z: usize) -> usize {
+        (0..map.y())
+            .take_while(|y| ![Cell::NONE, Cell::WATER].contains(&map.at(x, *y, z)))
+            .count()
+    }
+
+    #[test]
+    fn same_seed_gives_same_map() {
+        for generator in generators() {
+            let a = generator.generate(&params());
+            let b = generator.generate(&params());
+            assert_eq!(cells(&a), cells(&b), "{}", generator.name());
+
+            let other = generator.generate(&TerrainParams {
+                seed: 8,
+                ..params()
+            });
+            assert_ne!(cells(&a), cells(&other), "{}", generator.name());
+        }
+    }
+
+    #[test]
+    fn generators_stay_within_size() {
+        for generator in generators() {
+            let params = params();
+            let map = generator.generate(&params);
+            assert_eq!(
+                [map.x(), map.y(), map.z()],
+                params.size,
+                "{}",
+                generator.name()
+            );
+            // columns are solid from the bottom up, nothing floats above them
+            for x in 0..map.x() {
+                for z in 0..map.z() {
+                    let height = column_height(&map, x, z);
+                    assert!((height..map.y())
+                        .all(|y| [Cell::NONE, Cell::WATER].contains(&map.at(x, y, z))));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sea_level_fills_empty_cells_up_to_it() {
+        let map = PerlinHills.generate(&TerrainParams {
+            sea: Sea::Level(6),
+            ..params()
+        });
+        for x in 0..map.x() {
+            for z in 0..map.z() {
+                for y in 0..map.y() {
+                    let cell = map.at(x, y, z);
+                    if y <= 6 {
+                        assert_ne!(cell, Cell::NONE, "{:?}", (x, y, z));
+                    } else {
+                        assert_ne!(cell, Cell::WATER, "{:?}", (x, y, z));
+                    }
+                }
+            }
+        }
+
+        let dry = PerlinHills.generate(&TerrainParams {
+            sea: Sea::None,
+            ..params()
+        });
+        assert!(!cells(&dry).contains(&Cell::WATER));
+
+        // only the top cells of the lowest columns
+        let map = PerlinHills.generate(&params());
+        let water = cells(&map).iter().filter(|it| **it == Cell::WATER).count();
+        assert!(water > 0);
+        let lowest = (0..map.x())
+            .flat_map(|x| (0..map.z()).map(move |z| (x, z)))
+            .map(|(x, z)| column_height(&map, x, z))
+            .min()
+            .unwrap();
+        for x in 0..map.x() {
+            for z in 0..map.z() {
+                for y in 0..map.y() {
+                    if map.at(x, y, z) == Cell::WATER {
+                        assert_eq!(y, lowest, "{:?}", (x, z));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layers_have_requested_thickness() {
+        let params = TerrainParams {
+            top_thickness: 2,
+            soil_thickness: 3,
+            sea: Sea::None,
+            ..params()
+        };
+        // half of the height everywhere
+        let map = heightmap(&params, |_, _, _| 0.5);
+        for x in 0..map.x() {
+            for z in 0..map.z() {
+                let column = (0..map.y()).map(|y| map.at(x, y, z)).collect::<Vec<_>>();
+                let mut expected = vec![Cell::STONE; 3];
+                expected.extend([Cell::GROUND; 3]);
+                expected.extend([Cell::GRASS; 2]);
+                expected.extend([Cell::NONE; 8]);
+                assert_eq!(column, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn amplitude_bounds_height() {
+        for generator in generators() {
+            let params = TerrainParams {
+                amplitude: 0.25,
+                sea: Sea::None,
+                ..params()
+            };
+            let map = generator.generate(&params);
+            for x in 0..map.x() {
+                for z in 0..map.z() {
+                    assert!(column_height(&map, x, z) <= 4, "{}", generator.name());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use crate::brush::VoxelGrid;
+use crate::cells::{Cell, CellRegistry};
+use crate::map::Map;
+use crate::materials::Material;
+use crate::math::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+// MagicaVoxel file format:
+// https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt
+const MAGIC: [u8; 4] = *b"VOX ";
+const VERSION: u32 = 150;
+/// Voxel coordinates are stored as bytes
+const MAX_MODEL_SIZE: usize = 256;
+/// Limit for map built from all models of a file, bounds allocation before
+/// voxels are placed.
+const MAX_IMPORT_CELLS: usize = 1 << 26;
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(std::io::Error),
+    BadMagic,
+    Truncated,
+    Malformed(&'static str),
+    NoModels,
+    ModelOutOfRange(usize),
+    TooManyColors,
+    TooLarge([usize; 3]),
+}
+
+impl std::fmt::Display for VoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {}", err),
+            Self::BadMagic => write!(f, "not a .vox file"),
+            Self::Truncated => write!(f, ".vox file is truncated"),
+            Self::Malformed(what) => write!(f, "malformed .vox file: {}", what),
+            Self::NoModels => write!(f, ".vox file contains no models"),
+            Self::ModelOutOfRange(index) => write!(f, "no model with index {}", index),
+            Self::TooManyColors => write!(f, "not enough free cell types for palette"),
+            Self::TooLarge(size) => write!(
+                f,
+                "{}x{}x{} is too large, .vox models have at most {} cells per axis \
+                 and imported maps at most {} cells",
+                size[0], size[1], size[2], MAX_MODEL_SIZE, MAX_IMPORT_CELLS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<std::io::Error> for VoxError {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            Self::Truncated
+        } else {
+            Self::Io(err)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpAxis {
+    Y,
+    /// MagicaVoxel default
+    Z,
+}
+
+pub struct VoxImportOptions {
+    pub up_axis: UpAxis,
+    /// Import only model with given index. All models are placed next to each
+    /// other along x axis otherwise.
+    pub model: Option<usize>,
+    /// Explicit palette index to cell mapping. Indices not present here get
+    /// their own diffuse cell type with palette color.
+    pub palette_map: HashMap<u8, Cell>,
+}
+
+impl Default for VoxImportOptions {
+    fn default() -> Self {
+        Self {
+            up_axis: UpAxis::Z,
+            model: None,
+            palette_map: HashMap::new(),
+        }
+    }
+}
+
+pub struct VoxImport {
+    pub map: Map,
+    pub model_count: usize,
+    /// Ids of chunks that were skipped, in order of appearance.
+    pub unsupported_chunks: Vec<String>,
+}
+
+struct VoxModel {
+    size: [usize; 3],
+    voxels: Vec<[u8; 4]>,
+}
+
+pub fn import_file(
+    path: impl AsRef<std::path::Path>,
+    registry: &mut CellRegistry,
+    options: &VoxImportOptions,
+) -> Result<VoxImport, VoxError> {
+    let file = std::fs::File::open(path)?;
+    import(&mut std::io::BufReader::new(file), registry, options)
+}
+
+pub fn import(
+    r: &mut impl Read,
+    registry: &mut CellRegistry,
+    options: &VoxImportOptions,
+) -> Result<VoxImport, VoxError> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(VoxError::BadMagic);
+    }
+    let _version = read_u32(r)?;
+
+    let (id, content_size, children_size) = read_chunk_header(r)?;
+    if &id != b"MAIN" {
+        return Err(VoxError::Malformed("first chunk is not MAIN"));
+    }
+    skip(r, content_size)?;
+
+    let mut data = Vec::new();
+    r.by_ref()
+        .take(children_size as u64)
+        .read_to_end(&mut data)?;
+    if data.len() != children_size {
+        return Err(VoxError::Truncated);
+    }
+
+    let mut r = data.as_slice();
+    let mut models = Vec::new();
+    let mut pending_size = None;
+    let mut palette = default_palette();
+    let mut unsupported_chunks = Vec::new();
+    while !r.is_empty() {
+        let (id, content_size, children_size) = read_chunk_header(&mut r)?;
+        if r.len() < content_size.saturating_add(children_size) {
+            return Err(VoxError::Truncated);
+        }
+        let (mut content, rest) = r.split_at(content_size);
+        r = &rest[children_size..];
+
+        match &id {
+            b"PACK" => {}
+            b"SIZE" => {
+                let size = [
+                    read_u32(&mut content)? as usize,
+                    read_u32(&mut content)? as usize,
+                    read_u32(&mut content)? as usize,
+                ];
+                if size.contains(&0) {
+                    return Err(VoxError::Malformed("model size"));
+                }
+                if size.iter().any(|it| *it > MAX_MODEL_SIZE) {
+                    return Err(VoxError::TooLarge(size));
+                }
+                pending_size = Some(size);
+            }
+            b"XYZI" => {
+                let size = pending_size
+                    .take()
+                    .ok_or(VoxError::Malformed("XYZI chunk without SIZE"))?;
+                let count = read_u32(&mut content)? as usize;
+                let len = count.checked_mul(4).ok_or(VoxError::Truncated)?;
+                if content.len() < len {
+                    return Err(VoxError::Truncated);
+                }
+                let voxels = content[..len]
+                    .chunks_exact(4)
+                    .map(|it| [it[0], it[1], it[2], it[3]])
+                    .collect::<Vec<_>>();
+                if voxels.iter().any(|it| {
+                    it[0] as usize >= size[0]
+                        || it[1] as usize >= size[1]
+                        || it[2] as usize >= size[2]
+                }) {
+                    return Err(VoxError::Malformed("voxel outside of model bounds"));
+                }
+                models.push(VoxModel { size, voxels });
+            }
+            b"RGBA" => {
+                if content.len() < 256 * 4 {
+                    return Err(VoxError::Truncated);
+                }
+                // palette entry i describes color index i + 1, last entry is unused
+                for i in 0..255 {
+                    palette[i + 1].copy_from_slice(&content[i * 4..i * 4 + 4]);
+                }
+            }
+            _ => unsupported_chunks.push(String::from_utf8_lossy(&id).into_owned()),
+        }
+    }
+
+    if models.is_empty() {
+        return Err(VoxError::NoModels);
+    }
+    let model_count = models.len();
+    let models = match options.model {
+        Some(index) => vec![models
+            .into_iter()
+            .nth(index)
+            .ok_or(VoxError::ModelOutOfRange(index))?],
+        None => models,
+    };
+
+    let mut cells = [None; 256];
+    for (index, cell) in options.palette_map.iter() {
+        cells[*index as usize] = Some(*cell);
+    }
+
+    let sizes = models
+        .iter()
+        .map(|it| to_map_size(options.up_axis, it.size))
+        .collect::<Vec<_>>();
+    // one cell gap between models
+    let x = sizes.iter().map(|it| it[0]).sum::<usize>() + sizes.len() - 1;
+    let y = sizes.iter().map(|it| it[1]).max().unwrap();
+    let z = sizes.iter().map(|it| it[2]).max().unwrap();
+    if x.checked_mul(y)
+        .and_then(|it| it.checked_mul(z))
+        .is_none_or(|it| it > MAX_IMPORT_CELLS)
+    {
+        return Err(VoxError::TooLarge([x, y, z]));
+    }
+    let mut map = Map::empty(x, y, z);
+    let mut offset = 0;
+    for (model, size) in models.iter().zip(sizes.iter()) {
+        for voxel in model.voxels.iter() {
+            let index = voxel[3];
+            if index == 0 {
+                continue;
+            }
+            let cell = match cells[index as usize] {
+                Some(cell) => cell,
+                None => {
+                    let cell = palette_cell(registry, palette[index as usize])?;
+                    cells[index as usize] = Some(cell);
+                    cell
+                }
+            };
+            let p = [voxel[0] as usize, voxel[1] as usize, voxel[2] as usize];
+            let p = to_map_coords(options.up_axis, model.size, p);
+            *map.at_mut(p[0] + offset, p[1], p[2]) = cell;
+        }
+        offset += size[0] + 1;
+    }
+
+    Ok(VoxImport {
+        map,
+        model_count,
+        unsupported_chunks,
+    })
+}
+
+pub fn export_file(
+    path: impl AsRef<std::path::Path>,
+    sim: &(impl VoxelGrid + ?Sized),
+    materials: &[Material],
+    up_axis: UpAxis,
+) -> Result<(), VoxError> {
+    let file = std::fs::File::create(path)?;
+    let mut writer = std::io::BufWriter::new(file);
+    export(&mut writer, sim, materials, up_axis)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes editable part of the grid as single model, so the padding border
+/// of a simulation is left out.
+/// Cell id is used as palette index, palette colors are taken from material albedos.
+pub fn export(
+    w: &mut impl Write,
+    sim: &(impl VoxelGrid + ?Sized),
+    materials: &[Material],
+    up_axis: UpAxis,
+) -> Result<(), VoxError> {
+    let editable = sim.editable();
+    let map_size = editable.size();
+    let size = to_vox_size(up_axis, map_size);
+    if size.iter().any(|it| *it > MAX_MODEL_SIZE) {
+        return Err(VoxError::TooLarge(size));
+    }
+
+    let mut voxels = Vec::new();
+    for z in 0..map_size[2] {
+        for y in 0..map_size[1] {
+            for x in 0..map_size[0] {
+                let cell = sim.get([0, 1, 2].map(|i| editable.min[i] + [x, y, z][i]));
+                if cell == Cell::NONE {
+                    continue;
+                }
+                let p = to_vox_coords(up_axis, map_size, [x, y, z]);
+                voxels.extend_from_slice(&[p[0] as u8, p[1] as u8, p[2] as u8, cell.id()]);
+            }
+        }
+    }
+
+    let mut palette = vec![0u8; 256 * 4];
+    for (id, material) in materials.iter().enumerate().skip(1) {
+        let albedo = material.albedo();
+        palette[(id - 1) * 4..id * 4].copy_from_slice(&[
+            (albedo.x.clamp(0.0, 1.0) * 255.0).round() as u8,
+            (albedo.y.clamp(0.0, 1.0) * 255.0).round() as u8,
+            (albedo.z.clamp(0.0, 1.0) * 255.0).round() as u8,
+            0xff,
+        ]);
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", |w| {
+        write_u32(w, size[0] as u32)?;
+        write_u32(w, size[1] as u32)?;
+        write_u32(w, size[2] as u32)
+    })?;
+    write_chunk(&mut children, b"XYZI", |w| {
+        write_u32(w, (voxels.len() / 4) as u32)?;
+        w.write_all(&voxels)
+    })?;
+    write_chunk(&mut children, b"RGBA", |w| w.write_all(&palette))?;
+
+    w.write_all(&MAGIC)?;
+    write_u32(w, VERSION)?;
+    w.write_all(b"MAIN")?;
+    write_u32(w, 0)?;
+    write_u32(w, children.len() as u32)?;
+    w.write_all(&children)?;
+    Ok(())
+}
+
+fn to_vox_size(up_axis: UpAxis, size: [usize; 3]) -> [usize; 3] {
+    match up_axis {
+        UpAxis::Y => size,
+        UpAxis::Z => [size[0], size[2], size[1]],
+    }
+}
+
+/// Inverse of `to_map_coords`, `size` is map size.
+fn to_vox_coords(up_axis: UpAxis, size: [usize; 3], p: [usize; 3]) -> [usize; 3] {
+    match up_axis {
+        UpAxis::Y => p,
+        UpAxis::Z => [p[0], size[2] - 1 - p[2], p[1]],
+    }
+}
+
+fn write_chunk(
+    w: &mut Vec<u8>,
+    id: &[u8; 4],
+    write_content: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut content = Vec::new();
+    write_content(&mut content)?;
+    w.write_all(id)?;
+    write_u32(w, content.len() as u32)?;
+    write_u32(w, 0)?;
+    w.write_all(&content)
+}
+
+fn to_map_size(up_axis: UpAxis, size: [usize; 3]) -> [usize; 3] {
+    match up_axis {
+        UpAxis::Y => size,
+        UpAxis::Z => [size[0], size[2], size[1]],
+    }
+}
+
+/// Converts position inside model of given size to map coordinates, keeping
+/// handedness of coordinate system.
+fn to_map_coords(up_axis: UpAxis, size: [usize; 3], p: [usize; 3]) -> [usize; 3] {
+    match up_axis {
+        UpAxis::Y => p,
+        UpAxis::Z => [p[0], p[2], size[1] - 1 - p[1]],
+    }
+}
+
+fn palette_cell(registry: &mut CellRegistry, color: [u8; 4]) -> Result<Cell, VoxError> {
+    let name = format!("vox #{:02x}{:02x}{:02x}", color[0], color[1], color[2]);
+    if let Some(cell) = registry.by_name(&name) {
+        return Ok(cell);
+    }
+
+    let albedo = Vector3::new(
+        color[0] as f32 / 255.0,
+        color[1] as f32 / 255.0,
+        color[2] as f32 / 255.0,
+    );
+    registry
+        .register(&name, true, false, Material::diffuse(albedo))
+        .ok_or(VoxError::TooManyColors)
+}
+
+/// Palette used by MagicaVoxel when file has no RGBA chunk, indexed by color index.
+fn default_palette() -> [[u8; 4]; 256] {
+    const STEPS: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0u8; 4]; 256];
+    let mut i = 1;
+    for r in STEPS {
+        for g in STEPS {
+            for b in STEPS {
+                if r == 0 && g == 0 && b == 0 {
+                    continue;
+                }
+                palette[i] = [r, g, b, 0xff];
+                i += 1;
+            }
+        }
+    }
+    for channel in 0..3 {
+        for v in RAMP {
+            let mut color = [0, 0, 0, 0xff];
+            color[channel] = v;
+            palette[i] = color;
+            i += 1;
+        }
+    }
+    for v in RAMP {
+        palette[i] = [v, v, v, 0xff];
+        i += 1;
+    }
+    palette
+}
+
+fn read_chunk_header(r: &mut impl Read) -> Result<([u8; 4], usize, usize), VoxError> {
+    let mut id = [0u8; 4];
+    r.read_exact(&mut id)?;
+    let content_size = read_u32(r)? as usize;
+    let children_size = read_u32(r)? as usize;
+    Ok((id, content_size, children_size))
+}
+
+fn skip(r: &mut impl Read, count: usize) -> Result<(), VoxError> {
+    let skipped = std::io::copy(&mut r.by_ref().take(count as u64), &mut std::io::sink())?;
+    if skipped != count as u64 {
+        return Err(VoxError::Truncated);
+    }
+    Ok(())
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::WaterSim;
+
+    fn vox_file(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut children = Vec::new();
+        for (id, content) in chunks {
+            write_chunk(&mut children, id, |w| w.write_all(content)).unwrap();
+        }
+        let mut file = Vec::new();
+        file.extend_from_slice(&MAGIC);
+        write_u32(&mut file, VERSION).unwrap();
+        file.extend_from_slice(b"MAIN");
+        write_u32(&mut file, 0).unwrap();
+        write_u32(&mut file, children.len() as u32).unwrap();
+        file.extend_from_slice(&children);
+        file
+    }
+
+    fn size_chunk(size: [u32; 3]) -> (&'static [u8; 4], Vec<u8>) {
+        (
+            b"SIZE",
+            size.iter().flat_map(|it| it.to_le_bytes()).collect(),
+        )
+    }
+
+    fn xyzi_chunk(count: u32, voxels: &[[u8; 4]]) -> (&'static [u8; 4], Vec<u8>) {
+        let mut content = count.to_le_bytes().to_vec();
+        content.extend(voxels.iter().flatten());
+        (b"XYZI", content)
+    }
+
+    fn import_bytes(bytes: &[u8]) -> Result<VoxImport, VoxError> {
+        let options = VoxImportOptions {
+            palette_map: HashMap::from([(1, Cell::STONE)]),
+            ..VoxImportOptions::default()
+        };
+        import(&mut &bytes[..], &mut CellRegistry::default(), &options)
+    }
+
+    #[test]
+    fn minimal_file_is_imported() {
+        let bytes = vox_file(&[
+            size_chunk([2, 3, 4]),
+            xyzi_chunk(2, &[[0, 0, 0, 1], [1, 2, 3, 1]]),
+        ]);
+        let import = import_bytes(&bytes).unwrap();
+
+        let map = &import.map;
+        assert_eq!(import.model_count, 1);
+        assert!(import.unsupported_chunks.is_empty());
+        // z up in the file, y up in the map
+        assert_eq!([map.x(), map.y(), map.z()], [2, 4, 3]);
+        assert_eq!(map.at(0, 0, 2), Cell::STONE);
+        assert_eq!(map.at(1, 3, 0), Cell::STONE);
+        let solid = (0..2)
+            .flat_map(|x| (0..4).flat_map(move |y| (0..3).map(move |z| (x, y, z))))
+            .filter(|(x, y, z)| map.at(*x, *y, *z) != Cell::NONE)
+            .count();
+        assert_eq!(solid, 2);
+    }
+
+    #[test]
+    fn truncated_chunk_is_rejected() {
+        let bytes = vox_file(&[size_chunk([2, 2, 2]), xyzi_chunk(3, &[[0, 0, 0, 1]])]);
+        assert!(matches!(import_bytes(&bytes), Err(VoxError::Truncated)));
+
+        let bytes = vox_file(&[size_chunk([2, 2, 2]), xyzi_chunk(u32::MAX, &[])]);
+        assert!(matches!(import_bytes(&bytes), Err(VoxError::Truncated)));
+
+        let bytes = vox_file(&[size_chunk([2, 2, 2]), xyzi_chunk(1, &[[0, 0, 0, 1]])]);
+        let cut = &bytes[..bytes.len() - 2];
+        assert!(matches!(import_bytes(cut), Err(VoxError::Truncated)));
+    }
+
+    #[test]
+    fn oversized_models_are_rejected() {
+        let bytes = vox_file(&[size_chunk([2, 257, 2]), xyzi_chunk(0, &[])]);
+        assert!(matches!(
+            import_bytes(&bytes),
+            Err(VoxError::TooLarge([2, 257, 2]))
+        ));
+
+        // every model fits, but all of them next to each other don't
+        let model = [size_chunk([256, 256, 256]), xyzi_chunk(0, &[])];
+        let bytes = vox_file(&[0; 5].map(|_| model.clone()).concat());
+        assert!(matches!(
+            import_bytes(&bytes),
+            Err(VoxError::TooLarge([1284, 256, 256]))
+        ));
+    }
+
+    #[test]
+    fn map_is_exported_without_dropping_cells() {
+        let registry = CellRegistry::default();
+        let mut map = Map::empty(3, 2, 4);
+        *map.at_mut(0, 0, 0) = Cell::STONE;
+        *map.at_mut(2, 1, 3) = Cell::SAND;
+
+        let mut bytes = Vec::new();
+        export(&mut bytes, &map, &registry.materials(), UpAxis::Y).unwrap();
+        let options = VoxImportOptions {
+            up_axis: UpAxis::Y,
+            model: None,
+            palette_map: registry.iter().map(|it| (it.id.id(), it.id)).collect(),
+        };
+        let import = import(&mut bytes.as_slice(), &mut registry.clone(), &options).unwrap();
+
+        let imported = &import.map;
+        assert_eq!([imported.x(), imported.y(), imported.z()], [3, 2, 4]);
+        assert_eq!(imported.at(0, 0, 0), Cell::STONE);
+        assert_eq!(imported.at(2, 1, 3), Cell::SAND);
+    }
+
+    #[test]
+    fn export_and_import_keep_cells() {
+        let registry = CellRegistry::default();
+        let mut map = Map::empty(4, 3, 5);
+        // corners of the map touch the padding strip that is not exported
+        *map.at_mut(0, 0, 0) = Cell::STONE;
+        *map.at_mut(3, 2, 4) = Cell::GRASS;
+        *map.at_mut(1, 0, 3) = Cell::SAND;
+        *map.at_mut(2, 1, 0) = Cell::GLASS;
+        let sim = WaterSim::new(map.clone(), &registry);
+
+        for up_axis in [UpAxis::Y, UpAxis::Z] {
+            let mut bytes = Vec::new();
+            export(&mut bytes, &sim, &registry.materials(), up_axis).unwrap();
+            let options = VoxImportOptions {
+                up_axis,
+                model: None,
+                palette_map: registry.iter().map(|it| (it.id.id(), it.id)).collect(),
+            };
+            let import = import(&mut bytes.as_slice(), &mut registry.clone(), &options).unwrap();
+
+            let imported = &import.map;
+            assert_eq!(
+                [imported.x(), imported.y(), imported.z()],
+                [map.x(), map.y(), map.z()]
+            );
+            for z in 0..map.z() {
+                for y in 0..map.y() {
+                    for x in 0..map.x() {
+                        assert_eq!(imported.at(x, y, z), map.at(x, y, z), "{:?}", (x, y, z));
+                    }
+                }
+            }
+        }
+    }
+}
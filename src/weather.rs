@@ -0,0 +1,274 @@
+use crate::solver::FluidSolver;
+use crate::xorshift32::Xorshift32;
+use rand::Rng;
+
+/// Where rain falls, amounts are per column and tick on average.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RainDistribution {
+    /// Every column gets the same amount
+    Uniform,
+    /// Only `coverage` fraction of random columns gets rain each tick, in
+    /// larger drops, so that average amount is the same
+    Scattered { coverage: f32 },
+    /// Columns closer than `radius` to `center` (x, z) get rain
+    Cloud { center: [usize; 2], radius: f32 },
+}
+
+impl RainDistribution {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Uniform => "uniform",
+            Self::Scattered { .. } => "scattered",
+            Self::Cloud { .. } => "cloud",
+        }
+    }
+}
+
+/// Rain adds fluid on top of the terrain, evaporation takes it from surface
+/// fluid exposed to air. Runs on the CPU solver before every step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Weather {
+    pub enabled: bool,
+    /// Mass per column and tick
+    pub rain_rate: f32,
+    pub distribution: RainDistribution,
+    /// Mass per exposed surface cell and tick
+    pub evaporation_rate: f32,
+}
+
+impl Default for Weather {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rain_rate: 0.001,
+            distribution: RainDistribution::Uniform,
+            evaporation_rate: 0.0,
+        }
+    }
+}
+
+impl Weather {
+    /// Runs one tick. Random choices are taken from `rng`, pass the generator
+    /// of the simulation, so that history and saved scenes replay the same rain.
+    pub fn apply(&self, sim: &mut dyn FluidSolver, rng: &mut Xorshift32) {
+        if !self.enabled {
+            return;
+        }
+        let editable = sim.editable();
+        for z in editable.min[2]..editable.max[2] {
+            for x in editable.min[0]..editable.max[0] {
+                let surface = surface(sim, x, z);
+                let rain = match self.distribution {
+                    RainDistribution::Uniform => self.rain_rate,
+                    RainDistribution::Scattered { coverage } => {
+                        let coverage = coverage.clamp(0.0, 1.0);
+                        if coverage > 0.0 && rng.gen::<f32>() < coverage {
+                            self.rain_rate / coverage
+                        } else {
+                            0.0
+                        }
+                    }
+                    RainDistribution::Cloud { center, radius } => {
+                        let dx = x as f32 - center[0] as f32;
+                        let dz = z as f32 - center[1] as f32;
+                        if dx * dx + dz * dz <= radius * radius {
+                            self.rain_rate
+                        } else {
+                            0.0
+                        }
+                    }
+                };
+
+                match surface {
+                    Some((y, Surface::Water)) => {
+                        if self.evaporation_rate > 0.0 {
+                            sim.remove([x, y, z], self.evaporation_rate);
+                        }
+                        if rain > 0.0 {
+                            sim.inject([x, y, z], rain);
+                        }
+                    }
+                    // on top of solid, unless it is the highest layer
+                    Some((y, Surface::Solid)) if y + 1 < editable.max[1] && rain > 0.0 => {
+                        sim.inject([x, y + 1, z], rain);
+                    }
+                    Some(_) => {}
+                    // falls through the bottom
+                    None if rain > 0.0 => {
+                        sim.inject([x, editable.min[1], z], rain);
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Surface {
+    /// Rain joins the water and evaporation takes from it.
+    Water,
+    /// Rain stays on top of it.
+    Solid,
+    /// Other fluids such as lava, rain evaporates before it reaches them.
+    Fluid,
+}
+
+/// Highest cell of the column that is not air, and what it is made of.
+/// Water is recognized by its mass too, cell types lag one step behind.
+fn surface(sim: &dyn FluidSolver, x: usize, z: usize) -> Option<(usize, Surface)> {
+    let editable = sim.editable();
+    let registry = sim.registry();
+    let water = registry.by_name("water");
+    for y in (editable.min[1]..editable.max[1]).rev() {
+        let cell = sim.get([x, y, z]);
+        if registry.is_solid(cell) {
+            return Some((y, Surface::Solid));
+        }
+        if Some(cell) == water {
+            return Some((y, Surface::Water));
+        }
+        if registry.is_fluid(cell) {
+            return Some((y, Surface::Fluid));
+        }
+        if sim.water_at([x, y, z]) > 0.0 {
+            return Some((y, Surface::Water));
+        }
+        if sim.mass_at([x, y, z]) > 0.0 {
+            return Some((y, Surface::Fluid));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::VoxelGrid;
+    use crate::cells::{Cell, CellRegistry};
+    use crate::map::{Map, WaterSim};
+
+    const COLUMNS: f32 = 8.0 * 8.0;
+
+    /// Stone floor with air above, 8 by 8 columns.
+    fn sim() -> WaterSim {
+        let mut map = Map::empty(8, 4, 8);
+        for x in 0..8 {
+            for z in 0..8 {
+                *map.at_mut(x, 0, z) = Cell::STONE;
+            }
+        }
+        WaterSim::new(map, &CellRegistry::default())
+    }
+
+    fn rain(distribution: RainDistribution) -> Weather {
+        Weather {
+            enabled: true,
+            rain_rate: 0.01,
+            distribution,
+            evaporation_rate: 0.0,
+        }
+    }
+
+    fn rained(weather: &Weather, sim: &mut WaterSim, rng: &mut Xorshift32) -> f32 {
+        let before = FluidSolver::total_mass(sim);
+        weather.apply(sim, rng);
+        FluidSolver::total_mass(sim) - before
+    }
+
+    #[test]
+    fn uniform_rain_adds_rate_per_column() {
+        let mut sim = sim();
+        let mut rng = Xorshift32::new(1);
+        let weather = rain(RainDistribution::Uniform);
+
+        let added = rained(&weather, &mut sim, &mut rng);
+        assert!((added - 0.01 * COLUMNS).abs() < 1e-4, "{added}");
+        // on top of the floor
+        assert!(sim.mass_at([3, 2, 3]) > 0.0);
+
+        let disabled = Weather {
+            enabled: false,
+            ..weather
+        };
+        assert_eq!(rained(&disabled, &mut sim, &mut rng), 0.0);
+    }
+
+    #[test]
+    fn scattered_rain_adds_same_volume_on_average() {
+        let mut sim = sim();
+        let mut rng = Xorshift32::new(1);
+        let weather = rain(RainDistribution::Scattered { coverage: 0.25 });
+
+        let steps = 200;
+        let added = (0..steps)
+            .map(|_| rained(&weather, &mut sim, &mut rng))
+            .sum::<f32>();
+        let expected = 0.01 * COLUMNS * steps as f32;
+        assert!(
+            (added - expected).abs() < 0.1 * expected,
+            "{added} {expected}"
+        );
+    }
+
+    #[test]
+    fn cloud_rains_only_under_itself() {
+        let mut sim = sim();
+        let mut rng = Xorshift32::new(1);
+        let weather = rain(RainDistribution::Cloud {
+            center: [4, 4],
+            radius: 1.0,
+        });
+
+        let added = rained(&weather, &mut sim, &mut rng);
+        // center and its four neighbours
+        assert!((added - 0.01 * 5.0).abs() < 1e-5, "{added}");
+        assert!(sim.mass_at([4, 2, 4]) > 0.0);
+        assert_eq!(sim.mass_at([6, 2, 6]), 0.0);
+    }
+
+    #[test]
+    fn evaporation_takes_rate_per_surface_cell() {
+        let mut sim = sim();
+        let mut rng = Xorshift32::new(1);
+        rained(&rain(RainDistribution::Uniform), &mut sim, &mut rng);
+        let weather = Weather {
+            rain_rate: 0.0,
+            evaporation_rate: 0.004,
+            ..rain(RainDistribution::Uniform)
+        };
+
+        let removed = rained(&weather, &mut sim, &mut rng);
+        assert!((removed + 0.004 * COLUMNS).abs() < 1e-4, "{removed}");
+    }
+
+    #[test]
+    fn rain_and_evaporation_skip_lava() {
+        let mut map = Map::empty(8, 4, 8);
+        for x in 0..8 {
+            for z in 0..8 {
+                *map.at_mut(x, 0, z) = Cell::STONE;
+            }
+        }
+        for y in 1..3 {
+            *map.at_mut(3, y, 3) = Cell::LAVA;
+        }
+        let mut sim = WaterSim::new(map, &CellRegistry::default());
+        let lava = sim.total_lava_mass();
+        assert!(lava > 0.0);
+        let mut rng = Xorshift32::new(1);
+        let weather = Weather {
+            evaporation_rate: 0.004,
+            ..rain(RainDistribution::Uniform)
+        };
+
+        let added = rained(&weather, &mut sim, &mut rng);
+        // every column but the lava one
+        assert!((added - 0.01 * (COLUMNS - 1.0)).abs() < 1e-4, "{added}");
+        assert_eq!(sim.total_lava_mass(), lava);
+        let min = sim.editable().min;
+        for y in 1..4 {
+            assert_eq!(sim.water_at([min[0] + 3, min[1] + y, min[2] + 3]), 0.0);
+        }
+    }
+}
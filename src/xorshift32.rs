@@ -3,6 +3,31 @@ pub struct Xorshift32 {
     state: u32,
 }
 
+impl Xorshift32 {
+    /// Scrambles seed so that close seeds give unrelated sequences. Zero state
+    /// is avoided because generator would only produce zeros from it.
+    pub fn new(seed: u32) -> Self {
+        let mut x = seed.wrapping_add(0x9e3779b9);
+        x = (x ^ (x >> 16)).wrapping_mul(0x85ebca6b);
+        x = (x ^ (x >> 13)).wrapping_mul(0xc2b2ae35);
+        x ^= x >> 16;
+        Self {
+            state: if x == 0 { 0x9e3779b9 } else { x },
+        }
+    }
+
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+}
+
+/// Seed for independent random stream derived from single session seed.
+/// Result is never zero.
+pub fn derive_seed(seed: u32, stream: u32) -> u32 {
+    let mut rng = Xorshift32::new(seed ^ stream.wrapping_mul(0x632be5ab));
+    rand::RngCore::next_u32(&mut rng)
+}
+
 impl rand::RngCore for Xorshift32 {
     fn next_u32(&mut self) -> u32 {
         let mut x = self.state;